@@ -0,0 +1,82 @@
+use axum::extract::Path;
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::routing::post;
+use axum::{Json, Router};
+use serde_json::json;
+
+use crate::registry::registry;
+
+// Admin endpoints are disabled unless this variable holds the token they are called with
+const ADMIN_TOKEN_VARIABLE: &str = "CHATCLM_ADMIN_TOKEN";
+
+pub fn router<S: Clone + Send + Sync + 'static>() -> Router<S> {
+    Router::new().route("/admin/models/:id/reload", post(reload_model))
+}
+
+// Loads the checkpoint of a model again, the old model keeps serving if the new one is broken
+async fn reload_model(Path(id): Path<String>, headers: HeaderMap) -> Response {
+    if let Err(status) = authorize(std::env::var(ADMIN_TOKEN_VARIABLE).ok().as_deref(), &headers) {
+        return error(status, "Missing or invalid admin token".to_string());
+    }
+
+    let registry = match registry() {
+        Ok(registry) => registry,
+        Err(registry_error) => return error(StatusCode::INTERNAL_SERVER_ERROR, registry_error.to_string()),
+    };
+    let Some(model) = registry.models().iter().find(|model| model.id() == id) else {
+        return error(StatusCode::NOT_FOUND, format!("The model `{}` does not exist", id));
+    };
+
+    match tokio::task::spawn_blocking(|| model.reload()).await {
+        Ok(Ok(_)) => Json(json!({ "id": model.id(), "reloaded": true })).into_response(),
+        Ok(Err(load_error)) => error(StatusCode::UNPROCESSABLE_ENTITY, load_error.to_string()),
+        Err(join_error) => error(StatusCode::INTERNAL_SERVER_ERROR, join_error.to_string()),
+    }
+}
+
+fn authorize(token: Option<&str>, headers: &HeaderMap) -> Result<(), StatusCode> {
+    let Some(token) = token.filter(|token| !token.is_empty()) else {
+        return Err(StatusCode::FORBIDDEN);
+    };
+    let given = headers.get("authorization")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .unwrap_or_default();
+
+    if constant_time_eq(given.as_bytes(), token.as_bytes()) {
+        Ok(())
+    } else {
+        Err(StatusCode::UNAUTHORIZED)
+    }
+}
+
+// Compares every byte so the time taken does not tell how much of the token was right
+pub(crate) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |difference, (x, y)| difference | (x ^ y)) == 0
+}
+
+fn error(status: StatusCode, message: String) -> Response {
+    (status, Json(json!({ "error": { "message": message } }))).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::{HeaderMap, StatusCode};
+
+    use crate::api::admin::authorize;
+
+    #[test]
+    fn admin_requests_need_the_token() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(authorize(None, &headers), Err(StatusCode::FORBIDDEN));
+        assert_eq!(authorize(Some("secret"), &headers), Err(StatusCode::UNAUTHORIZED));
+
+        headers.insert("authorization", "Bearer secrets".parse().unwrap());
+        assert_eq!(authorize(Some("secret"), &headers), Err(StatusCode::UNAUTHORIZED));
+
+        headers.insert("authorization", "Bearer secret".parse().unwrap());
+        assert_eq!(authorize(Some("secret"), &headers), Ok(()));
+        assert_eq!(authorize(Some(""), &headers), Err(StatusCode::FORBIDDEN));
+    }
+}
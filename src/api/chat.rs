@@ -0,0 +1,241 @@
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::sync::{LazyLock, Mutex};
+use std::time::{Duration, Instant};
+
+use axum::extract::Path;
+use axum::http::StatusCode;
+use axum::response::sse::{Event, KeepAlive, Sse};
+use axum::response::{IntoResponse, Response};
+use axum::routing::get;
+use axum::Router;
+use futures::channel::mpsc;
+use leptos::ServerFnError;
+
+use crate::backend::generation::{CancelToken, FinishReason};
+use crate::chat::{ChatHistory, TokenInfo};
+use crate::compare::{bytes_per_token, record_answer, ComparedAnswer};
+use crate::model::{chat_prompt_template, generate_response, GenerationEvent, GenerationSettings};
+use crate::rate_limit::{rate_limiter, Caller};
+use crate::registry::RegisteredModel;
+use crate::scheduler::scheduler;
+
+// Generations are registered by the `start_generation` server function and run once the chat
+// subscribes to their events. Nobody subscribing within this time means nobody is listening.
+const PENDING_TIMEOUT: Duration = Duration::from_secs(60);
+// Registered generations nobody subscribed to yet, more are turned away until those run or expire
+const MAX_PENDING: usize = 1024;
+
+struct RegisteredGeneration {
+    request: Option<(String, ChatHistory, GenerationSettings)>, /* model id, conversation and settings, taken once the generation runs */
+    caller: Caller, /* who registered it and is charged for its tokens */
+    comparison: Option<String>, /* the comparison the answer is recorded for */
+    cancel: CancelToken,
+    created: Instant,
+}
+
+static GENERATIONS: LazyLock<Mutex<HashMap<String, RegisteredGeneration>>> = LazyLock::new(|| Mutex::new(HashMap::new()));
+
+pub fn router<S: Clone + Send + Sync + 'static>() -> Router<S> {
+    Router::new().route("/api/chat/generations/:id/events", get(generation_events))
+}
+
+// People using the chat share the rate limits of the web tier, by address
+pub fn register_generation(client: &str, model: &RegisteredModel, history: ChatHistory, settings: GenerationSettings) -> Result<String, ServerFnError> {
+    let mut ids = register(client, vec![(model, settings)], &history, None)?;
+    Ok(ids.remove(0))
+}
+
+// Compared models answer with their defaults, the answer is stored with the comparison once it ends
+pub fn register_compared_generations(client: &str, comparison_id: &str, models: &[&RegisteredModel], history: ChatHistory) -> Result<Vec<String>, ServerFnError> {
+    let requests = models.iter().map(|model| (*model, GenerationSettings::default())).collect();
+    register(client, requests, &history, Some(comparison_id))
+}
+
+// Every generation is admitted by the rate limiter when it is registered, either all of them are
+// registered or none. Only the part of the conversation the model gets to see is kept.
+fn register(client: &str, requests: Vec<(&RegisteredModel, GenerationSettings)>, history: &ChatHistory, comparison: Option<&str>) -> Result<Vec<String>, ServerFnError> {
+    let requests = requests.into_iter()
+        .map(|(model, settings)| {
+            let history = model.load()?.truncate_history(&chat_prompt_template(), history)?;
+            Ok((model, history, settings))
+        })
+        .collect::<Result<Vec<_>, ServerFnError>>()?;
+    let limiter = rate_limiter()?;
+    let caller = Caller::web(client);
+
+    let mut generations = GENERATIONS.lock().unwrap();
+    generations.retain(|_, generation| generation.request.is_none() || generation.created.elapsed() < PENDING_TIMEOUT);
+    let pending = generations.values().filter(|generation| generation.request.is_some()).count();
+    if pending + requests.len() > MAX_PENDING {
+        return Err(ServerFnError::new("The server is busy, too many generations are waiting to start"));
+    }
+    for (model, _, _) in &requests {
+        limiter.admit(&caller, model.id(), 1).map_err(|refusal| ServerFnError::new(refusal.message()))?;
+    }
+
+    let ids = requests.into_iter()
+        .map(|(model, history, settings)| {
+            let id = format!("{:016x}", rand::random::<u64>());
+            generations.insert(id.clone(), RegisteredGeneration {
+                request: Some((model.id().to_string(), history, settings)),
+                caller: caller.clone(),
+                comparison: comparison.map(str::to_string),
+                cancel: CancelToken::new(),
+                created: Instant::now(),
+            });
+            id
+        })
+        .collect();
+    Ok(ids)
+}
+
+// Returns whether there was a generation to cancel, finished ones are forgotten
+pub fn cancel_generation(id: &str) -> bool {
+    match GENERATIONS.lock().unwrap().get(id) {
+        Some(generation) => {
+            generation.cancel.cancel();
+            true
+        }
+        None => false,
+    }
+}
+
+// The generation was admitted when it was registered, its tokens are charged to whoever registered it
+async fn generation_events(Path(id): Path<String>) -> Result<Response, StatusCode> {
+    let limiter = rate_limiter().map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    // taking the request out makes sure it only runs once, even if the browser reconnects
+    let ((model_id, history, settings), caller, comparison, cancel) = {
+        let mut generations = GENERATIONS.lock().unwrap();
+        let generation = generations.get_mut(&id).ok_or(StatusCode::NOT_FOUND)?;
+        let request = generation.request.take().ok_or(StatusCode::NOT_FOUND)?;
+        (request, generation.caller.clone(), generation.comparison.take(), generation.cancel.clone())
+    };
+    let started = Instant::now();
+    let (sender, receiver) = mpsc::unbounded::<Result<Event, Infallible>>();
+
+    // a failed send means the chat closed the stream, stop generating for nobody
+    let send = {
+        let cancel = cancel.clone();
+        move |event: GenerationEvent| {
+            let event = Event::default().json_data(event).expect("Generation events are valid JSON");
+            if sender.unbounded_send(Ok(event)).is_err() {
+                cancel.cancel();
+            }
+        }
+    };
+    let on_position = {
+        let send = send.clone();
+        move |position| send(GenerationEvent::Queued { position })
+    };
+    let finish = {
+        let send = send.clone();
+        let id = id.clone();
+        move |result: Result<FinishReason, String>| {
+            GENERATIONS.lock().unwrap().remove(&id);
+            send(match result {
+                Ok(reason) => GenerationEvent::Done { finish_reason: reason.name().to_string() },
+                Err(message) => GenerationEvent::Error { message },
+            });
+        }
+    };
+
+    let submitted = scheduler().submit(&caller.subject.clone(), on_position, {
+        let finish = finish.clone();
+        move |turn| {
+            let mut tokens: Vec<TokenInfo> = Vec::new();
+            let mut first_token = None;
+            let result = turn.map_err(|rejection| rejection.to_string()).and_then(|_| {
+                generate_response(&model_id, &history, &settings, &cancel, |token| {
+                    first_token.get_or_insert_with(|| started.elapsed());
+                    tokens.push(token.clone());
+                    send(GenerationEvent::Token(token))
+                }).map_err(|error| error.to_string())
+            });
+            limiter.charge_tokens(&caller, &model_id, tokens.len());
+            // recorded before the chat hears the answer is done and can vote on it
+            if let (Some(comparison_id), Ok(_)) = (&comparison, &result) {
+                let answer = ComparedAnswer {
+                    text: tokens.iter().map(|token| token.text.as_str()).collect::<String>().trim().to_string(),
+                    first_token_ms: first_token.map(|elapsed| elapsed.as_secs_f64() * 1000.0),
+                    total_ms: started.elapsed().as_secs_f64() * 1000.0,
+                    bytes_per_token: bytes_per_token(&tokens),
+                };
+                record_answer(comparison_id, &model_id, &answer);
+            }
+            finish(result);
+        }
+    });
+    if let Err(rejection) = submitted {
+        finish(Err(rejection.to_string()));
+    }
+
+    Ok(Sse::new(receiver).keep_alive(KeepAlive::default()).into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::api::chat::{cancel_generation, register_compared_generations, register_generation, GENERATIONS, MAX_PENDING};
+    use crate::chat::{ChatHistory, TokenAlternative, TokenInfo};
+    use crate::config::config;
+    use crate::model::{GenerationEvent, GenerationSettings};
+    use crate::registry::{GenerationDefaults, ModelConfig, ModelSource, RegisteredModel};
+
+    fn random_model() -> RegisteredModel {
+        RegisteredModel::new(ModelConfig {
+            id: "random".to_string(),
+            name: "Random".to_string(),
+            source: ModelSource::Random,
+            generation: None,
+        }, &GenerationDefaults::default())
+    }
+
+    #[test]
+    fn events_are_tagged_json() {
+        let token = TokenInfo {
+            text: " hi".to_string(),
+            bytes_added: 2,
+            alternatives: vec![TokenAlternative { text: " hey".to_string(), bytes_added: 1 }],
+        };
+        let event = serde_json::to_string(&GenerationEvent::Token(token)).unwrap();
+        assert_eq!(event, r#"{"type":"token","text":" hi","bytes_added":2,"alternatives":[{"text":" hey","bytes_added":1}]}"#);
+
+        let event: GenerationEvent = serde_json::from_str(r#"{"type":"done","finish_reason":"stop"}"#).unwrap();
+        assert_eq!(event, GenerationEvent::Done { finish_reason: "stop".to_string() });
+    }
+
+    // one test, the others would see the pending generations it registers
+    #[test]
+    fn registered_generations_can_be_cancelled() {
+        let model = random_model();
+        let mut history = ChatHistory::default();
+        history.new_user_message("hello".to_string());
+        let id = register_generation("registering", &model, history.clone(), GenerationSettings::default()).unwrap();
+        assert!(cancel_generation(&id));
+        assert!(!cancel_generation("unknown"));
+
+        let generation = GENERATIONS.lock().unwrap().remove(&id).unwrap();
+        assert!(generation.cancel.is_cancelled());
+        assert_eq!(generation.request.unwrap().1, history);
+
+        // only what the model gets to see is kept
+        let mut long = ChatHistory::default();
+        long.new_user_message("word ".repeat(config().limits.context_tokens));
+        long.new_user_message("hello".to_string());
+        let id = register_generation("registering", &model, long, GenerationSettings::default()).unwrap();
+        let generation = GENERATIONS.lock().unwrap().remove(&id).unwrap();
+        assert_eq!(generation.request.unwrap().1, history);
+
+        // the web tier admits 30 generations a minute
+        let ids = (0..28).map(|_| register_generation("limited", &model, history.clone(), GenerationSettings::default()).unwrap());
+        let ids: Vec<String> = ids.collect();
+        let compared = register_compared_generations("limited", "comparison", &[&model, &model], history.clone()).unwrap();
+        assert!(register_generation("limited", &model, history.clone(), GenerationSettings::default()).is_err());
+        assert!(register_compared_generations("limited", "comparison", &[&model, &model], history.clone()).is_err());
+        GENERATIONS.lock().unwrap().retain(|id, _| !ids.contains(id) && !compared.contains(id));
+
+        let ids: Vec<String> = (0..MAX_PENDING).map(|index| register_generation(&format!("client {}", index), &model, history.clone(), GenerationSettings::default()).unwrap()).collect();
+        assert!(register_generation("another", &model, history.clone(), GenerationSettings::default()).is_err());
+        GENERATIONS.lock().unwrap().retain(|id, _| !ids.contains(id));
+    }
+}
@@ -0,0 +1,51 @@
+use axum::extract::Path;
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::routing::get;
+use axum::{Json, Router};
+use serde_json::json;
+
+use crate::registry::registry;
+
+// Probes for the proxy in front of the server and details of the models it serves
+
+pub fn router<S: Clone + Send + Sync + 'static>() -> Router<S> {
+    Router::new()
+        .route("/healthz", get(healthz))
+        .route("/readyz", get(readyz))
+        .route("/api/models/:id/info", get(model_info))
+}
+
+// Answering at all is being alive
+async fn healthz() -> Json<serde_json::Value> {
+    Json(json!({ "status": "ok" }))
+}
+
+// Ready once every model is loaded and generated its warm-up token
+async fn readyz() -> Response {
+    let registry = match registry() {
+        Ok(registry) => registry,
+        Err(error) => return (StatusCode::SERVICE_UNAVAILABLE, Json(json!({ "ready": false, "error": error.to_string() }))).into_response(),
+    };
+
+    let models: Vec<_> = registry.models().iter()
+        .map(|model| {
+            let details = model.details();
+            json!({ "id": details.id, "ready": details.serving, "error": details.error })
+        })
+        .collect();
+    let ready = registry.is_ready();
+    let status = if ready { StatusCode::OK } else { StatusCode::SERVICE_UNAVAILABLE };
+    (status, Json(json!({ "ready": ready, "models": models }))).into_response()
+}
+
+async fn model_info(Path(id): Path<String>) -> Response {
+    let registry = match registry() {
+        Ok(registry) => registry,
+        Err(error) => return (StatusCode::INTERNAL_SERVER_ERROR, Json(json!({ "error": { "message": error.to_string() } }))).into_response(),
+    };
+    match registry.models().iter().find(|model| model.id() == id) {
+        Some(model) => Json(model.details()).into_response(),
+        None => (StatusCode::NOT_FOUND, Json(json!({ "error": { "message": format!("The model `{}` does not exist", id) } }))).into_response(),
+    }
+}
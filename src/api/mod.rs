@@ -0,0 +1,153 @@
+pub mod admin;
+pub mod chat;
+pub mod health;
+pub mod metrics;
+pub mod openai;
+
+use std::net::{IpAddr, SocketAddr};
+
+use axum::async_trait;
+use axum::extract::{ConnectInfo, FromRequestParts, Request};
+use axum::http::header::{COOKIE, SET_COOKIE};
+use axum::http::request::Parts;
+use axum::http::{HeaderMap, HeaderValue, StatusCode};
+use axum::middleware::Next;
+use axum::response::Response;
+use axum::Router;
+
+use crate::config::config;
+
+// HTTP routes served next to the Leptos app
+pub fn router<S: Clone + Send + Sync + 'static>() -> Router<S> {
+    Router::new()
+        .nest("/v1", openai::router())
+        .merge(chat::router())
+        .merge(admin::router())
+        .merge(health::router())
+        .merge(metrics::router())
+}
+
+// Who sent a request, generations take turns and rate limits apply by it. That is the address the
+// request came from or, if that is a trusted proxy, the client the proxy forwarded it for.
+pub struct ClientId(pub String);
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for ClientId {
+    type Rejection = std::convert::Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let address = parts.extensions.get::<ConnectInfo<SocketAddr>>().map(|ConnectInfo(address)| *address);
+        Ok(ClientId(client_id(&parts.headers, address, &config().trusted_proxies)))
+    }
+}
+
+// Conversations belong to the browser that started them. A browser without an owner id gets a
+// random one in a cookie with its first response and sends it along from then on.
+const OWNER_COOKIE: &str = "chatclm_owner";
+const OWNER_COOKIE_MAX_AGE_SECS: u64 = 400 * 24 * 60 * 60; /* the longest browsers keep a cookie */
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct OwnerId(pub String);
+
+// Middleware that hands every request the owner id of its browser
+pub async fn assign_owner(mut request: Request, next: Next) -> Response {
+    let known = owner_from_cookies(request.headers());
+    let owner = known.clone().unwrap_or_else(|| format!("{:032x}", rand::random::<u128>()));
+    request.extensions_mut().insert(OwnerId(owner.clone()));
+
+    let mut response = next.run(request).await;
+    if known.is_none() {
+        let cookie = format!("{}={}; Path=/; Max-Age={}; HttpOnly; SameSite=Lax", OWNER_COOKIE, owner, OWNER_COOKIE_MAX_AGE_SECS);
+        response.headers_mut().append(SET_COOKIE, HeaderValue::from_str(&cookie).expect("Owner cookies are valid headers"));
+    }
+    response
+}
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for OwnerId {
+    type Rejection = StatusCode;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        parts.extensions.get::<OwnerId>().cloned().ok_or(StatusCode::INTERNAL_SERVER_ERROR)
+    }
+}
+
+// Only ids the server could have made up count, anything else gets a new one
+fn owner_from_cookies(headers: &HeaderMap) -> Option<String> {
+    headers.get_all(COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .filter_map(|cookie| cookie.trim().split_once('='))
+        .find(|(name, _)| *name == OWNER_COOKIE)
+        .map(|(_, owner)| owner.to_string())
+        .filter(|owner| owner.len() == 32 && owner.chars().all(|char| char.is_ascii_hexdigit()))
+}
+
+// Proxies append the address they got a request from, so the last address that is not one of our
+// proxies is the client. Anything in front of it was sent by the client and can be made up.
+fn client_id(headers: &HeaderMap, address: Option<SocketAddr>, trusted_proxies: &[IpAddr]) -> String {
+    let Some(peer) = address.map(|address| address.ip()) else {
+        return String::new();
+    };
+    if !trusted_proxies.contains(&peer) {
+        return peer.to_string();
+    }
+    headers.get_all("x-forwarded-for")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .filter(|client| !client.is_empty())
+        .rev()
+        .find(|client| client.parse().map_or(true, |client: IpAddr| !trusted_proxies.contains(&client)))
+        .map_or_else(|| peer.to_string(), str::to_string)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, SocketAddr};
+
+    use axum::http::{HeaderMap, HeaderValue};
+
+    use crate::api::{client_id, owner_from_cookies};
+    use crate::rate_limit::{Caller, RateLimitConfig, RateLimiter};
+
+    fn forwarded_for(value: &str) -> HeaderMap {
+        HeaderMap::from_iter([("x-forwarded-for".parse().unwrap(), HeaderValue::from_str(value).unwrap())])
+    }
+
+    #[test]
+    fn spoofed_forwarded_addresses_keep_the_bucket() {
+        let peer: SocketAddr = "203.0.113.7:50000".parse().unwrap();
+        let limiter = RateLimiter::new(RateLimitConfig::default(), Vec::new()).unwrap();
+        let caller = Caller::web(&client_id(&HeaderMap::new(), Some(peer), &[]));
+        let per_minute = RateLimitConfig::default().tiers["web"].requests_per_minute.unwrap() as usize;
+        limiter.admit(&caller, "chatclm", per_minute).unwrap();
+        assert!(limiter.admit(&caller, "chatclm", 1).is_err());
+
+        let spoofed = client_id(&forwarded_for("198.51.100.1"), Some(peer), &[]);
+        assert_eq!(spoofed, "203.0.113.7");
+        assert!(limiter.admit(&Caller::web(&spoofed), "chatclm", 1).is_err());
+    }
+
+    #[test]
+    fn owners_come_from_their_cookie() {
+        let cookies = |value: &str| HeaderMap::from_iter([("cookie".parse().unwrap(), HeaderValue::from_str(value).unwrap())]);
+        let owner = "0123456789abcdef0123456789abcdef";
+        assert_eq!(owner_from_cookies(&cookies(&format!("theme=dark; chatclm_owner={}", owner))), Some(owner.to_string()));
+        assert_eq!(owner_from_cookies(&cookies("chatclm_owner=guessable")), None);
+        assert_eq!(owner_from_cookies(&HeaderMap::new()), None);
+    }
+
+    #[test]
+    fn trusted_proxies_name_the_client() {
+        let proxy: SocketAddr = "127.0.0.1:50000".parse().unwrap();
+        let proxies: Vec<IpAddr> = vec!["127.0.0.1".parse().unwrap(), "10.0.0.2".parse().unwrap()];
+        assert_eq!(client_id(&forwarded_for("203.0.113.7"), Some(proxy), &proxies), "203.0.113.7");
+        // what the client put in front of its own address is ignored, and so are chained proxies
+        assert_eq!(client_id(&forwarded_for("198.51.100.1, 203.0.113.7, 10.0.0.2"), Some(proxy), &proxies), "203.0.113.7");
+        assert_eq!(client_id(&HeaderMap::new(), Some(proxy), &proxies), "127.0.0.1");
+        assert_eq!(client_id(&HeaderMap::new(), None, &proxies), "");
+    }
+}
@@ -0,0 +1,704 @@
+use std::convert::Infallible;
+use std::sync::Arc;
+
+use axum::async_trait;
+use axum::extract::FromRequestParts;
+use axum::http::request::Parts;
+use axum::http::StatusCode;
+use axum::response::sse::{Event, KeepAlive, Sse};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use futures::channel::mpsc;
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::api::ClientId;
+use crate::backend::error::ClmError;
+use crate::backend::generation::{CancelToken, FinishReason, Generation, GenerationConfig};
+use crate::backend::sampling::SamplingOptions;
+use crate::chat::ChatHistory;
+use crate::config::config;
+use crate::model::{chat_prompt_template, MAX_STOP_SEQUENCES};
+use crate::prompt::PromptTemplate;
+use crate::rate_limit::{rate_limiter, Allowance, Caller, RateLimiter};
+use crate::registry::{registry, LanguageModel, RegisteredModel};
+use crate::request_log::generate_logged;
+use crate::scheduler::{scheduler, Rejection};
+
+// OpenAI compatible endpoints, see https://platform.openai.com/docs/api-reference
+
+// Every stop string is searched for in the whole answer after every token
+const MAX_STOP_LENGTH: usize = 64;
+
+pub fn router<S: Clone + Send + Sync + 'static>() -> Router<S> {
+    Router::new()
+        .route("/models", get(list_models))
+        .route("/completions", post(completions))
+        .route("/chat/completions", post(chat_completions))
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(untagged)]
+enum OneOrMany {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl OneOrMany {
+    fn into_vec(self) -> Vec<String> {
+        match self {
+            OneOrMany::One(value) => vec![value],
+            OneOrMany::Many(values) => values,
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Debug, Default)]
+struct GenerationParameters {
+    max_tokens: Option<usize>,
+    temperature: Option<f64>,
+    top_p: Option<f64>,
+    stop: Option<OneOrMany>,
+    n: Option<usize>,
+    seed: Option<u64>,
+    #[serde(default)]
+    stream: bool,
+}
+
+impl GenerationParameters {
+    fn validate(&self, prompts: usize) -> Result<(), ApiError> {
+        let limits = &config().limits;
+        if !(1..=limits.max_prompts).contains(&prompts) {
+            return Err(ApiError::invalid_request(format!("prompt must hold between 1 and {} prompts", limits.max_prompts)));
+        }
+        if self.max_tokens.is_some_and(|max_tokens| max_tokens > limits.max_tokens) {
+            return Err(ApiError::invalid_request(format!("max_tokens must be at most {}", limits.max_tokens)));
+        }
+        if !(1..=limits.max_choices).contains(&self.choices()) {
+            return Err(ApiError::invalid_request(format!("n must be between 1 and {}", limits.max_choices)));
+        }
+        if self.temperature.is_some_and(|temperature| temperature < 0.0) {
+            return Err(ApiError::invalid_request("temperature must not be negative".to_string()));
+        }
+        if self.top_p.is_some_and(|top_p| !(0.0..=1.0).contains(&top_p)) {
+            return Err(ApiError::invalid_request("top_p must be between 0 and 1".to_string()));
+        }
+        let stop = self.stop.clone().map(OneOrMany::into_vec).unwrap_or_default();
+        if stop.len() > MAX_STOP_SEQUENCES {
+            return Err(ApiError::invalid_request(format!("stop must hold at most {} sequences", MAX_STOP_SEQUENCES)));
+        }
+        if stop.iter().any(|stop| stop.len() > MAX_STOP_LENGTH) {
+            return Err(ApiError::invalid_request(format!("stop sequences must be at most {} bytes long", MAX_STOP_LENGTH)));
+        }
+        Ok(())
+    }
+
+    fn choices(&self) -> usize {
+        self.n.unwrap_or(1)
+    }
+
+    // Unset parameters keep the defaults of the model. Seeded requests still get different
+    // choices, each one uses the next seed.
+    fn config(&self, defaults: &GenerationConfig, choice: usize) -> GenerationConfig {
+        GenerationConfig {
+            max_new_tokens: self.max_tokens.unwrap_or(defaults.max_new_tokens),
+            stop_strings: self.stop.clone().map(OneOrMany::into_vec).unwrap_or_else(|| defaults.stop_strings.clone()),
+            sampling: SamplingOptions {
+                temperature: self.temperature.unwrap_or(defaults.sampling.temperature),
+                top_p: self.top_p.or(defaults.sampling.top_p),
+                seed: self.seed.map(|seed| seed.wrapping_add(choice as u64)),
+                ..defaults.sampling.clone()
+            },
+            ..defaults.clone()
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct CompletionRequest {
+    model: Option<String>,
+    prompt: OneOrMany,
+    #[serde(flatten)]
+    parameters: GenerationParameters,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+struct ChatMessage {
+    role: Role,
+    content: String,
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum Role {
+    System,
+    User,
+    Assistant,
+}
+
+#[derive(Deserialize, Debug)]
+struct ChatCompletionRequest {
+    model: Option<String>,
+    messages: Vec<ChatMessage>,
+    #[serde(flatten)]
+    parameters: GenerationParameters,
+}
+
+// What a request asks to continue, a conversation is rendered the way the chat renders its own
+enum Prompt {
+    Text(String),
+    Chat(PromptTemplate, ChatHistory),
+}
+
+impl Prompt {
+    fn render(&self, model: &LanguageModel) -> Result<String, ApiError> {
+        match self {
+            Prompt::Text(text) => Ok(text.clone()),
+            Prompt::Chat(template, history) => Ok(model.render_prompt(template, history)?),
+        }
+    }
+
+    // A conversation always ends where the model starts to write the next user turn
+    fn stop_strings(&self) -> Vec<String> {
+        match self {
+            Prompt::Text(_) => Vec::new(),
+            Prompt::Chat(..) => PromptTemplate::stop_strings(),
+        }
+    }
+}
+
+// System messages are added to the preamble of the chat, the others become its turns
+fn chat_prompt(messages: &[ChatMessage]) -> Prompt {
+    let mut template = chat_prompt_template();
+    let mut history = ChatHistory::default();
+    for message in messages {
+        match message.role {
+            Role::System => {
+                let system = template.system.take().unwrap_or_default();
+                template.system = Some(format!("{}\n{}", system, message.content.trim()).trim().to_string());
+            }
+            Role::User => history.new_user_message(message.content.clone()),
+            Role::Assistant => history.new_server_message(message.content.clone()),
+        }
+    }
+    Prompt::Chat(template, history)
+}
+
+// Who is asking: the owner of the API key a request carries or, without one, the address it came from
+struct ApiCaller(Caller);
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for ApiCaller {
+    type Rejection = ApiError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let ClientId(client) = ClientId::from_request_parts(parts, state).await.unwrap_or_else(|never| match never {});
+        let limiter = rate_limiter()?;
+        let key = parts.headers.get("authorization")
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "));
+
+        match key {
+            Some(key) => limiter.find_key(key.trim())
+                .map(|key| ApiCaller(Caller::key(key)))
+                .ok_or_else(|| ApiError::invalid_api_key("Incorrect API key provided".to_string())),
+            None if limiter.require_api_key() => Err(ApiError::invalid_api_key(
+                "You didn't provide an API key, send it as a Bearer token in the Authorization header".to_string(),
+            )),
+            None => Ok(ApiCaller(Caller::anonymous(&client))),
+        }
+    }
+}
+
+async fn list_models(_caller: ApiCaller) -> Result<Json<Value>, ApiError> {
+    let models: Vec<Value> = registry()?.models().iter().map(model_object).collect();
+    Ok(Json(json!({
+        "object": "list",
+        "data": models,
+    })))
+}
+
+fn model_object(model: &RegisteredModel) -> Value {
+    json!({
+        "id": model.id(),
+        "object": "model",
+        "created": 0,
+        "owned_by": "chatclm",
+    })
+}
+
+async fn completions(ApiCaller(caller): ApiCaller, Json(request): Json<CompletionRequest>) -> Result<Response, ApiError> {
+    let model = find_model(request.model.as_deref())?;
+    let prompts = request.prompt.into_vec().into_iter().map(Prompt::Text).collect();
+    respond(&caller, Endpoint::Completion, model, prompts, request.parameters).await
+}
+
+async fn chat_completions(ApiCaller(caller): ApiCaller, Json(request): Json<ChatCompletionRequest>) -> Result<Response, ApiError> {
+    let model = find_model(request.model.as_deref())?;
+    if !request.messages.iter().any(|message| message.role != Role::System) {
+        return Err(ApiError::invalid_request("messages must hold a user or assistant message".to_string()));
+    }
+    respond(&caller, Endpoint::Chat, model, vec![chat_prompt(&request.messages)], request.parameters).await
+}
+
+// Requests without a model get the default one
+fn find_model(model: Option<&str>) -> Result<&'static RegisteredModel, ApiError> {
+    registry()?.find(model).ok_or_else(|| ApiError {
+        status: StatusCode::NOT_FOUND,
+        kind: "invalid_request_error",
+        code: Some("model_not_found"),
+        message: format!("The model `{}` does not exist", model.unwrap_or_default()),
+        refusal: None,
+    })
+}
+
+async fn respond(caller: &Caller, endpoint: Endpoint, model: &'static RegisteredModel, prompts: Vec<Prompt>, parameters: GenerationParameters) -> Result<Response, ApiError> {
+    parameters.validate(prompts.len())?;
+    let limiter = rate_limiter()?;
+    let allowance = limiter.admit(caller, model.id(), prompts.len() * parameters.choices()).map_err(ApiError::rate_limited)?;
+    let completion = Completion {
+        endpoint,
+        id: format!("{}-{:016x}", endpoint.id_prefix(), rand::random::<u64>()),
+        created: chrono::Utc::now().timestamp(),
+        model: model.id().to_string(),
+    };
+
+    if parameters.stream {
+        let stream = stream_response(limiter, caller, completion, model, prompts, parameters)?;
+        return Ok((allowance.headers(), stream).into_response());
+    }
+
+    // a client that hangs up drops this future, the generation has nobody to answer anymore
+    let cancel = CancelToken::new();
+    let _cancel_on_drop = CancelOnDrop(cancel.clone());
+    let (prompt_tokens, generations) = scheduler().run(&caller.subject, move || {
+        generate_choices(endpoint, model, &prompts, &parameters, &cancel, |_| {})
+    }).await??;
+
+    let completion_tokens = generated_tokens(&generations);
+    limiter.charge_tokens(caller, model.id(), completion_tokens);
+    let choices: Vec<Value> = generations.iter()
+        .enumerate()
+        .map(|(index, generation)| endpoint.choice(index, &generation.text, finish_reason(generation.finish_reason)))
+        .collect();
+
+    Ok((allowance.headers(), Json(json!({
+        "id": completion.id,
+        "object": endpoint.object(),
+        "created": completion.created,
+        "model": completion.model,
+        "choices": choices,
+        "usage": {
+            "prompt_tokens": prompt_tokens,
+            "completion_tokens": completion_tokens,
+            "total_tokens": prompt_tokens + completion_tokens,
+        },
+    }))).into_response())
+}
+
+// A full queue is answered with a 429 right away, waiting too long once streaming has begun ends
+// the stream with an error
+fn stream_response(limiter: &'static RateLimiter, caller: &Caller, completion: Completion, model: &'static RegisteredModel, prompts: Vec<Prompt>, parameters: GenerationParameters) -> Result<Sse<mpsc::UnboundedReceiver<Result<Event, Infallible>>>, ApiError> {
+    let (sender, receiver) = mpsc::unbounded();
+
+    let charged = caller.clone();
+    scheduler().submit(&caller.subject, |_| {}, move |turn| {
+        // a failed send means the client went away, stop generating for nobody
+        let cancel = CancelToken::new();
+        let send = |data: String| {
+            if sender.unbounded_send(Ok(Event::default().data(data))).is_err() {
+                cancel.cancel();
+            }
+        };
+
+        let result = turn.map_err(ApiError::from).and_then(|_| {
+            generate_choices(completion.endpoint, model, &prompts, &parameters, &cancel, |event| {
+                if let Some(chunk) = completion.chunk(event) {
+                    send(chunk.to_string());
+                }
+            })
+        });
+        match result {
+            Ok((_, generations)) => limiter.charge_tokens(&charged, &completion.model, generated_tokens(&generations)),
+            Err(error) => send(error.body().to_string()),
+        }
+        send("[DONE]".to_string());
+    })?;
+
+    Ok(Sse::new(receiver).keep_alive(KeepAlive::default()))
+}
+
+// Cancels a generation once whoever waits for it is gone
+struct CancelOnDrop(CancelToken);
+
+impl Drop for CancelOnDrop {
+    fn drop(&mut self) {
+        self.0.cancel();
+    }
+}
+
+enum ChoiceEvent<'t> {
+    Start(usize),
+    Token(usize, &'t str),
+    Finish(usize, FinishReason),
+}
+
+// Generates `n` choices for every prompt, returns the number of prompt tokens and the generations
+fn generate_choices(endpoint: Endpoint, model: &RegisteredModel, prompts: &[Prompt], parameters: &GenerationParameters, cancel: &CancelToken, mut on_event: impl FnMut(ChoiceEvent)) -> Result<(usize, Vec<Generation>), ApiError> {
+    let language_model = model.load()?;
+    let defaults = model.generation.config();
+
+    let mut prompt_tokens = 0;
+    let mut generations = Vec::new();
+    let context_tokens = config().limits.context_tokens;
+    for prompt in prompts {
+        // conversations are cut to fit, a text prompt is taken as it is or not at all
+        let text = prompt.render(&language_model)?;
+        let tokens = language_model.prompt_tokens(&text)?;
+        if tokens > context_tokens {
+            return Err(ApiError::context_length_exceeded(tokens, context_tokens));
+        }
+        prompt_tokens += tokens;
+
+        for choice in 0..parameters.choices() {
+            let index = generations.len();
+            on_event(ChoiceEvent::Start(index));
+            let mut config = GenerationConfig { cancel: cancel.clone(), ..parameters.config(&defaults, choice) };
+            config.stop_strings.extend(prompt.stop_strings());
+            let generation = generate_logged(endpoint.route(), model.id(), &language_model, &text, &config, |token| {
+                on_event(ChoiceEvent::Token(index, &token.text))
+            })?;
+            on_event(ChoiceEvent::Finish(index, generation.finish_reason));
+            generations.push(generation);
+        }
+    }
+
+    Ok((prompt_tokens, generations))
+}
+
+fn generated_tokens(generations: &[Generation]) -> usize {
+    generations.iter().map(|generation| generation.tokens.len()).sum()
+}
+
+fn finish_reason(reason: FinishReason) -> &'static str {
+    match reason {
+        // generations are only cancelled once the client is gone
+        FinishReason::Stop | FinishReason::Cancelled => "stop",
+        // OpenAI has no timeouts, running out of time is running out of budget
+        FinishReason::Length | FinishReason::Timeout => "length",
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Endpoint {
+    Completion,
+    Chat,
+}
+
+impl Endpoint {
+    fn route(self) -> &'static str {
+        match self {
+            Endpoint::Completion => "/v1/completions",
+            Endpoint::Chat => "/v1/chat/completions",
+        }
+    }
+
+    fn id_prefix(self) -> &'static str {
+        match self {
+            Endpoint::Completion => "cmpl",
+            Endpoint::Chat => "chatcmpl",
+        }
+    }
+
+    fn object(self) -> &'static str {
+        match self {
+            Endpoint::Completion => "text_completion",
+            Endpoint::Chat => "chat.completion",
+        }
+    }
+
+    fn chunk_object(self) -> &'static str {
+        match self {
+            Endpoint::Completion => "text_completion",
+            Endpoint::Chat => "chat.completion.chunk",
+        }
+    }
+
+    fn choice(self, index: usize, text: &str, finish_reason: &str) -> Value {
+        match self {
+            Endpoint::Completion => json!({
+                "index": index,
+                "text": text,
+                "logprobs": null,
+                "finish_reason": finish_reason,
+            }),
+            Endpoint::Chat => json!({
+                "index": index,
+                "message": { "role": "assistant", "content": text },
+                "logprobs": null,
+                "finish_reason": finish_reason,
+            }),
+        }
+    }
+}
+
+struct Completion {
+    endpoint: Endpoint,
+    id: String,
+    created: i64,
+    model: String,
+}
+
+impl Completion {
+    fn chunk(&self, event: ChoiceEvent) -> Option<Value> {
+        let choice = match (self.endpoint, event) {
+            // only chat streams announce the role of a new choice
+            (Endpoint::Completion, ChoiceEvent::Start(_)) => return None,
+            (Endpoint::Chat, ChoiceEvent::Start(index)) => json!({
+                "index": index,
+                "delta": { "role": "assistant", "content": "" },
+                "finish_reason": null,
+            }),
+            (Endpoint::Completion, ChoiceEvent::Token(index, text)) => json!({
+                "index": index,
+                "text": text,
+                "logprobs": null,
+                "finish_reason": null,
+            }),
+            (Endpoint::Chat, ChoiceEvent::Token(index, text)) => json!({
+                "index": index,
+                "delta": { "content": text },
+                "finish_reason": null,
+            }),
+            (Endpoint::Completion, ChoiceEvent::Finish(index, reason)) => json!({
+                "index": index,
+                "text": "",
+                "logprobs": null,
+                "finish_reason": finish_reason(reason),
+            }),
+            (Endpoint::Chat, ChoiceEvent::Finish(index, reason)) => json!({
+                "index": index,
+                "delta": {},
+                "finish_reason": finish_reason(reason),
+            }),
+        };
+
+        Some(json!({
+            "id": self.id,
+            "object": self.endpoint.chunk_object(),
+            "created": self.created,
+            "model": self.model,
+            "choices": [choice],
+        }))
+    }
+}
+
+#[derive(Debug)]
+pub struct ApiError {
+    status: StatusCode,
+    kind: &'static str,
+    code: Option<&'static str>,
+    message: String,
+    refusal: Option<Box<Allowance>>, /* rate limited requests tell when to try again */
+}
+
+impl ApiError {
+    fn invalid_request(message: String) -> Self {
+        ApiError { status: StatusCode::BAD_REQUEST, kind: "invalid_request_error", code: None, message, refusal: None }
+    }
+
+    fn context_length_exceeded(tokens: usize, context_tokens: usize) -> Self {
+        ApiError {
+            status: StatusCode::BAD_REQUEST,
+            kind: "invalid_request_error",
+            code: Some("context_length_exceeded"),
+            message: format!("The prompt holds {} tokens, at most {} are allowed", tokens, context_tokens),
+            refusal: None,
+        }
+    }
+
+    fn invalid_api_key(message: String) -> Self {
+        ApiError { status: StatusCode::UNAUTHORIZED, kind: "invalid_request_error", code: Some("invalid_api_key"), message, refusal: None }
+    }
+
+    fn rate_limited(allowance: Allowance) -> Self {
+        ApiError {
+            status: StatusCode::TOO_MANY_REQUESTS,
+            kind: "rate_limit_error",
+            code: Some("rate_limit_exceeded"),
+            message: allowance.message(),
+            refusal: Some(Box::new(allowance)),
+        }
+    }
+
+    fn server_error(message: String) -> Self {
+        ApiError { status: StatusCode::INTERNAL_SERVER_ERROR, kind: "server_error", code: None, message, refusal: None }
+    }
+
+    fn body(&self) -> Value {
+        json!({
+            "error": {
+                "message": self.message,
+                "type": self.kind,
+                "param": null,
+                "code": self.code,
+            }
+        })
+    }
+}
+
+impl From<ClmError> for ApiError {
+    fn from(error: ClmError) -> Self {
+        ApiError::server_error(error.to_string())
+    }
+}
+
+impl From<&ClmError> for ApiError {
+    fn from(error: &ClmError) -> Self {
+        ApiError::server_error(error.to_string())
+    }
+}
+
+impl From<Arc<ClmError>> for ApiError {
+    fn from(error: Arc<ClmError>) -> Self {
+        ApiError::server_error(error.to_string())
+    }
+}
+
+impl From<Rejection> for ApiError {
+    fn from(rejection: Rejection) -> Self {
+        let code = match rejection {
+            Rejection::QueueFull | Rejection::ClientQueueFull => "queue_full",
+            Rejection::TimedOut => "queue_timeout",
+        };
+        ApiError { status: StatusCode::TOO_MANY_REQUESTS, kind: "rate_limit_error", code: Some(code), message: rejection.to_string(), refusal: None }
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let headers = self.refusal.as_ref().map(|allowance| allowance.headers()).unwrap_or_default();
+        (self.status, headers, Json(self.body())).into_response()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::StatusCode;
+
+    use crate::api::openai::{ChatCompletionRequest, CompletionRequest, Completion, CancelOnDrop, ChoiceEvent, Endpoint, GenerationParameters, Prompt, chat_prompt, generate_choices};
+    use crate::backend::generation::{CancelToken, FinishReason, GenerationConfig};
+    use crate::config::config;
+    use crate::prompt::PromptTemplate;
+    use crate::registry::{GenerationDefaults, ModelConfig, ModelSource, RegisteredModel};
+
+    #[test]
+    fn request_parameters_map_to_generation_config() {
+        let request: CompletionRequest = serde_json::from_str(
+            r#"{"model": "chatclm", "prompt": "hello", "max_tokens": 5, "temperature": 0.5, "top_p": 0.9, "stop": "\n", "n": 2, "seed": 7}"#
+        ).unwrap();
+        let parameters = request.parameters;
+        assert!(parameters.validate(1).is_ok());
+        assert_eq!(parameters.choices(), 2);
+
+        let config = parameters.config(&GenerationConfig::new(), 1);
+        assert_eq!(config.max_new_tokens, 5);
+        assert_eq!(config.stop_strings, vec!["\n".to_string()]);
+        assert_eq!(config.sampling.temperature, 0.5);
+        assert_eq!(config.sampling.top_p, Some(0.9));
+        assert_eq!(config.sampling.seed, Some(8));
+    }
+
+    #[test]
+    fn unset_parameters_keep_model_defaults() {
+        let request: CompletionRequest = serde_json::from_str(r#"{"prompt": "hello", "temperature": 0.5}"#).unwrap();
+        let defaults = GenerationConfig { max_new_tokens: 7, depth: 2, ..GenerationConfig::new() };
+
+        let config = request.parameters.config(&defaults, 0);
+        assert_eq!(config.max_new_tokens, 7);
+        assert_eq!(config.depth, 2);
+        assert_eq!(config.sampling.temperature, 0.5);
+    }
+
+    #[test]
+    fn invalid_parameters_are_rejected() {
+        let request: CompletionRequest = serde_json::from_str(r#"{"prompt": ["a", "b"], "n": 0}"#).unwrap();
+        assert!(request.parameters.validate(2).is_err());
+
+        let request: CompletionRequest = serde_json::from_str(r#"{"prompt": "a", "top_p": 2.0}"#).unwrap();
+        assert!(request.parameters.validate(1).is_err());
+
+        let request: CompletionRequest = serde_json::from_str(r#"{"prompt": ["a", "b", "c", "d", "e"]}"#).unwrap();
+        let prompts = request.prompt.into_vec().len();
+        assert!(request.parameters.validate(prompts).is_err());
+        assert!(request.parameters.validate(0).is_err());
+
+        let request: CompletionRequest = serde_json::from_str(r#"{"prompt": "a", "stop": ["a", "b", "c", "d", "e"]}"#).unwrap();
+        assert!(request.parameters.validate(1).is_err());
+
+        let stop = "x".repeat(65);
+        let request: CompletionRequest = serde_json::from_str(&format!(r#"{{"prompt": "a", "stop": "{}"}}"#, stop)).unwrap();
+        assert!(request.parameters.validate(1).is_err());
+    }
+
+    #[test]
+    fn prompts_beyond_the_context_are_rejected() {
+        let model = RegisteredModel::new(ModelConfig {
+            id: "random".to_string(),
+            name: "Random".to_string(),
+            source: ModelSource::Random,
+            generation: None,
+        }, &GenerationDefaults::default());
+        let prompt = Prompt::Text("word ".repeat(config().limits.context_tokens + 1));
+
+        let error = generate_choices(Endpoint::Completion, &model, &[prompt], &GenerationParameters::default(), &CancelToken::new(), |_| {}).unwrap_err();
+        assert_eq!(error.status, StatusCode::BAD_REQUEST);
+        assert_eq!(error.code, Some("context_length_exceeded"));
+    }
+
+    #[test]
+    fn dropped_requests_cancel_their_generation() {
+        let cancel = CancelToken::new();
+        let guard = CancelOnDrop(cancel.clone());
+        assert!(!cancel.is_cancelled());
+
+        drop(guard);
+        assert!(cancel.is_cancelled());
+    }
+
+    #[test]
+    fn chat_messages_are_rendered_like_the_chat() {
+        let request: ChatCompletionRequest = serde_json::from_str(
+            r#"{"messages": [{"role": "system", "content": "Be brief."}, {"role": "user", "content": "hi there. "}, {"role": "assistant", "content": "hello."}], "stop": ["a", "b"]}"#
+        ).unwrap();
+        let prompt = chat_prompt(&request.messages);
+        let Prompt::Chat(template, history) = &prompt else {
+            panic!("a chat request has to become a conversation");
+        };
+        let text = template.render_with(history, |text| Ok(text.len())).unwrap();
+        assert!(text.ends_with("Be brief.\nUser: hi there.\nChatCLM: hello.\nChatCLM:"));
+        assert_eq!(prompt.stop_strings(), PromptTemplate::stop_strings());
+        assert_eq!(request.parameters.config(&GenerationConfig::new(), 0).stop_strings.len(), 2);
+
+        assert!(serde_json::from_str::<ChatCompletionRequest>(r#"{"messages": [{"role": "robot", "content": "beep"}]}"#).is_err());
+    }
+
+    #[test]
+    fn chat_chunks_follow_the_stream_format() {
+        let completion = Completion { endpoint: Endpoint::Chat, id: "chatcmpl-1".to_string(), created: 0, model: "chatclm".to_string() };
+
+        let start = completion.chunk(ChoiceEvent::Start(0)).unwrap();
+        assert_eq!(start["object"], "chat.completion.chunk");
+        assert_eq!(start["choices"][0]["delta"]["role"], "assistant");
+
+        let token = completion.chunk(ChoiceEvent::Token(0, "hi")).unwrap();
+        assert_eq!(token["choices"][0]["delta"]["content"], "hi");
+
+        let finish = completion.chunk(ChoiceEvent::Finish(0, FinishReason::Timeout)).unwrap();
+        assert_eq!(finish["choices"][0]["finish_reason"], "length");
+
+        let completion = Completion { endpoint: Endpoint::Completion, ..completion };
+        assert!(completion.chunk(ChoiceEvent::Start(0)).is_none());
+    }
+}
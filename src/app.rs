@@ -1,8 +1,11 @@
-use crate::chat::ChatHistory;
+use crate::chat::{get_conversation, ChatHistory, MessageAction};
 use crate::component::chat::Chat;
+use crate::component::compare::ComparePage;
 use crate::component::navbar::NavBar;
 use crate::component::prompt_section::PromptSection;
+use crate::component::sidebar::Sidebar;
 use crate::error_template::{AppError, ErrorTemplate};
+use crate::model::GenerationSettings;
 use leptos::*;
 use leptos_meta::*;
 use leptos_router::*;
@@ -14,13 +17,13 @@ pub fn App() -> impl IntoView {
 
     // create chat as reactive signal object
     let (chat, set_chat) = create_signal(ChatHistory::default());
-    let (selected_model_index, set_selected_model_index) = create_signal(0usize);
-    // fill with dummy data
-    set_chat.update(|chat| {
-        chat.new_server_message("Welcome to ChatCLM!".to_string());
-        chat.new_user_message("Hello!".to_string());
-        chat.new_server_message("Type a message and press Enter to chat.".to_string());
-    });
+    // an empty model id stands for the default model until the registry is loaded
+    let (selected_model, set_selected_model) = create_signal(String::new());
+    // how the answers of the shown conversation are generated
+    let settings = create_rw_signal(GenerationSettings::default());
+    // the stored conversation shown in the chat, a new one is only stored once something is said
+    let conversation_id = create_rw_signal(None::<String>);
+    let conversations_changed = create_rw_signal(0usize);
 
     view! {
         <Stylesheet id="leptos" href="/pkg/chatclm.css"/>
@@ -36,6 +39,7 @@ pub fn App() -> impl IntoView {
         }>
             <main>
                 <Routes>
+                    // both routes share the page, so switching conversations keeps it mounted
                     <Route
                         path=""
                         view=move || {
@@ -43,8 +47,25 @@ pub fn App() -> impl IntoView {
                                 <HomePage
                                     chat=chat
                                     set_chat=set_chat
-                                    selected_model_index=selected_model_index
-                                    set_selected_model_index=set_selected_model_index
+                                    selected_model=selected_model
+                                    set_selected_model=set_selected_model
+                                    settings=settings
+                                    conversation_id=conversation_id
+                                    conversations_changed=conversations_changed
+                                />
+                            }
+                        }
+                    >
+                        <Route path="" view=|| ()/>
+                        <Route path="c/:id" view=|| ()/>
+                    </Route>
+                    <Route
+                        path="compare"
+                        view=move || {
+                            view! {
+                                <ComparePage
+                                    conversation_id=conversation_id
+                                    conversations_changed=conversations_changed
                                 />
                             }
                         }
@@ -59,17 +80,77 @@ pub fn App() -> impl IntoView {
 fn HomePage(
     chat: ReadSignal<ChatHistory>,
     set_chat: WriteSignal<ChatHistory>,
-    selected_model_index: ReadSignal<usize>,
-    set_selected_model_index: WriteSignal<usize>,
+    selected_model: ReadSignal<String>,
+    set_selected_model: WriteSignal<String>,
+    settings: RwSignal<GenerationSettings>,
+    conversation_id: RwSignal<Option<String>>,
+    conversations_changed: RwSignal<usize>,
 ) -> impl IntoView {
+    let location = use_location();
+    let navigate = use_navigate();
+    // the chat asks the prompt section to regenerate or edit, which runs one answer at a time
+    let generating = create_rw_signal(false);
+    let (actions, set_actions) = create_signal(None::<MessageAction>);
+    let route_id = create_memo(move |_| {
+        location.pathname.get().strip_prefix("/c/").map(|id| id.trim_end_matches('/').to_string())
+    });
+
+    // loads the conversation of the route, unless it is the one already shown
+    create_effect(move |_| {
+        let id = route_id.get();
+        if id == conversation_id.get_untracked() {
+            return;
+        }
+        conversation_id.set(id.clone());
+        set_chat.set(ChatHistory::default());
+        settings.set(GenerationSettings::default());
+
+        if let Some(id) = id {
+            let navigate = navigate.clone();
+            spawn_local(async move {
+                let conversation = get_conversation(id.clone()).await;
+                if conversation_id.get_untracked().as_ref() != Some(&id) {
+                    return;
+                }
+                match conversation {
+                    Ok(Some(conversation)) => {
+                        set_chat.set(conversation.history);
+                        set_selected_model.set(conversation.summary.model_id);
+                        settings.set(conversation.settings);
+                    }
+                    _ => navigate("/", Default::default()),
+                }
+            });
+        }
+    });
+
     view! {
-        <NavBar
-            selected_model_index=selected_model_index
-            set_selected_model_index=set_selected_model_index
-        />
+        <Sidebar conversation_id=conversation_id conversations_changed=conversations_changed/>
+
+        <div class="conversation">
+            <NavBar
+                selected_model=selected_model
+                set_selected_model=set_selected_model
+                settings=settings
+                conversation_id=conversation_id
+            />
 
-        <Chat chat=chat/>
+            <Chat
+                chat=chat
+                generating=generating
+                on_action=Callback::new(move |action| set_actions.set(Some(action)))
+            />
 
-        <PromptSection set_chat=set_chat selected_model_index=selected_model_index/>
+            <PromptSection
+                chat=chat
+                set_chat=set_chat
+                selected_model=selected_model
+                settings=settings
+                conversation_id=conversation_id
+                conversations_changed=conversations_changed
+                generating=generating
+                actions=actions
+            />
+        </div>
     }
 }
@@ -0,0 +1,251 @@
+use std::fs::File;
+use std::io::{Read, Write};
+
+use serde::{Deserialize, Serialize};
+
+use crate::backend::clm_model::ClmModel;
+use crate::backend::error::{ClmError, ClmResult};
+use crate::backend::tokenizer::ClmTokenizer;
+use crate::backend::training_options::TrainingOptions;
+use crate::backend::TokenWidth;
+
+// A bundle file is laid out as
+//   magic "CLMB" | format version (u32 LE) | FNV-1a checksum of the body (u64 LE) | body
+// where the body is the MessagePack encoded ModelBundle.
+const MAGIC: &[u8; 4] = b"CLMB";
+const FORMAT_VERSION: u32 = 1;
+const HEADER_SIZE: usize = MAGIC.len() + 4 + 8;
+
+const SQLITE_MAGIC: &[u8] = b"SQLite format 3\0";
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum TokenizerDefinition {
+    Gpt2,
+    Custom(String), /* the tokenizer.json contents */
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct CorpusStatistics {
+    pub sentences: usize,
+    pub tokens: usize,
+    pub bytes: usize,
+}
+
+impl CorpusStatistics {
+    fn combine(statistics: impl IntoIterator<Item = CorpusStatistics>) -> Self {
+        statistics.into_iter().fold(CorpusStatistics::default(), |total, other| CorpusStatistics {
+            sentences: total.sentences + other.sentences,
+            tokens: total.tokens + other.tokens,
+            bytes: total.bytes + other.bytes,
+        })
+    }
+}
+
+// Everything known about how a model was trained, carried along with its dictionary
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct ModelMetadata {
+    pub training_options: Option<TrainingOptions>,
+    pub corpus: Option<CorpusStatistics>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ModelBundle {
+    pub tokenizer: TokenizerDefinition,
+    pub token_width: TokenWidth,
+    pub compression_level: i32, /* used for inference, not for training */
+    pub metadata: ModelMetadata,
+    pub dictionaries: Vec<Vec<u8>>, /* one per model, more than one for ensembles */
+}
+
+pub enum Checkpoint {
+    Bundle(ModelBundle),
+    LegacyDictionary(Vec<u8>),
+    LegacyEnsemble, /* the sqlite checkpoints written before bundles existed */
+}
+
+impl ModelBundle {
+    pub fn from_models<'m, 'a: 'm>(models: impl IntoIterator<Item = &'m ClmModel<'a>>) -> ClmResult<Self> {
+        let models: Vec<&ClmModel> = models.into_iter().collect();
+        let first = models.first().ok_or_else(|| ClmError::Format("Cannot bundle zero models".to_string()))?;
+
+        let corpus = models.iter()
+            .map(|model| model.metadata().corpus.clone())
+            .collect::<Option<Vec<_>>>()
+            .map(CorpusStatistics::combine);
+
+        Ok(ModelBundle {
+            tokenizer: first.tokenizer.to_definition()?,
+            token_width: first.token_width(),
+            compression_level: first.compression_level(),
+            metadata: ModelMetadata {
+                training_options: first.metadata().training_options.clone(),
+                corpus,
+            },
+            dictionaries: models.iter().map(|model| model.to_buffer()).collect(),
+        })
+    }
+
+    pub fn into_models<'a>(self) -> ClmResult<Vec<ClmModel<'a>>> {
+        let tokenizer = ClmTokenizer::from_definition(&self.tokenizer)?;
+        let token_width = tokenizer.token_width()?;
+        if token_width != self.token_width {
+            return Err(ClmError::Format(format!(
+                "Tokenizer needs {:?} tokens but the bundle was written with {:?}", token_width, self.token_width
+            )));
+        }
+
+        self.dictionaries.into_iter()
+            .map(|dictionary| ClmModel::from_parts(dictionary, tokenizer.clone(), self.compression_level, self.metadata.clone()))
+            .collect()
+    }
+
+    pub fn to_bytes(&self) -> ClmResult<Vec<u8>> {
+        let body = rmp_serde::to_vec_named(self)?;
+
+        let mut bytes = Vec::with_capacity(HEADER_SIZE + body.len());
+        bytes.extend_from_slice(MAGIC);
+        bytes.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+        bytes.extend_from_slice(&checksum(&body).to_le_bytes());
+        bytes.extend_from_slice(&body);
+        Ok(bytes)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> ClmResult<Self> {
+        if bytes.len() < HEADER_SIZE || !bytes.starts_with(MAGIC) {
+            return Err(ClmError::Format("Not a model bundle".to_string()));
+        }
+
+        let version = u32::from_le_bytes(bytes[4..8].try_into().unwrap());
+        if version > FORMAT_VERSION {
+            return Err(ClmError::Format(format!(
+                "Model bundle format version {} is newer than the supported version {}", version, FORMAT_VERSION
+            )));
+        }
+
+        let expected_checksum = u64::from_le_bytes(bytes[8..HEADER_SIZE].try_into().unwrap());
+        let body = &bytes[HEADER_SIZE..];
+        if checksum(body) != expected_checksum {
+            return Err(ClmError::Format("Model bundle checksum mismatch, the file is corrupted".to_string()));
+        }
+
+        Ok(rmp_serde::from_slice(body)?)
+    }
+
+    pub fn save(&self, path: &str) -> ClmResult<()> {
+        let mut file = File::create(path)?;
+        file.write_all(&self.to_bytes()?)?;
+        file.flush()?;
+        Ok(())
+    }
+}
+
+impl Checkpoint {
+    pub fn load(path: &str) -> ClmResult<Self> {
+        let mut file = File::open(path)?;
+        let mut buffer = Vec::new();
+        file.read_to_end(&mut buffer)?;
+
+        Ok(if buffer.starts_with(MAGIC) {
+            Checkpoint::Bundle(ModelBundle::from_bytes(&buffer)?)
+        } else if buffer.starts_with(SQLITE_MAGIC) {
+            Checkpoint::LegacyEnsemble
+        } else {
+            Checkpoint::LegacyDictionary(buffer)
+        })
+    }
+}
+
+// FNV-1a, good enough to catch truncated or corrupted files
+fn checksum(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, byte| (hash ^ *byte as u64).wrapping_mul(0x100000001b3))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::backend::bundle::{Checkpoint, CorpusStatistics, ModelBundle, TokenizerDefinition};
+    use crate::backend::clm_model::ClmModel;
+    use crate::backend::error::ClmError;
+    use crate::backend::tests::random_tokens;
+    use crate::backend::tokenizer::ClmTokenizer;
+    use crate::backend::trainer::train_model;
+    use crate::backend::training_options::TrainingOptions;
+    use crate::backend::TokenWidth;
+
+    #[test]
+    fn bundle_roundtrip_keeps_metadata() {
+        let data = random_tokens(100);
+        let model = train_model(&(0..10).map(|_| data.clone()).collect::<Vec<_>>(), &TrainingOptions::new()).unwrap();
+
+        let bytes = ModelBundle::from_models([&model]).unwrap().to_bytes().unwrap();
+        let loaded = ModelBundle::from_bytes(&bytes).unwrap();
+
+        assert_eq!(loaded.token_width, TokenWidth::U8);
+        assert_eq!(loaded.metadata.corpus, Some(CorpusStatistics { sentences: 10, tokens: 1000, bytes: 1000 }));
+        assert_eq!(loaded.metadata.training_options.as_ref().unwrap().d, TrainingOptions::new().d);
+
+        let models = loaded.into_models().unwrap();
+        assert_eq!(models.len(), 1);
+        assert_eq!(models[0].compress(&data).unwrap(), model.compress(&data).unwrap());
+    }
+
+    #[test]
+    fn bundle_keeps_gpt2_tokenizer() {
+        let model = ClmModel::new(Vec::new(), ClmTokenizer::new_gpt2().unwrap()).unwrap();
+        let bytes = ModelBundle::from_models([&model]).unwrap().to_bytes().unwrap();
+        let models = ModelBundle::from_bytes(&bytes).unwrap().into_models().unwrap();
+        assert_eq!(models[0].token_width(), TokenWidth::U16);
+    }
+
+    #[test]
+    fn corrupted_bundle_is_rejected() {
+        let model = train_model(&Vec::new(), &TrainingOptions::new()).unwrap();
+        let mut bytes = ModelBundle::from_models([&model]).unwrap().to_bytes().unwrap();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 0xff;
+
+        let error = ModelBundle::from_bytes(&bytes).unwrap_err();
+        assert!(matches!(error, ClmError::Format(message) if message.contains("checksum")));
+    }
+
+    #[test]
+    fn oversized_vocabulary_fails_to_load() {
+        let vocab = (0..=(1 << 16)).map(|id| format!("\"t{}\": {}", id, id)).collect::<Vec<_>>().join(", ");
+        let tokenizer = format!(r#"{{"version": "1.0", "truncation": null, "padding": null, "added_tokens": [], "normalizer": null,
+            "pre_tokenizer": null, "post_processor": null, "decoder": null,
+            "model": {{"type": "WordLevel", "vocab": {{{}}}, "unk_token": "t0"}}}}"#, vocab);
+        let model = train_model(&Vec::new(), &TrainingOptions::new()).unwrap();
+        let bundle = ModelBundle { tokenizer: TokenizerDefinition::Custom(tokenizer), ..ModelBundle::from_models([&model]).unwrap() };
+
+        assert!(matches!(bundle.into_models(), Err(ClmError::Tokenizer(message)) if message.starts_with("Vocabularies")));
+    }
+
+    #[test]
+    fn invalid_dictionaries_fail_to_load() {
+        // the magic of a trained dictionary followed by garbage, as a truncated file could start
+        let mut garbage = vec![0x37, 0xa4, 0x30, 0xec];
+        garbage.extend((0..64u8).map(|byte| byte.wrapping_mul(97)));
+        std::fs::write("invalid_dictionary_test.zstd_dict", &garbage).unwrap();
+        let loaded = ClmModel::from_checkpoint("invalid_dictionary_test.zstd_dict");
+        std::fs::remove_file("invalid_dictionary_test.zstd_dict").unwrap();
+        assert!(matches!(loaded, Err(ClmError::Format(message)) if message.contains("dictionary")));
+
+        // a bundle whose checksum is fine but whose dictionary is not
+        let model = train_model(&Vec::new(), &TrainingOptions::new()).unwrap();
+        let bundle = ModelBundle { dictionaries: vec![garbage], ..ModelBundle::from_models([&model]).unwrap() };
+        let bytes = bundle.to_bytes().unwrap();
+        assert!(matches!(ModelBundle::from_bytes(&bytes).unwrap().into_models(), Err(ClmError::Format(_))));
+    }
+
+    #[test]
+    fn raw_dictionaries_are_legacy_checkpoints() {
+        let data = random_tokens(100);
+        let model = train_model(&(0..10).map(|_| data.clone()).collect::<Vec<_>>(), &TrainingOptions::new()).unwrap();
+        std::fs::write("legacy_test.zstd_dict", model.to_buffer()).unwrap();
+
+        let checkpoint = Checkpoint::load("legacy_test.zstd_dict").unwrap();
+        assert!(matches!(checkpoint, Checkpoint::LegacyDictionary(ref buffer) if *buffer == model.to_buffer()));
+
+        // cleanup
+        std::fs::remove_file("legacy_test.zstd_dict").unwrap();
+    }
+}
@@ -1,151 +1,241 @@
-use std::fs::File;
 use std::io::{Read, Write};
 
-use itertools::Itertools;
 use rand::prelude::SliceRandom;
 use rand::thread_rng;
-use rayon::iter::{ParallelBridge, ParallelIterator};
 use zstd::dict::{DecoderDictionary, EncoderDictionary};
+use zstd::zstd_safe::{CDict, DDict};
 
 use crate::backend;
-use crate::backend::{INFERENCE_COMPRESSION_LEVEL, MAX_TOKEN, Token};
+use crate::backend::bundle::{Checkpoint, ModelBundle, ModelMetadata};
+use crate::backend::error::{zstd_error, ClmError, ClmResult};
+use crate::backend::generation::CancelToken;
+use crate::backend::{INFERENCE_COMPRESSION_LEVEL, Token, TokenWidth};
+use crate::backend::prefix_scorer::{EncoderPool, PrefixScorer};
+use crate::backend::sampling::Sampler;
 use crate::backend::tokenizer::ClmTokenizer;
 
+// The best continuation, its score and the sizes of all candidates for its first token
+type BeamSearch = (Vec<Token>, usize, Vec<(Token, usize)>);
+
 pub struct ClmModel<'a> {
+    encoders: EncoderPool<'a>, /* reference `dict`, so they go before it */
     dict: EncoderDictionary<'a>,
     model_buffer: Vec<u8>,
     pub(crate) tokenizer: ClmTokenizer,
+    token_width: TokenWidth,
+    candidates: Vec<Token>,
+    compression_level: i32,
+    metadata: ModelMetadata,
 }
 
 impl Clone for ClmModel<'_> {
     fn clone(&self) -> Self {
-        let dict = EncoderDictionary::copy(&*self.model_buffer, INFERENCE_COMPRESSION_LEVEL);
-        let tokenizer = self.tokenizer.clone();
-        Self { dict, model_buffer: self.model_buffer.clone(), tokenizer }
+        ClmModel {
+            encoders: EncoderPool::default(),
+            dict: EncoderDictionary::copy(&self.model_buffer, self.compression_level),
+            model_buffer: self.model_buffer.clone(),
+            tokenizer: self.tokenizer.clone(),
+            token_width: self.token_width,
+            candidates: self.candidates.clone(),
+            compression_level: self.compression_level,
+            metadata: self.metadata.clone(),
+        }
     }
 }
 
 
 impl<'a> ClmModel<'a> {
-    pub fn from_buffer(model_buffer: Vec<u8>) -> Self {
-        let dict = EncoderDictionary::copy(&*model_buffer, INFERENCE_COMPRESSION_LEVEL);
-        let tokenizer = ClmTokenizer::new_custom();
-        Self { dict, model_buffer, tokenizer }
+    pub fn new(model_buffer: Vec<u8>, tokenizer: ClmTokenizer) -> ClmResult<Self> {
+        ClmModel::from_parts(model_buffer, tokenizer, INFERENCE_COMPRESSION_LEVEL, ModelMetadata::default())
+    }
+
+    pub fn from_parts(model_buffer: Vec<u8>, tokenizer: ClmTokenizer, compression_level: i32, metadata: ModelMetadata) -> ClmResult<Self> {
+        let dict = prepared_dictionary(&model_buffer, compression_level)?;
+        let token_width = tokenizer.token_width()?;
+        let candidates = tokenizer.candidate_tokens();
+        Ok(Self { encoders: EncoderPool::default(), dict, model_buffer, tokenizer, token_width, candidates, compression_level, metadata })
+    }
+
+    pub fn from_buffer(model_buffer: Vec<u8>) -> ClmResult<Self> {
+        ClmModel::new(model_buffer, ClmTokenizer::new_custom()?)
+    }
+
+    pub fn token_width(&self) -> TokenWidth {
+        self.token_width
+    }
+
+    pub fn compression_level(&self) -> i32 {
+        self.compression_level
+    }
+
+    pub fn metadata(&self) -> &ModelMetadata {
+        &self.metadata
+    }
+
+    pub fn prefix_scorer(&self, tokens: &[Token]) -> ClmResult<PrefixScorer<'_, 'a>> {
+        PrefixScorer::new(&self.dict, &self.encoders, tokens, self.token_width)
     }
 
     pub fn to_buffer(&self) -> Vec<u8> {
         self.model_buffer.clone()
     }
 
-    pub fn compress(&self, tokens: &Vec<Token>) -> Vec<u8> {
-        let raw_data = backend::tokens_to_bytes(tokens);
+    pub fn compress(&self, tokens: &[Token]) -> ClmResult<Vec<u8>> {
+        let raw_data = backend::tokens_to_bytes(tokens, self.token_width)?;
 
         // Actual compression
-        let mut writer = zstd::stream::write::Encoder::with_prepared_dictionary(Vec::new(), &self.dict).unwrap();
-        writer.write_all(&raw_data).unwrap();
-        let compressed = writer.finish().unwrap();
-        compressed
+        let mut writer = zstd::stream::write::Encoder::with_prepared_dictionary(Vec::new(), &self.dict).map_err(zstd_error)?;
+        writer.write_all(&raw_data).map_err(zstd_error)?;
+        writer.finish().map_err(zstd_error)
     }
 
-    pub fn predict_next(&self, prompt: String, depth: usize, width: usize) -> String {
-        let mut tokens = self.tokenizer.encode(&prompt);
-        let (next_token, _size) = self.predict_tokens(&tokens, depth, width);
-        tokens.push(next_token);
+    pub fn predict_next(&self, prompt: String, depth: usize, width: usize) -> ClmResult<String> {
+        let mut tokens = self.tokenizer.encode(&prompt)?;
+        let (continuation, _score) = self.beam_search(&tokens, depth + 1, width)?;
+        if let Some(next_token) = continuation.first() {
+            tokens.push(*next_token);
+        }
         self.tokenizer.decode(tokens)
     }
 
-    fn get_next_token_sizes(&self, tokens: &Vec<Token>) -> Vec<(Token, usize)> {
-        (1..MAX_TOKEN)
-            .par_bridge()
-            .map(|x| x as Token)
-            .map(|x| {
-                let mut prompt = tokens.clone();
-                prompt.push(x);
-                (x, self.compress(&prompt).len())
-            }).collect()
+    pub fn sample_next(&self, prompt: String, sampler: &mut Sampler) -> ClmResult<String> {
+        let mut tokens = self.tokenizer.encode(&prompt)?;
+        if let Some(next_token) = self.sample_next_token(&tokens, sampler)? {
+            tokens.push(next_token);
+        }
+        self.tokenizer.decode(tokens)
     }
 
-    fn predict_tokens(&self, tokens: &Vec<Token>, depth: usize, width: usize) -> (Token, usize) {
-        let mut c = self.get_next_token_sizes(tokens);
+    pub fn sample_next_token(&self, tokens: &[Token], sampler: &mut Sampler) -> ClmResult<Option<Token>> {
+        let sizes = self.get_next_token_sizes(tokens)?
+            .into_iter()
+            .map(|(token, size)| (token, size as f64))
+            .collect::<Vec<_>>();
+        Ok(sampler.sample(&sizes))
+    }
 
-        c.shuffle(&mut thread_rng());
+    pub fn get_next_token_sizes(&self, tokens: &[Token]) -> ClmResult<Vec<(Token, usize)>> {
+        self.next_token_sizes_until(tokens, &CancelToken::new())
+    }
 
-        c.sort_by(|a, b| a.1.cmp(&b.1));
+    pub(crate) fn next_token_sizes_until(&self, tokens: &[Token], cancel: &CancelToken) -> ClmResult<Vec<(Token, usize)>> {
+        self.prefix_scorer(tokens)?.candidate_sizes_until(self.candidates.clone(), cancel)
+    }
 
-        let mut hist = c.iter().map(|x| x.1).counts().iter().map(|(k, v)| (*k, *v)).collect::<Vec<_>>();
-        hist.sort_by(|a, b| a.1.cmp(&b.1));
+    // Searches `length` tokens ahead, keeping the `width` best beams at every step.
+    // A beam is scored by the total number of bytes its tokens add to the compressed prompt.
+    pub fn beam_search(&self, tokens: &[Token], length: usize, width: usize) -> ClmResult<(Vec<Token>, usize)> {
+        let (continuation, score, _) = self.beam_search_until(tokens, length, width, &CancelToken::new())?;
+        Ok((continuation, score))
+    }
 
-        println!("Predicting tokens: {:?}", hist);
+    // Also returns the compressed sizes of all candidates for the first position, the first step scores them anyway
+    pub(crate) fn beam_search_until(&self, tokens: &[Token], length: usize, width: usize, cancel: &CancelToken) -> ClmResult<BeamSearch> {
+        let width = width.max(1);
+        let prompt_size = self.compress(tokens)?.len();
 
-        if depth == 0 {
-            return c[0];
-        }
+        let mut first_sizes = Vec::new();
+        let mut beams: Vec<(Vec<Token>, usize)> = vec![(Vec::new(), 0)];
+        for _ in 0..length {
+            if cancel.is_cancelled() {
+                break;
+            }
 
-        let mut best = (0, std::usize::MAX);
-        for (token, _) in c.iter().take(width) {
-            let (_next_token, next_compression) = self.predict_tokens(tokens, depth - 1, width / 2);
-            if next_compression < best.1 {
-                best = (*token, next_compression);
+            let mut candidates = Vec::new();
+            for (sequence, _) in beams.iter() {
+                let mut prefix = tokens.to_vec();
+                prefix.extend(sequence);
+
+                let mut sizes = self.next_token_sizes_until(&prefix, cancel)?;
+                if sequence.is_empty() {
+                    first_sizes = sizes.clone();
+                }
+                // shuffle before the stable sort so equally sized tokens are picked at random
+                sizes.shuffle(&mut thread_rng());
+                sizes.sort_by_key(|(_, size)| *size);
+
+                for (token, size) in sizes.into_iter().take(width) {
+                    let mut continuation = sequence.clone();
+                    continuation.push(token);
+                    candidates.push((continuation, size.saturating_sub(prompt_size)));
+                }
             }
+
+            candidates.sort_by_key(|(_, score)| *score);
+            candidates.truncate(width);
+            beams = candidates;
         }
-        best
+
+        let (continuation, score) = beams.into_iter().next().unwrap_or_default();
+        Ok((continuation, score, first_sizes))
     }
 
-    pub fn decompress_to_tokens(&self, compressed: &[u8]) -> Vec<Token> {
+    pub fn decompress_to_tokens(&self, compressed: &[u8]) -> ClmResult<Vec<Token>> {
+        DDict::try_create(&self.model_buffer).ok_or_else(invalid_dictionary)?;
         let dict = DecoderDictionary::copy(self.model_buffer.as_slice());
-        let mut reader = zstd::stream::read::Decoder::with_prepared_dictionary(compressed, &dict).unwrap();
+        let mut reader = zstd::stream::read::Decoder::with_prepared_dictionary(compressed, &dict).map_err(zstd_error)?;
 
         let mut decompressed = Vec::new();
-        reader.read_to_end(&mut decompressed).unwrap();
-
-        let mut tokens = Vec::new();
-        for i in decompressed.chunks(backend::BYTES_PER_TOKEN) {
-            if backend::BYTES_PER_TOKEN == 1 {
-                tokens.push(Token::from_be_bytes([i[0]]));
-            } else if backend::BYTES_PER_TOKEN == 8 {
-                todo!("Implement 8 byte tokens")
-                //tokens.push(Token::from_be_bytes([i[0], i[1], i[2], i[3], i[4], i[5], i[6], i[7]]));
-            }
-        }
+        reader.read_to_end(&mut decompressed).map_err(zstd_error)?;
 
-        tokens
+        Ok(backend::bytes_to_tokens(&decompressed, self.token_width))
     }
 
-    pub fn evaluate(&self, test_data: &Vec<Vec<Token>>) -> f64 {
+    pub fn evaluate(&self, test_data: &Vec<Vec<Token>>) -> ClmResult<f64> {
         let mut total = 0;
         let mut correct = 0;
         for tokens in test_data {
-            let compressed = self.compress(tokens);
-            let decompressed = self.decompress_to_tokens(&compressed);
+            let compressed = self.compress(tokens)?;
+            let decompressed = self.decompress_to_tokens(&compressed)?;
             total += tokens.len();
             correct += tokens.iter().zip(decompressed.iter()).filter(|(a, b)| a == b).count();
         }
-        correct as f64 / total as f64
+        Ok(correct as f64 / total as f64)
     }
 
     pub fn get_dictionary_size(&self) -> usize {
         self.model_buffer.len()
     }
 
-    #[allow(dead_code)]
-    pub(crate) fn save_checkpoint(&self, path: &str) {
-        // write the buffer as Vec<u8> to a flat file
-        let mut file = File::create(path).unwrap();
-        file.write_all(&self.model_buffer).unwrap();
-        file.flush().unwrap();
+    // Trained dictionaries carry an id in their header, raw content dictionaries have none
+    pub fn dictionary_id(&self) -> Option<u32> {
+        zstd::zstd_safe::get_dict_id_from_dict(&self.model_buffer).map(|id| id.get())
     }
 
-    pub(crate) fn from_checkpoint(path: &str) -> ClmModel<'a> {
-        // read the buffer from a flat file and return a new ClmModel
-        let mut file = File::open(path).unwrap();
-        let mut buffer = Vec::new();
-        file.read_to_end(&mut buffer).unwrap();
-        ClmModel::from_buffer(buffer)
+    pub fn save_checkpoint(&self, path: &str) -> ClmResult<()> {
+        ModelBundle::from_models([self])?.save(path)
     }
 
-    pub(crate) fn compress_together(&self, prompt: &Vec<Token>, next: &Vec<Token>) -> usize {
-        let mut prompt = prompt.clone();
+    pub fn from_checkpoint(path: &str) -> ClmResult<ClmModel<'a>> {
+        let not_single_model = || ClmError::Format(format!("{} holds an ensemble, not a single model", path));
+        match Checkpoint::load(path)? {
+            Checkpoint::Bundle(bundle) => {
+                let mut models = bundle.into_models()?;
+                if models.len() != 1 {
+                    return Err(not_single_model());
+                }
+                Ok(models.remove(0))
+            }
+            // raw dictionaries from before bundles existed were always trained with the custom tokenizer
+            Checkpoint::LegacyDictionary(buffer) => ClmModel::from_buffer(buffer),
+            Checkpoint::LegacyEnsemble => Err(not_single_model()),
+        }
+    }
+
+    pub(crate) fn compress_together(&self, prompt: &[Token], next: &[Token]) -> ClmResult<usize> {
+        let mut prompt = prompt.to_vec();
         prompt.extend(next);
-        self.compress(&prompt).len()
+        Ok(self.compress(&prompt)?.len())
     }
 }
+
+// `EncoderDictionary::copy` panics on a dictionary zstd cannot read, which a corrupted or truncated
+// checkpoint easily is, so zstd gets to reject it first
+fn prepared_dictionary(model_buffer: &[u8], compression_level: i32) -> ClmResult<EncoderDictionary<'static>> {
+    CDict::try_create(model_buffer, compression_level).ok_or_else(invalid_dictionary)?;
+    Ok(EncoderDictionary::copy(model_buffer, compression_level))
+}
+
+fn invalid_dictionary() -> ClmError {
+    ClmError::Format("The model dictionary is not a valid zstd dictionary".to_string())
+}
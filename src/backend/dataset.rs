@@ -12,6 +12,7 @@ use serde::{Deserialize, Serialize};
 use rmp_serde::{Deserializer, Serializer};
 
 use crate::backend::{DATA_PATH, Token};
+use crate::backend::error::ClmResult;
 use crate::backend::tokenizer::ClmTokenizer;
 
 #[derive(Serialize, Deserialize, Clone)]
@@ -23,43 +24,50 @@ pub struct Dataset {
 
 impl Dataset {
     fn locate_data_files() -> Vec<String> {
-        glob(&format!("{}/**/*-sentences.txt", DATA_PATH))
+        Self::locate_data_files_in(DATA_PATH)
+    }
+
+    fn locate_data_files_in(path: &str) -> Vec<String> {
+        glob(&format!("{}/**/*-sentences.txt", path))
             .expect("Failed to read glob pattern")
             .filter_map(Result::ok)
             .map(|x| x.display().to_string())
             .collect()
     }
 
-    fn compute_from_files(files: Vec<String>) -> Dataset {
-        let tokenizer = Self::get_tokenizer();
+    fn compute_from_files(files: Vec<String>) -> ClmResult<Dataset> {
+        let tokenizer = Self::get_tokenizer()?;
         let re = Regex::new(r"^\d+\s").unwrap();
 
         let pb = indicatif::ProgressBar::new(0);
         pb.set_style(indicatif::ProgressStyle::default_bar().template("{msg} {bar:60.cyan/blue} {pos}/{len} {per_sec}").unwrap());
         pb.set_message("Tokenizing");
-        pb.set_length(files.iter().map(|file|
-              File::open(file).unwrap().metadata().unwrap().len()
-        ).sum());
+        let mut total_length = 0;
+        let mut readers = Vec::new();
+        for filename in files.iter() {
+            let file = File::open(filename)?;
+            total_length += file.metadata()?.len();
+            readers.push(BufReader::new(file));
+        }
+        pb.set_length(total_length);
 
 
-        let tokens = files.iter()
-            .flat_map(|filename| {
-                let file = File::open(filename).unwrap();
-                BufReader::new(file).lines()
-            })
+        let tokens = readers.into_iter()
+            .flat_map(|reader| reader.lines())
             .par_bridge()
-            .map(|x| x.expect("Failed to read line"))
-            .map(|x| {pb.inc(x.len() as u64); x})
-            .map(|x| re.replace_all(&x, "").to_string())
-            .map(|x| tokenizer.encode(&x))
-            .collect();
+            .map(|line| {
+                let line = line?;
+                pb.inc(line.len() as u64);
+                tokenizer.encode(&re.replace_all(&line, ""))
+            })
+            .collect::<ClmResult<_>>()?;
 
         pb.finish();
 
         println!("Shuffling dataset");
         let mut dataset = Dataset { data: tokens };
         dataset.shuffle();
-        dataset
+        Ok(dataset)
 
     }
 
@@ -69,18 +77,19 @@ impl Dataset {
         (Dataset { data: train.to_vec() }, Dataset { data: test.to_vec() })
     }
 
-    fn save_to_file(&self, filename: &str) {
-        let file = File::create(filename).unwrap();
+    fn save_to_file(&self, filename: &str) -> ClmResult<()> {
+        let file = File::create(filename)?;
         let mut writer = BufWriter::new(file);
         let mut serializer = Serializer::new(&mut writer);
-        self.serialize(&mut serializer).unwrap();
+        self.serialize(&mut serializer)?;
+        Ok(())
     }
 
-    pub fn load_from_file(filename: &str) -> Dataset {
-        let file = File::open(filename).unwrap();
+    pub fn load_from_file(filename: &str) -> ClmResult<Dataset> {
+        let file = File::open(filename)?;
         let mut reader = BufReader::new(file);
         let mut deserializer = Deserializer::new(&mut reader);
-        Dataset::deserialize(&mut deserializer).unwrap()
+        Ok(Dataset::deserialize(&mut deserializer)?)
     }
 
     pub fn shuffle(&mut self) {
@@ -88,13 +97,13 @@ impl Dataset {
         self.data.shuffle(&mut rng);
     }
 
-    pub fn load_or_compute(filename: &str) -> Dataset {
+    pub fn load_or_compute(filename: &str) -> ClmResult<Dataset> {
         if std::path::Path::new(filename).exists() {
             Dataset::load_from_file(filename)
         } else {
-            let dataset = Dataset::compute_from_files(Dataset::locate_data_files());
-            dataset.save_to_file(filename);
-            dataset
+            let dataset = Dataset::compute_from_files(Dataset::locate_data_files())?;
+            dataset.save_to_file(filename)?;
+            Ok(dataset)
         }
     }
 
@@ -160,26 +169,22 @@ impl Dataset {
         Dataset { data: Vec::new() }
     }
 
-    pub fn get_tokenizer() -> ClmTokenizer {
+    pub fn get_tokenizer() -> ClmResult<ClmTokenizer> {
         ClmTokenizer::new_custom()
     }
-    pub fn tokenize(text: &str) -> Vec<Token> {
-        Self::get_tokenizer().encode(text)
+    pub fn tokenize(text: &str) -> ClmResult<Vec<Token>> {
+        Self::get_tokenizer()?.encode(text)
     }
     
-    pub fn detokenize(tokens: Vec<Token>) -> String {
-        Self::get_tokenizer().decode(tokens)
+    pub fn detokenize(tokens: Vec<Token>) -> ClmResult<String> {
+        Self::get_tokenizer()?.decode(tokens)
     }
 
     #[cfg(test)]
     pub fn test_dataset() -> Dataset {
         let mut data = Vec::new();
         for _ in 0..20 {
-            let mut sentence = Vec::new();
-            for _ in 0..100 {
-                sentence.push(rand::random());
-            }
-            data.push(sentence);
+            data.push(crate::backend::tests::random_tokens(100));
         }
         Dataset { data }
     }
@@ -192,19 +197,28 @@ mod tests {
 
     #[test]
     fn test_locate_data_files() {
-        let files = Dataset::locate_data_files();
-        assert_ne!(files.len(), 0);
+        // the corpus itself is not checked in, so look for the files in a copy of its layout
+        let path = std::env::temp_dir().join(format!("chatclm-data-{}", rand::random::<u64>()));
+        std::fs::create_dir_all(path.join("eng_news_2023_1M")).unwrap();
+        std::fs::write(path.join("eng_news_2023_1M/eng_news_2023_1M-sentences.txt"), "1\tA sentence.\n").unwrap();
+        std::fs::write(path.join("eng_news_2023_1M/eng_news_2023_1M-words.txt"), "1\ta\n").unwrap();
+
+        let files = Dataset::locate_data_files_in(path.to_str().unwrap());
+        assert_eq!(files.len(), 1);
+        assert!(files[0].ends_with("eng_news_2023_1M-sentences.txt"));
+
+        std::fs::remove_dir_all(path).unwrap();
     }
 
     #[test]
     fn save_and_load_dataset() {
         let start_time = std::time::Instant::now();
-        let dataset = Dataset::compute_from_files(vec!["./data/tests.txt".to_string()]);
+        let dataset = Dataset::compute_from_files(vec!["./data/tests.txt".to_string()]).unwrap();
         println!("Dataset computed in {:?}", start_time.elapsed());
-        dataset.save_to_file("dataset.msgpack");
+        dataset.save_to_file("dataset.msgpack").unwrap();
 
         let load_time = std::time::Instant::now();
-        let loaded_dataset = Dataset::load_from_file("dataset.msgpack");
+        let loaded_dataset = Dataset::load_from_file("dataset.msgpack").unwrap();
         println!("Dataset loaded in {:?}", load_time.elapsed());
         println!("Computed file size: {:?}", std::fs::metadata("dataset.msgpack").unwrap().len());
         println!("Original file size: {:?}", std::fs::metadata("./data/tests.txt").unwrap().len());
@@ -223,7 +237,7 @@ mod tests {
 
     #[test]
     fn test_compute_from_files() {
-        let dataset = Dataset::compute_from_files(vec!["data/tests.txt".to_string()]);
+        let dataset = Dataset::compute_from_files(vec!["data/tests.txt".to_string()]).unwrap();
         assert_eq!(dataset.data.len(), 1000);
 
         // no sentence should be empty
@@ -232,7 +246,7 @@ mod tests {
 
     #[test]
     fn shrink_dataset() {
-        let dataset = Dataset::compute_from_files(vec!["data/tests.txt".to_string()]);
+        let dataset = Dataset::compute_from_files(vec!["data/tests.txt".to_string()]).unwrap();
         let shrunk = dataset.shrink_to_size(1000);
         assert_eq!(shrunk.data.iter().map(|x| x.len()).sum::<usize>(), 1000);
     }
@@ -240,7 +254,7 @@ mod tests {
 
     #[test]
     fn split_into_chunks() {
-        let dataset = Dataset::compute_from_files(vec!["data/tests.txt".to_string()]);
+        let dataset = Dataset::compute_from_files(vec!["data/tests.txt".to_string()]).unwrap();
         let chunks = dataset.split_into_chunks(10).collect_vec();
         assert_eq!(chunks.len(), 10);
         assert_eq!(chunks.iter().map(|x| x.data.len()).sum::<usize>(), dataset.data.len());
@@ -248,7 +262,7 @@ mod tests {
     
     #[test]
     fn join_lines() {
-        let dataset = Dataset::compute_from_files(vec!["data/tests.txt".to_string()]);
+        let dataset = Dataset::compute_from_files(vec!["data/tests.txt".to_string()]).unwrap();
         let joined = dataset.join_lines(100);
         // the last line can be shorter than 100, it doesn't matter
         let min_len = joined.data.iter().rev().skip(1).map(|x| x.len()).min().unwrap();
@@ -2,8 +2,12 @@
 use rayon::prelude::*;
 use rusqlite::Connection;
 
+use crate::backend::bundle::{Checkpoint, ModelBundle};
 use crate::backend::clm_model::ClmModel;
+use crate::backend::error::{ClmError, ClmResult};
 use crate::backend::dataset::Dataset;
+use crate::backend::generation::CancelToken;
+use crate::backend::tokenizer::ClmTokenizer;
 use crate::backend::Token;
 use crate::backend::trainer::train_model;
 use crate::backend::training_options::TrainingOptions;
@@ -12,12 +16,12 @@ pub struct EnsembleModel<'a> {
     models: Vec<ClmModel<'a>>,
 }
 
-impl EnsembleModel<'_> {
-    pub fn train(data: Dataset, options: &TrainingOptions) -> Self {
+impl<'a> EnsembleModel<'a> {
+    pub fn train(data: Dataset, options: &TrainingOptions) -> ClmResult<Self> {
 
         if data.get_data().is_empty() {
-            let models : Vec<ClmModel> = (0..options.ensemble_size).map(|_| ClmModel::from_buffer(vec![])).collect();
-            return EnsembleModel { models: models };
+            let models = (0..options.ensemble_size).map(|_| ClmModel::from_buffer(vec![])).collect::<ClmResult<Vec<ClmModel>>>()?;
+            return Ok(EnsembleModel { models });
         }
 
         let chunks  =
@@ -32,60 +36,87 @@ impl EnsembleModel<'_> {
             let model = train_model(chunk.get_data(), options);
             progress_bar.inc(1);
             model
-        }).collect();
+        }).collect::<ClmResult<_>>()?;
 
         progress_bar.finish();
 
-        EnsembleModel { models }
+        Ok(EnsembleModel { models })
     }
 
-    pub fn save_checkpoint(&self, path: &str) {
-        // delete the file if it already exists
-
-        if std::path::Path::new(path).exists() {
-            println!("Model checkpoint already exists, deleting it");
-            std::fs::remove_file(path).unwrap();
-        }
-
-        // write all models to a sqlite database
-        let conn = Connection::open(path).unwrap();
-        conn.execute("CREATE TABLE models (id INTEGER PRIMARY KEY, model BLOB)", []).unwrap();
+    pub fn save_checkpoint(&self, path: &str) -> ClmResult<()> {
+        ModelBundle::from_models(&self.models)?.save(path)
+    }
 
-        for (i, model) in self.models.iter().enumerate() {
-            let mut stmt = conn.prepare("INSERT INTO models (id, model) VALUES (?, ?)").unwrap();
-            let model_bytes = model.to_buffer();
-            stmt.execute((&i, &model_bytes)).unwrap();
+    pub fn from_checkpoint(path: &str) -> ClmResult<Self> {
+        match Checkpoint::load(path)? {
+            Checkpoint::Bundle(bundle) => Ok(EnsembleModel { models: bundle.into_models()? }),
+            Checkpoint::LegacyDictionary(buffer) => Ok(EnsembleModel { models: vec![ClmModel::from_buffer(buffer)?] }),
+            Checkpoint::LegacyEnsemble => Self::from_legacy_checkpoint(path),
         }
-
-        conn.close().unwrap();
     }
 
-    pub fn from_checkpoint(path: &str) -> Self {
+    fn from_legacy_checkpoint(path: &str) -> ClmResult<Self> {
         // read all models from a sqlite database
-        let  conn = Connection::open(path).unwrap();
-        let models;
+        let conn = Connection::open(path)?;
+        let buffers;
         {
-            let mut stmt = conn.prepare("SELECT model FROM models").unwrap();
-            models = stmt.query_map([], |row| {
-                let model_bytes: Vec<u8> = row.get(0)?;
-                Ok(ClmModel::from_buffer(model_bytes))
-            }).unwrap().map(|x| x.unwrap()).collect();
+            let mut stmt = conn.prepare("SELECT model FROM models")?;
+            buffers = stmt.query_map([], |row| row.get::<_, Vec<u8>>(0))?.collect::<Result<Vec<_>, _>>()?;
         }
-        conn.close().unwrap();
-        EnsembleModel { models }
+        conn.close().map_err(|(_, error)| error)?;
+
+        let models = buffers.into_iter().map(ClmModel::from_buffer).collect::<ClmResult<_>>()?;
+        Ok(EnsembleModel { models })
     }
 
-    pub fn compressed_size(&self, tokens: &Vec<Token>) -> f64 {
+    pub fn models(&self) -> &[ClmModel<'a>] {
+        &self.models
+    }
+
+    pub fn first_model(&self) -> Option<&ClmModel<'a>> {
+        self.models.first()
+    }
+
+    // All members are trained with the same tokenizer
+    pub fn tokenizer(&self) -> ClmResult<&ClmTokenizer> {
+        self.first_model()
+            .map(|model| &model.tokenizer)
+            .ok_or_else(|| ClmError::Format("ensemble without models".to_string()))
+    }
+
+    pub fn compressed_size(&self, tokens: &[Token]) -> ClmResult<f64> {
         let mut total_size = 0.0;
         for model in &self.models {
-            total_size += model.compress(tokens).len() as f64;
+            total_size += model.compress(tokens)?.len() as f64;
         }
-        total_size / self.models.len() as f64
+        Ok(total_size / self.models.len() as f64)
+    }
+
+    pub fn get_next_token_sizes(&self, tokens: &[Token]) -> ClmResult<Vec<(Token, f64)>> {
+        self.next_token_sizes_until(tokens, &CancelToken::new())
+    }
+
+    pub(crate) fn next_token_sizes_until(&self, tokens: &[Token], cancel: &CancelToken) -> ClmResult<Vec<(Token, f64)>> {
+        let Some(first_model) = self.models.first() else {
+            return Ok(Vec::new());
+        };
+
+        let mut sizes: Vec<(Token, f64)> = first_model.tokenizer.candidate_tokens().into_iter().map(|token| (token, 0.0)).collect();
+        for model in &self.models {
+            // candidates come back in the same order for every model
+            for (total, (_, size)) in sizes.iter_mut().zip(model.next_token_sizes_until(tokens, cancel)?) {
+                total.1 += size as f64;
+            }
+        }
+        sizes.iter_mut().for_each(|(_, size)| *size /= self.models.len() as f64);
+        Ok(sizes)
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use rusqlite::Connection;
+
     use crate::backend::dataset::Dataset;
     use crate::backend::ensemble_model::EnsembleModel;
     use crate::backend::training_options::TrainingOptions;
@@ -94,9 +125,8 @@ mod tests {
     fn training_works() {
         let dataset = Dataset::test_dataset();
 
-        let mut options = TrainingOptions::default();
-        options.ensemble_size = 2;
-        let trained_model = EnsembleModel::train(dataset, &options);
+        let options = TrainingOptions { ensemble_size: 2, ..TrainingOptions::default() };
+        let trained_model = EnsembleModel::train(dataset, &options).unwrap();
 
         assert_eq!(trained_model.models.len(), 2);
     }
@@ -105,13 +135,12 @@ mod tests {
     fn compressed_size_improves() {
         let dataset = Dataset::test_dataset();
 
-        let mut options = TrainingOptions::default();
-        options.ensemble_size = 2;
-        let trained_model = EnsembleModel::train(dataset.clone(), &options);
-        let untrained_model = EnsembleModel::train(Dataset::empty(), &options);
+        let options = TrainingOptions { ensemble_size: 2, ..TrainingOptions::default() };
+        let trained_model = EnsembleModel::train(dataset.clone(), &options).unwrap();
+        let untrained_model = EnsembleModel::train(Dataset::empty(), &options).unwrap();
 
-        let trained_size = trained_model.compressed_size(&dataset.get_data()[0]);
-        let naive_size = untrained_model.compressed_size(&dataset.get_data()[0]);
+        let trained_size = trained_model.compressed_size(&dataset.get_data()[0]).unwrap();
+        let naive_size = untrained_model.compressed_size(&dataset.get_data()[0]).unwrap();
 
         println!("Trained size: {}", trained_size);
         println!("Naive size: {}", naive_size);
@@ -122,13 +151,12 @@ mod tests {
     fn save_and_load_ensemble_model() {
         let dataset = Dataset::test_dataset();
 
-        let mut options = TrainingOptions::default();
-        options.ensemble_size = 2;
-        let trained_model = EnsembleModel::train(dataset, &options);
+        let options = TrainingOptions { ensemble_size: 2, ..TrainingOptions::default() };
+        let trained_model = EnsembleModel::train(dataset, &options).unwrap();
 
-        trained_model.save_checkpoint("test.ensemble");
+        trained_model.save_checkpoint("test.ensemble").unwrap();
 
-        let loaded_model = EnsembleModel::from_checkpoint("test.ensemble");
+        let loaded_model = EnsembleModel::from_checkpoint("test.ensemble").unwrap();
 
         assert_eq!(trained_model.models.len(), loaded_model.models.len());
         assert_eq!(trained_model.models[0].get_dictionary_size(), loaded_model.models[0].get_dictionary_size());
@@ -136,5 +164,29 @@ mod tests {
         // clean up
         std::fs::remove_file("test.ensemble").unwrap();
     }
+
+    #[test]
+    fn load_legacy_sqlite_ensemble() {
+        let dataset = Dataset::test_dataset();
+
+        let options = TrainingOptions { ensemble_size: 2, ..TrainingOptions::default() };
+        let trained_model = EnsembleModel::train(dataset, &options).unwrap();
+
+        // the format checkpoints were written in before model bundles
+        let conn = Connection::open("test_legacy.ensemble").unwrap();
+        conn.execute("CREATE TABLE models (id INTEGER PRIMARY KEY, model BLOB)", []).unwrap();
+        for (i, model) in trained_model.models.iter().enumerate() {
+            conn.execute("INSERT INTO models (id, model) VALUES (?, ?)", (&i, &model.to_buffer())).unwrap();
+        }
+        conn.close().unwrap();
+
+        let loaded_model = EnsembleModel::from_checkpoint("test_legacy.ensemble").unwrap();
+
+        assert_eq!(trained_model.models.len(), loaded_model.models.len());
+        assert_eq!(trained_model.models[1].to_buffer(), loaded_model.models[1].to_buffer());
+
+        // clean up
+        std::fs::remove_file("test_legacy.ensemble").unwrap();
+    }
 }
 
@@ -0,0 +1,43 @@
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum ClmError {
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Compression error: {0}")]
+    Zstd(String),
+    #[error("Tokenizer error: {0}")]
+    Tokenizer(String),
+    #[error("Invalid model format: {0}")]
+    Format(String),
+    #[error("Training failed: {0}")]
+    Training(String),
+    #[error("Invalid configuration: {0}")]
+    Config(String),
+}
+
+pub type ClmResult<T> = Result<T, ClmError>;
+
+// zstd reports its failures as io errors, which would otherwise end up as `ClmError::Io`
+pub(crate) fn zstd_error(error: std::io::Error) -> ClmError {
+    ClmError::Zstd(error.to_string())
+}
+
+impl From<rmp_serde::encode::Error> for ClmError {
+    fn from(error: rmp_serde::encode::Error) -> Self {
+        ClmError::Format(error.to_string())
+    }
+}
+
+impl From<rmp_serde::decode::Error> for ClmError {
+    fn from(error: rmp_serde::decode::Error) -> Self {
+        ClmError::Format(error.to_string())
+    }
+}
+
+// only the checkpoints written before model bundles existed are sqlite databases
+impl From<rusqlite::Error> for ClmError {
+    fn from(error: rusqlite::Error) -> Self {
+        ClmError::Format(format!("legacy ensemble checkpoint: {}", error))
+    }
+}
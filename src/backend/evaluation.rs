@@ -1,10 +1,12 @@
 use num::Num;
 use num::pow::Pow;
 use rand::Rng;
+use rand::seq::SliceRandom;
 
 use crate::backend::clm_model::ClmModel;
 use crate::backend::dataset::Dataset;
-use crate::backend::{MAX_TOKEN, Token};
+use crate::backend::error::ClmResult;
+use crate::backend::Token;
 
 const SAMPLES: usize = 20000;
 
@@ -26,7 +28,7 @@ fn fmax<T: Num + PartialOrd>(a: T, b: T) -> T {
 }
 
 impl<'a> ClmModel<'a> {
-    pub fn average_bytes_per_token(&self, test_data: &Dataset) -> (f64, f64) {
+    pub fn average_bytes_per_token(&self, test_data: &Dataset) -> ClmResult<(f64, f64)> {
         let mut values = Vec::new();
 
         let mut rng = rand::thread_rng();
@@ -41,16 +43,17 @@ impl<'a> ClmModel<'a> {
 
             let prompt = sentence[..pos].to_vec();
             let next_token = sentence[pos];
-            let compressed_prompt = self.compress(&prompt);
-            let compressed = self.compress_together(&prompt, &vec![next_token]);
+            let compressed_prompt = self.compress(&prompt)?;
+            let compressed = self.compress_together(&prompt, &[next_token])?;
             values.push(compressed as f64 - compressed_prompt.len() as f64)
         }
 
-        average_with_error(values)
+        Ok(average_with_error(values))
     }
 
-    pub fn average_information_gain(&self, test_data: &Dataset) -> (f64, f64){
+    pub fn average_information_gain(&self, test_data: &Dataset) -> ClmResult<(f64, f64)> {
         let mut values = Vec::new();
+        let candidates = self.tokenizer.candidate_tokens();
 
         let mut rng = rand::thread_rng();
         for _ in 0..SAMPLES {
@@ -64,11 +67,11 @@ impl<'a> ClmModel<'a> {
 
             let prompt = sentence[..pos].to_vec();
             let next_token = sentence[pos];
-            let random_token = rng.gen_range(0..MAX_TOKEN) as Token;
+            let random_token: Token = *candidates.choose(&mut rng).unwrap();
 
-            let compressed_prompt = self.compress(&prompt).len() as f64;
-            let compressed_truth = self.compress_together(&prompt, &vec![next_token]) as f64;
-            let compressed_random = self.compress_together(&prompt, &vec![random_token]) as f64;
+            let compressed_prompt = self.compress(&prompt)?.len() as f64;
+            let compressed_truth = self.compress_together(&prompt, &[next_token])? as f64;
+            let compressed_random = self.compress_together(&prompt, &[random_token])? as f64;
 
             let truth_bytes_added = compressed_truth - compressed_prompt;
             let random_bytes_added = compressed_random - compressed_prompt;
@@ -76,7 +79,7 @@ impl<'a> ClmModel<'a> {
             values.push(random_bytes_added/fmax(0.1, truth_bytes_added));
         }
 
-        average_with_error(values)
+        Ok(average_with_error(values))
     }
 }
 
@@ -92,11 +95,11 @@ mod tests {
         let random_data = random_tokens(300);
         let training_data = Dataset::from_data((0..10).map(|_| random_data.clone()).collect());
 
-        let model = train_model(&Vec::new(), &TrainingOptions::new());
-        let (initial_avg,_) = model.average_bytes_per_token(&training_data);
+        let model = train_model(&Vec::new(), &TrainingOptions::new()).unwrap();
+        let (initial_avg,_) = model.average_bytes_per_token(&training_data).unwrap();
 
-        let trained_model = train_model(&training_data.get_data(), &TrainingOptions::new());
-        let (trained_avg, _) = trained_model.average_bytes_per_token(&training_data);
+        let trained_model = train_model(training_data.get_data(), &TrainingOptions::new()).unwrap();
+        let (trained_avg, _) = trained_model.average_bytes_per_token(&training_data).unwrap();
 
         assert!(trained_avg < initial_avg);
 
@@ -109,8 +112,8 @@ mod tests {
         let random_data = random_tokens(300);
         let testing_data = Dataset::from_data((0..10).map(|_| random_data.clone()).collect());
 
-        let model = train_model(&Vec::new(), &TrainingOptions::new());
-        let (initial_avg, initial_stderr) = model.average_information_gain(&testing_data);
+        let model = train_model(&Vec::new(), &TrainingOptions::new()).unwrap();
+        let (initial_avg, initial_stderr) = model.average_information_gain(&testing_data).unwrap();
 
         // 99%  confidence interval
         let confidence_interval = 2.576 * initial_stderr;
@@ -127,11 +130,11 @@ mod tests {
         let random_data = random_tokens(100);
         let training_data = Dataset::from_data((0..8).map(|_| random_data.clone()).collect());
 
-        let model = train_model(&Vec::new(), &TrainingOptions::new());
-        let (initial_avg,_) = model.average_information_gain(&training_data);
+        let model = train_model(&Vec::new(), &TrainingOptions::new()).unwrap();
+        let (initial_avg,_) = model.average_information_gain(&training_data).unwrap();
 
-        let trained_model = train_model(&training_data.get_data(), &TrainingOptions::new());
-        let (trained_avg, trained_stderr) = trained_model.average_information_gain(&training_data);
+        let trained_model = train_model(training_data.get_data(), &TrainingOptions::new()).unwrap();
+        let (trained_avg, trained_stderr) = trained_model.average_information_gain(&training_data).unwrap();
 
         assert!(trained_avg > 1f64);
 
@@ -0,0 +1,445 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+use crate::backend::clm_model::ClmModel;
+use crate::backend::ensemble_model::EnsembleModel;
+use crate::backend::error::ClmResult;
+use crate::backend::sampling::{Sampler, SamplingOptions};
+use crate::backend::tokenizer::ClmTokenizer;
+use crate::backend::Token;
+
+const SENTENCE_END: [char; 3] = ['.', '!', '?'];
+
+#[derive(Clone, Debug)]
+pub struct GenerationConfig {
+    pub max_new_tokens: usize,
+    pub stop_strings: Vec<String>, /* generation stops before the first occurrence of any of these */
+    pub stop_on_sentence_end: bool, /* generation stops after the first '.', '!' or '?' */
+    pub timeout: Option<Duration>,
+    pub sampling: SamplingOptions,
+    pub depth: usize, /* beam search lookahead, only used with greedy sampling */
+    pub width: usize, /* beam search width, only used with greedy sampling */
+    pub alternatives: usize, /* best scoring candidates reported with every token */
+    pub cancel: CancelToken,
+}
+
+impl GenerationConfig {
+    pub fn new() -> Self {
+        GenerationConfig {
+            max_new_tokens: 64,
+            stop_strings: Vec::new(),
+            stop_on_sentence_end: false,
+            timeout: None,
+            sampling: SamplingOptions::greedy(),
+            depth: 0,
+            width: 1,
+            alternatives: 0,
+            cancel: CancelToken::new(),
+        }
+    }
+
+    // Length of `text` that should be kept if it hits a stop condition
+    pub fn stop_position(&self, text: &str) -> Option<usize> {
+        let stop_string = self.stop_strings.iter()
+            .filter(|stop| !stop.is_empty())
+            .filter_map(|stop| text.find(stop.as_str()))
+            .min();
+
+        let sentence_end = if self.stop_on_sentence_end {
+            text.find(SENTENCE_END).map(|position| position + 1)
+        } else {
+            None
+        };
+
+        stop_string.into_iter().chain(sentence_end).min()
+    }
+}
+
+impl Default for GenerationConfig {
+    fn default() -> Self {
+        GenerationConfig::new()
+    }
+}
+
+// Stops a generation from another thread, clones share the same flag.
+// Scoring checks it between candidates, so a cancelled generation ends within a few compressions.
+#[derive(Clone, Debug, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn new() -> Self {
+        CancelToken::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FinishReason {
+    Length,
+    Stop,
+    Timeout,
+    Cancelled,
+}
+
+impl FinishReason {
+    pub fn name(self) -> &'static str {
+        match self {
+            FinishReason::Length => "length",
+            FinishReason::Stop => "stop",
+            FinishReason::Timeout => "timeout",
+            FinishReason::Cancelled => "cancelled",
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct GeneratedToken {
+    pub token: Token,
+    pub text: String,
+    pub bytes_added: usize, /* how much the token grew the compressed prompt */
+    pub alternatives: Vec<Alternative>, /* the best scoring candidates for the position, smallest first */
+}
+
+// A candidate for the position of a generated token, the picked one is usually among them
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Alternative {
+    pub token: Token,
+    pub text: String,
+    pub bytes_added: usize,
+}
+
+// The token a model picked together with the compressed size of the prompt including it, and the
+// compressed sizes of every candidate it scored for the position
+struct Choice {
+    token: Token,
+    size: usize,
+    candidates: Vec<(Token, f64)>,
+}
+
+#[derive(Clone, Debug)]
+pub struct Generation {
+    pub text: String,
+    pub tokens: Vec<GeneratedToken>,
+    pub finish_reason: FinishReason,
+}
+
+impl<'a> ClmModel<'a> {
+    pub fn generate(&self, prompt: &str, config: &GenerationConfig) -> ClmResult<Generation> {
+        let tokens = self.tokenizer.encode(prompt)?;
+        self.generate_tokens(&tokens, config)
+    }
+
+    pub fn generate_tokens(&self, prompt: &[Token], config: &GenerationConfig) -> ClmResult<Generation> {
+        self.generate_tokens_with(prompt, config, |_| {})
+    }
+
+    // Like `generate_tokens`, but hands every token to `on_token` as soon as it is generated
+    pub fn generate_tokens_with(&self, prompt: &[Token], config: &GenerationConfig, on_token: impl FnMut(&GeneratedToken)) -> ClmResult<Generation> {
+        generate_with(
+            &self.tokenizer,
+            prompt,
+            config,
+            |tokens| Ok(self.compress(tokens)?.len()),
+            |tokens, sampler| self.choose_next_token(tokens, config, sampler),
+            on_token,
+        )
+    }
+
+    fn choose_next_token(&self, tokens: &[Token], config: &GenerationConfig, sampler: &mut Sampler) -> ClmResult<Option<Choice>> {
+        if config.depth > 0 && sampler.options().temperature <= 0.0 {
+            let (continuation, _score, first_sizes) = self.beam_search_until(tokens, config.depth + 1, config.width, &config.cancel)?;
+            let Some(&next_token) = continuation.first() else {
+                return Ok(None);
+            };
+            let candidates = first_sizes.into_iter().map(|(token, size)| (token, size as f64)).collect();
+            let size = self.compress_together(tokens, &[next_token])?;
+            return Ok(Some(Choice { token: next_token, size, candidates }));
+        }
+
+        let sizes = self.next_token_sizes_until(tokens, &config.cancel)?;
+        let candidates = sizes.iter().map(|(token, size)| (*token, *size as f64)).collect::<Vec<_>>();
+        let choice = sampler.sample(&candidates)
+            .and_then(|next_token| sizes.into_iter().find(|(token, _)| *token == next_token))
+            .map(|(token, size)| Choice { token, size, candidates });
+        Ok(choice)
+    }
+}
+
+// The generation loop shared by all models
+#[tracing::instrument(level = "debug", skip_all, fields(prompt_tokens = prompt.len(), max_new_tokens = config.max_new_tokens))]
+fn generate_with(
+    tokenizer: &ClmTokenizer,
+    prompt: &[Token],
+    config: &GenerationConfig,
+    compressed_size: impl Fn(&[Token]) -> ClmResult<usize>,
+    mut choose_next_token: impl FnMut(&[Token], &mut Sampler) -> ClmResult<Option<Choice>>,
+    mut on_token: impl FnMut(&GeneratedToken),
+) -> ClmResult<Generation> {
+    let start = Instant::now();
+    let mut sampler = Sampler::new(config.sampling.clone());
+
+    let mut tokens = prompt.to_vec();
+    let prompt_text = tokenizer.decode_complete(&tokens)?;
+    let mut size_before = compressed_size(&tokens)?;
+
+    let mut generation = Generation {
+        text: String::new(),
+        tokens: Vec::new(),
+        finish_reason: FinishReason::Length,
+    };
+
+    while generation.tokens.len() < config.max_new_tokens {
+        if config.cancel.is_cancelled() {
+            generation.finish_reason = FinishReason::Cancelled;
+            break;
+        }
+        if config.timeout.is_some_and(|timeout| start.elapsed() >= timeout) {
+            generation.finish_reason = FinishReason::Timeout;
+            break;
+        }
+
+        let next_token = choose_next_token(&tokens, &mut sampler)?;
+        // scoring stops early when cancelled, whatever it picked is not a real prediction
+        if config.cancel.is_cancelled() {
+            generation.finish_reason = FinishReason::Cancelled;
+            break;
+        }
+        let Some(Choice { token: next_token, size, candidates }) = next_token else {
+            generation.finish_reason = FinishReason::Stop;
+            break;
+        };
+        tokens.push(next_token);
+
+        // decode everything so word boundaries between prompt and continuation come out right, if
+        // the tokenizer decodes the prompt differently in context only the generated tokens are used
+        let decoded = tokenizer.decode_complete(&tokens)?;
+        let mut text = match decoded.strip_prefix(&prompt_text) {
+            Some(continuation) => continuation.to_string(),
+            None => tokenizer.decode_complete(&tokens[prompt.len()..])?,
+        };
+
+        let stop_position = config.stop_position(&text);
+        if let Some(position) = stop_position {
+            text.truncate(position);
+        }
+
+        let generated = GeneratedToken {
+            token: next_token,
+            text: text.strip_prefix(&generation.text).unwrap_or_default().to_string(),
+            bytes_added: size.saturating_sub(size_before),
+            alternatives: best_alternatives(tokenizer, candidates, next_token, size_before, config.alternatives),
+        };
+        on_token(&generated);
+        generation.tokens.push(generated);
+        generation.text = text;
+        size_before = size;
+
+        if stop_position.is_some() {
+            generation.finish_reason = FinishReason::Stop;
+            break;
+        }
+    }
+
+    Ok(generation)
+}
+
+// The `count` candidates that grew the compressed prompt the least, among equally sized ones the
+// picked token comes first and the rest by token
+fn best_alternatives(tokenizer: &ClmTokenizer, mut candidates: Vec<(Token, f64)>, picked: Token, size_before: usize, count: usize) -> Vec<Alternative> {
+    if count == 0 {
+        return Vec::new();
+    }
+
+    candidates.sort_by(|a, b| a.1.total_cmp(&b.1).then((a.0 != picked).cmp(&(b.0 != picked))).then(a.0.cmp(&b.0)));
+    candidates.into_iter()
+        .take(count)
+        .map(|(token, size)| Alternative {
+            token,
+            // a token on its own can be part of a multi-byte character
+            text: tokenizer.decode(vec![token]).unwrap_or_else(|_| char::REPLACEMENT_CHARACTER.to_string()),
+            bytes_added: (size.round() as usize).saturating_sub(size_before),
+        })
+        .collect()
+}
+
+impl<'a> EnsembleModel<'a> {
+    pub fn generate(&self, prompt: &str, config: &GenerationConfig) -> ClmResult<Generation> {
+        let tokens = self.tokenizer()?.encode(prompt)?;
+        self.generate_tokens_with(&tokens, config, |_| {})
+    }
+
+    // The members vote by their average compressed size, beam search is left to single models
+    pub fn generate_tokens_with(&self, prompt: &[Token], config: &GenerationConfig, on_token: impl FnMut(&GeneratedToken)) -> ClmResult<Generation> {
+        generate_with(
+            self.tokenizer()?,
+            prompt,
+            config,
+            |tokens| Ok(self.compressed_size(tokens)?.round() as usize),
+            |tokens, sampler| {
+                let candidates = self.next_token_sizes_until(tokens, &config.cancel)?;
+                let choice = sampler.sample(&candidates)
+                    .and_then(|next_token| candidates.iter().find(|(token, _)| *token == next_token))
+                    .map(|&(token, size)| Choice { token, size: size.round() as usize, candidates: candidates.clone() });
+                Ok(choice)
+            },
+            on_token,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::backend::clm_model::ClmModel;
+    use crate::backend::dataset::Dataset;
+    use crate::backend::ensemble_model::EnsembleModel;
+    use crate::backend::generation::{generate_with, Choice, FinishReason, GenerationConfig};
+    use crate::backend::tokenizer::ClmTokenizer;
+    use crate::backend::training_options::TrainingOptions;
+    use crate::backend::sampling::SamplingOptions;
+
+    #[test]
+    fn stop_position_finds_earliest_stop() {
+        let config = GenerationConfig {
+            stop_strings: vec!["\n".to_string(), "user:".to_string()],
+            stop_on_sentence_end: true,
+            ..GenerationConfig::new()
+        };
+
+        assert_eq!(config.stop_position("hello there"), None);
+        assert_eq!(config.stop_position("hello. there"), Some(6));
+        assert_eq!(config.stop_position("hello\nthere."), Some(5));
+        assert_eq!(config.stop_position("hi user: there"), Some(3));
+    }
+
+    #[test]
+    fn generation_respects_max_new_tokens() {
+        let clm = ClmModel::from_buffer(Vec::new()).unwrap();
+        let config = GenerationConfig { max_new_tokens: 5, ..GenerationConfig::new() };
+
+        let generation = clm.generate("the quick brown fox", &config).unwrap();
+
+        assert_eq!(generation.tokens.len(), 5);
+        assert_eq!(generation.finish_reason, FinishReason::Length);
+        assert_eq!(generation.tokens.iter().map(|token| token.text.as_str()).collect::<String>(), generation.text);
+    }
+
+    #[test]
+    fn generation_stops_at_stop_string() {
+        let clm = ClmModel::from_buffer(Vec::new()).unwrap();
+        let sampling = SamplingOptions { seed: Some(3), ..SamplingOptions::greedy() };
+        let config = GenerationConfig { max_new_tokens: 8, sampling, ..GenerationConfig::new() };
+
+        let unrestricted = clm.generate("the quick brown fox", &config).unwrap();
+        let stop: String = unrestricted.text.trim_end().chars().rev().take(2).collect::<Vec<_>>().into_iter().rev().collect();
+
+        let config = GenerationConfig { stop_strings: vec![stop.clone()], ..config };
+        let generation = clm.generate("the quick brown fox", &config).unwrap();
+
+        assert_eq!(generation.finish_reason, FinishReason::Stop);
+        assert!(!generation.text.contains(&stop));
+    }
+
+    #[test]
+    fn characters_split_across_tokens_are_held_back() {
+        let tokenizer = ClmTokenizer::new_gpt2().unwrap();
+        let tokens = tokenizer.encode("日本語").unwrap();
+        assert!(tokenizer.decode(tokens[..1].to_vec()).is_err());
+
+        // the prompt ends inside a character and the continuation completes it
+        let config = GenerationConfig::new();
+        let mut streamed = Vec::new();
+        let generation = generate_with(
+            &tokenizer,
+            &tokens[..1],
+            &config,
+            |tokens| Ok(tokens.len()),
+            |generated, _| Ok(tokens.get(generated.len()).map(|&token| Choice { token, size: generated.len() + 1, candidates: Vec::new() })),
+            |token| streamed.push(token.text.clone()),
+        ).unwrap();
+
+        assert_eq!(generation.finish_reason, FinishReason::Stop);
+        assert_eq!(generation.text, "日本語");
+        assert_eq!(streamed.concat(), generation.text);
+        assert!(streamed.iter().all(|text| !text.contains(char::REPLACEMENT_CHARACTER)));
+    }
+
+    #[test]
+    fn generation_times_out() {
+        let clm = ClmModel::from_buffer(Vec::new()).unwrap();
+        let config = GenerationConfig { timeout: Some(Duration::ZERO), ..GenerationConfig::new() };
+
+        let generation = clm.generate("the quick brown fox", &config).unwrap();
+
+        assert_eq!(generation.finish_reason, FinishReason::Timeout);
+        assert!(generation.tokens.is_empty());
+    }
+
+    #[test]
+    fn streamed_tokens_match_generation() {
+        let clm = ClmModel::from_buffer(Vec::new()).unwrap();
+        let config = GenerationConfig { max_new_tokens: 4, ..GenerationConfig::new() };
+        let prompt = clm.tokenizer.encode("the quick brown fox").unwrap();
+
+        let mut streamed = String::new();
+        let generation = clm.generate_tokens_with(&prompt, &config, |token| streamed.push_str(&token.text)).unwrap();
+
+        assert_eq!(streamed, generation.text);
+    }
+
+    #[test]
+    fn cancelled_generation_stops() {
+        let clm = ClmModel::from_buffer(Vec::new()).unwrap();
+        let config = GenerationConfig { max_new_tokens: 10, ..GenerationConfig::new() };
+        let prompt = clm.tokenizer.encode("the quick brown fox").unwrap();
+
+        let cancel = config.cancel.clone();
+        let generation = clm.generate_tokens_with(&prompt, &config, |_| cancel.cancel()).unwrap();
+
+        assert_eq!(generation.finish_reason, FinishReason::Cancelled);
+        assert_eq!(generation.tokens.len(), 1);
+    }
+
+    #[test]
+    fn tokens_come_with_their_best_alternatives() {
+        let clm = ClmModel::from_buffer(Vec::new()).unwrap();
+        for depth in [0, 1] {
+            let config = GenerationConfig { max_new_tokens: 2, depth, alternatives: 3, ..GenerationConfig::new() };
+
+            let generation = clm.generate("the quick brown fox", &config).unwrap();
+
+            for token in generation.tokens {
+                assert_eq!(token.alternatives.len(), 3);
+                assert!(token.alternatives.windows(2).all(|pair| pair[0].bytes_added <= pair[1].bytes_added));
+                assert!(token.alternatives[0].bytes_added <= token.bytes_added);
+            }
+        }
+
+        let generation = clm.generate("the quick brown fox", &GenerationConfig { max_new_tokens: 1, ..GenerationConfig::new() }).unwrap();
+        assert!(generation.tokens[0].alternatives.is_empty());
+    }
+
+    #[test]
+    fn ensembles_generate() {
+        let options = TrainingOptions { ensemble_size: 2, ..TrainingOptions::default() };
+        let ensemble = EnsembleModel::train(Dataset::empty(), &options).unwrap();
+        let config = GenerationConfig { max_new_tokens: 3, ..GenerationConfig::new() };
+
+        let generation = ensemble.generate("the quick brown fox", &config).unwrap();
+
+        assert_eq!(generation.tokens.len(), 3);
+        assert_eq!(generation.finish_reason, FinishReason::Length);
+    }
+}
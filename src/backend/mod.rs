@@ -1,21 +1,80 @@
+pub mod bundle;
 pub mod trainer;
 pub mod training_options;
 pub mod clm_model;
 pub mod dataset;
+pub mod error;
 pub mod evaluation;
 pub mod ensemble_model;
-mod tokenizer;
+pub mod generation;
+pub mod prefix_scorer;
+pub mod sampling;
+pub mod tokenizer;
+
+use serde::{Deserialize, Serialize};
+
+use crate::backend::error::{ClmError, ClmResult};
 
 
 // https://wortschatz.uni-leipzig.de/en/download/English
 const DATA_PATH: &str = "./data";
 
-pub type Token = u8;
-const BYTES_PER_TOKEN: usize = std::mem::size_of::<Token>();
-pub const MAX_TOKEN: Token = 254; // Please update if u use another tokenizer!!!!
+// Tokens are held as u16 in memory, how many bytes they take up in the compressed stream
+// depends on the vocabulary of the tokenizer, see TokenWidth
+pub type Token = u16;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TokenWidth {
+    U8,
+    U16,
+}
+
+impl TokenWidth {
+    pub fn for_vocab_size(vocab_size: usize) -> ClmResult<Self> {
+        if vocab_size <= 1 << 8 {
+            Ok(TokenWidth::U8)
+        } else if vocab_size <= 1 << 16 {
+            Ok(TokenWidth::U16)
+        } else {
+            Err(ClmError::Tokenizer(format!("Vocabularies with more than {} tokens are not supported, this one has {}", 1 << 16, vocab_size)))
+        }
+    }
+
+    pub fn bytes(&self) -> usize {
+        match self {
+            TokenWidth::U8 => 1,
+            TokenWidth::U16 => 2,
+        }
+    }
+
+    pub fn write(&self, token: Token, output: &mut [u8]) -> ClmResult<()> {
+        match self {
+            TokenWidth::U8 => {
+                output[0] = u8::try_from(token).map_err(|_| ClmError::Format(format!("Token {} does not fit into a single byte", token)))?;
+            }
+            TokenWidth::U16 => output.copy_from_slice(&token.to_be_bytes()),
+        }
+        Ok(())
+    }
+
+    pub fn read(&self, input: &[u8]) -> Token {
+        match self {
+            TokenWidth::U8 => input[0] as Token,
+            TokenWidth::U16 => Token::from_be_bytes([input[0], input[1]]),
+        }
+    }
+}
+
+pub fn tokens_to_bytes(tokens: &[Token], width: TokenWidth) -> ClmResult<Vec<u8>> {
+    let mut bytes = vec![0u8; tokens.len() * width.bytes()];
+    for (token, output) in tokens.iter().zip(bytes.chunks_exact_mut(width.bytes())) {
+        width.write(*token, output)?;
+    }
+    Ok(bytes)
+}
 
-pub fn tokens_to_bytes(tokens: &Vec<Token>) -> Vec<u8> {
-    tokens.iter().flat_map(|x| (*x).to_be_bytes()).collect()
+pub fn bytes_to_tokens(bytes: &[u8], width: TokenWidth) -> Vec<Token> {
+    bytes.chunks_exact(width.bytes()).map(|chunk| width.read(chunk)).collect()
 }
 
 
@@ -30,11 +89,15 @@ pub mod tests {
     use crate::backend::*;
     use crate::backend::clm_model::ClmModel;
     use crate::backend::dataset::Dataset;
+    use crate::backend::error::ClmError;
+    use crate::backend::sampling::{Sampler, SamplingOptions};
+    use crate::backend::tokenizer::ClmTokenizer;
     use crate::backend::trainer::train_model;
     use crate::backend::training_options::TrainingOptions;
 
     pub fn random_tokens(n: usize) -> Vec<Token> {
-        rand::thread_rng().sample_iter(Uniform::from(0..MAX_TOKEN)).take(n).collect_vec()
+        let vocab_size = Dataset::get_tokenizer().unwrap().vocab_size() as Token;
+        rand::thread_rng().sample_iter(Uniform::from(0..vocab_size)).take(n).collect_vec()
     }
 
     pub fn predict_loop() {
@@ -43,54 +106,121 @@ pub mod tests {
 
         let mut prompt = "Lorem ipsum dolor sit amet, consectetur adipiscing elit. Proin tincidunt urna nisl, non molestie velit aliquam nec. In in erat id est porttitor efficitur ac eleifend ex. Nam auctor lacus urna, a sodales metus bibendum ut. Vestibulum vulputate facilisis ultrices. Vestibulum ut euismod erat. Maecenas pretium egestas nunc, non efficitur eros interdum eget. Suspendisse eleifend augue eu viverra rutrum. Phasellus non elementum erat, sit amet ultrices nunc. Sed facilisis at ipsum nec sagittis. Nulla non placerat purus. Pellentesque sed mollis enim. Praesent tincidunt purus id tellus tristique, ut ".to_string();
 
-        let clm = ClmModel::from_checkpoint("model.zstd_dict");
+        let clm = ClmModel::from_checkpoint("model.zstd_dict").unwrap();
 
         println!("{}", prompt);
 
         for _ in 0..100 {
-            prompt = clm.predict_next(prompt.clone(), 0, 0);
+            prompt = clm.predict_next(prompt.clone(), 0, 0).unwrap();
             println!("{}", prompt)
         }
     }
 
     #[test]
     fn can_compress_and_decompress() {
-        let clm = ClmModel::from_buffer(Vec::new());
-        let start: Vec<Token> = clm.tokenizer.encode("Lorem ipsum dolor sit amet, consectetur adipiscing elit. Proin tincidunt urna nisl, non molestie velit aliquam nec. In in erat id est porttitor efficitur ac eleifend ex. Nam auctor lacus urna, a sodales metus bibendum ut. Vestibulum vulputate facilisis ultrices. Vestibulum ut euismod erat. Maecenas pretium egestas nunc, non efficitur eros interdum eget. Suspendisse eleifend augue eu viverra rutrum. Phasellus non elementum erat, sit amet ultrices nunc. Sed facilisis at ipsum nec sagittis. Nulla non placerat purus. Pellentesque sed mollis enim. Praesent tincidunt purus id tellus tristique, ut rhoncus justo fringilla. Suspendisse fermentum ultrices dolor, vel mollis enim. Aliquam eros.");
-        let compressed = clm.compress(&start);
-        let decompressed = clm.decompress_to_tokens(&compressed);
+        let clm = ClmModel::from_buffer(Vec::new()).unwrap();
+        let start: Vec<Token> = clm.tokenizer.encode("Lorem ipsum dolor sit amet, consectetur adipiscing elit. Proin tincidunt urna nisl, non molestie velit aliquam nec. In in erat id est porttitor efficitur ac eleifend ex. Nam auctor lacus urna, a sodales metus bibendum ut. Vestibulum vulputate facilisis ultrices. Vestibulum ut euismod erat. Maecenas pretium egestas nunc, non efficitur eros interdum eget. Suspendisse eleifend augue eu viverra rutrum. Phasellus non elementum erat, sit amet ultrices nunc. Sed facilisis at ipsum nec sagittis. Nulla non placerat purus. Pellentesque sed mollis enim. Praesent tincidunt purus id tellus tristique, ut rhoncus justo fringilla. Suspendisse fermentum ultrices dolor, vel mollis enim. Aliquam eros.").unwrap();
+        let compressed = clm.compress(&start).unwrap();
+        let decompressed = clm.decompress_to_tokens(&compressed).unwrap();
 
         assert_eq!(decompressed, start);
     }
 
+    #[test]
+    fn tokens_roundtrip_through_bytes() {
+        let tokens: Vec<Token> = vec![0, 1, 200, 255];
+        assert_eq!(bytes_to_tokens(&tokens_to_bytes(&tokens, TokenWidth::U8).unwrap(), TokenWidth::U8), tokens);
+
+        let tokens: Vec<Token> = vec![0, 255, 256, 50256, Token::MAX];
+        let bytes = tokens_to_bytes(&tokens, TokenWidth::U16).unwrap();
+        assert_eq!(bytes.len(), tokens.len() * 2);
+        assert_eq!(bytes_to_tokens(&bytes, TokenWidth::U16), tokens);
+    }
+
+    #[test]
+    fn wide_tokens_do_not_fit_into_a_byte() {
+        assert!(matches!(tokens_to_bytes(&[1, 256], TokenWidth::U8), Err(ClmError::Format(_))));
+    }
+
+    #[test]
+    fn token_width_follows_vocab_size() {
+        assert_eq!(TokenWidth::for_vocab_size(255).unwrap(), TokenWidth::U8);
+        assert_eq!(TokenWidth::for_vocab_size(256).unwrap(), TokenWidth::U8);
+        assert_eq!(TokenWidth::for_vocab_size(4096).unwrap(), TokenWidth::U16);
+        assert_eq!(TokenWidth::for_vocab_size(50281).unwrap(), TokenWidth::U16);
+        assert!(matches!(TokenWidth::for_vocab_size((1 << 16) + 1), Err(ClmError::Tokenizer(_))));
+    }
+
+    #[test]
+    fn gpt2_model_compresses_wide_tokens() {
+        let clm = ClmModel::new(Vec::new(), ClmTokenizer::new_gpt2().unwrap()).unwrap();
+        let start = clm.tokenizer.encode("Unbelievable tokenization of the Encyclopaedia").unwrap();
+        assert!(start.iter().any(|token| *token > 255));
+
+        let compressed = clm.compress(&start).unwrap();
+        assert_eq!(clm.decompress_to_tokens(&compressed).unwrap(), start);
+    }
+
+    #[test]
+    fn seeded_sampling_is_reproducible() {
+        let clm = ClmModel::from_buffer(Vec::new()).unwrap();
+        let options = SamplingOptions { seed: Some(7), top_k: Some(20), ..SamplingOptions::new() };
+        let prompt = "the quick brown fox jumps over the".to_string();
+
+        let first = clm.sample_next(prompt.clone(), &mut Sampler::new(options.clone())).unwrap();
+        let second = clm.sample_next(prompt.clone(), &mut Sampler::new(options)).unwrap();
+
+        assert_eq!(first, second);
+        assert!(first.len() > prompt.len());
+    }
+
+    #[test]
+    fn beam_search_scores_whole_continuation() {
+        let data: Vec<Token> = random_tokens(100);
+        let training_data = (0usize..10).map(|_| data.clone()).collect_vec();
+        let model = train_model(&training_data, &TrainingOptions::new()).unwrap();
+
+        let prompt = data[..50].to_vec();
+        let (continuation, score) = model.beam_search(&prompt, 3, 4).unwrap();
+        assert_eq!(continuation.len(), 3);
+
+        let prompt_size = model.compress(&prompt).unwrap().len();
+        assert_eq!(score, model.compress_together(&prompt, &continuation).unwrap().saturating_sub(prompt_size));
+    }
+
     #[test]
     fn save_and_load_model() {
         let data: Vec<Token> = random_tokens(100);
         let training_data = (0usize..10).map(|_| data.clone()).collect_vec();
 
-        let model = train_model(&training_data, &TrainingOptions::new());
-        model.save_checkpoint("model_test.zstd_dict");
-        let loaded_model = ClmModel::from_checkpoint("model_test.zstd_dict");
+        let model = train_model(&training_data, &TrainingOptions::new()).unwrap();
+        model.save_checkpoint("model_test.zstd_dict").unwrap();
+        let loaded_model = ClmModel::from_checkpoint("model_test.zstd_dict").unwrap();
 
-        let compressed = model.compress(&data);
-        let compressed_loaded = loaded_model.compress(&data);
+        let compressed = model.compress(&data).unwrap();
+        let compressed_loaded = loaded_model.compress(&data).unwrap();
 
         assert_eq!(compressed, compressed_loaded);
 
         // cleanup
         std::fs::remove_file("model_test.zstd_dict").unwrap();
     }
+
+    #[test]
+    fn missing_checkpoint_is_an_error() {
+        assert!(matches!(ClmModel::from_checkpoint("missing_model.bin"), Err(ClmError::Io(_))));
+    }
     #[test]
     fn dictionary_helps_compression() {
         let data: Vec<Token> = random_tokens(50);
         
         let training_data = Dataset::from_data((0usize..10).map(|_| data.clone()).collect_vec());
 
-        let trained_model = train_model(training_data.get_data(), &TrainingOptions::new());
-        let untrained_model = train_model(&Vec::new(), &TrainingOptions::default());
+        let trained_model = train_model(training_data.get_data(), &TrainingOptions::new()).unwrap();
+        let untrained_model = train_model(&Vec::new(), &TrainingOptions::default()).unwrap();
 
-        let compressed = trained_model.compress(&data);
-        let compressed_no_dict = untrained_model.compress(&data);
+        let compressed = trained_model.compress(&data).unwrap();
+        let compressed_no_dict = untrained_model.compress(&data).unwrap();
 
         println!("Compressed size with dict: {}, without dict: {}", compressed.len(), compressed_no_dict.len());
 
@@ -0,0 +1,217 @@
+use std::ops::{Deref, DerefMut};
+use std::sync::Mutex;
+
+use rayon::prelude::*;
+use zstd::dict::EncoderDictionary;
+use zstd::stream::raw::{Encoder, InBuffer, Operation, OutBuffer};
+use zstd::zstd_safe;
+
+use crate::backend;
+use crate::backend::error::{zstd_error, ClmResult};
+use crate::backend::generation::CancelToken;
+use crate::backend::{Token, TokenWidth};
+use crate::metrics;
+
+// Scores many single-token continuations of the same prompt.
+//
+// Every candidate is compressed together with the whole prompt, exactly like `ClmModel::compress`
+// does: the entropy coding of a block depends on all of its content, so anything less changes the
+// sizes. What is shared is everything around it. The prompt is turned into bytes and compressed on
+// its own once, every worker copies it into its input a single time and then only overwrites the
+// bytes of the candidate, and the encoders come from the model's pool with the dictionary already
+// referenced, so they are set up once and reused by every step of a generation.
+pub struct PrefixScorer<'m, 'a> {
+    dict: &'m EncoderDictionary<'a>,
+    encoders: &'m EncoderPool<'a>,
+    prompt: Vec<u8>,
+    prefix_size: usize,
+    token_width: TokenWidth,
+}
+
+impl<'m, 'a> PrefixScorer<'m, 'a> {
+    pub fn new(dict: &'m EncoderDictionary<'a>, encoders: &'m EncoderPool<'a>, tokens: &[Token], token_width: TokenWidth) -> ClmResult<Self> {
+        let prompt = backend::tokens_to_bytes(tokens, token_width)?;
+        let prefix_size = compressed_size(&mut *encoders.take(dict)?, &prompt, &mut Vec::new())?;
+        Ok(PrefixScorer { dict, encoders, prompt, prefix_size, token_width })
+    }
+
+    // Compressed size of the prompt on its own
+    pub fn prefix_size(&self) -> usize {
+        self.prefix_size
+    }
+
+    // Compressed size of the prompt followed by each of the candidates
+    pub fn candidate_sizes<I>(&self, candidates: I) -> ClmResult<Vec<(Token, usize)>>
+    where
+        I: IntoParallelIterator<Item = Token>,
+    {
+        self.candidate_sizes_until(candidates, &CancelToken::new())
+    }
+
+    // Like `candidate_sizes`, but workers stop picking up candidates once `cancel` is set,
+    // so a cancelled call returns early with only part of the sizes
+    #[tracing::instrument(level = "trace", skip_all, fields(prompt_bytes = self.prompt.len()))]
+    pub fn candidate_sizes_until<I>(&self, candidates: I, cancel: &CancelToken) -> ClmResult<Vec<(Token, usize)>>
+    where
+        I: IntoParallelIterator<Item = Token>,
+    {
+        metrics::SCORING_JOBS.add(&[], 1.0);
+        let sizes: ClmResult<Vec<(Token, usize)>> = candidates
+            .into_par_iter()
+            .map_init(
+                || {
+                    let mut input = self.prompt.clone();
+                    input.resize(self.prompt.len() + self.token_width.bytes(), 0);
+                    (None, input, Vec::new())
+                },
+                |(encoder, input, output), token| {
+                    if cancel.is_cancelled() {
+                        return None;
+                    }
+                    Some(self.candidate_size(encoder, input, output, token).map(|size| (token, size)))
+                },
+            )
+            .while_some()
+            .collect();
+        metrics::SCORING_JOBS.add(&[], -1.0);
+        if let Ok(sizes) = &sizes {
+            metrics::CANDIDATE_COMPRESSIONS.add(&[], sizes.len() as f64);
+        }
+        sizes
+    }
+
+    // Every worker takes its encoder with its first candidate, so a failure ends up in the result
+    fn candidate_size(&self, encoder: &mut Option<PooledEncoder<'m, 'a>>, input: &mut [u8], output: &mut Vec<u8>, token: Token) -> ClmResult<usize> {
+        self.token_width.write(token, &mut input[self.prompt.len()..])?;
+        let encoder = match encoder {
+            Some(encoder) => encoder,
+            None => encoder.insert(self.encoders.take(self.dict)?),
+        };
+        compressed_size(encoder, input, output)
+    }
+}
+
+// Encoders referencing the dictionary of one model, kept between scoring steps
+#[derive(Default)]
+pub struct EncoderPool<'a>(Mutex<Vec<Encoder<'a>>>);
+
+impl<'a> EncoderPool<'a> {
+    // `dict` has to be the dictionary of the model the pool belongs to
+    fn take<'p>(&'p self, dict: &EncoderDictionary<'a>) -> ClmResult<PooledEncoder<'p, 'a>> {
+        let encoder = match self.0.lock().unwrap().pop() {
+            Some(encoder) => encoder,
+            None => Encoder::with_prepared_dictionary(dict).map_err(zstd_error)?,
+        };
+        Ok(PooledEncoder { pool: self, encoder: Some(encoder) })
+    }
+}
+
+// Goes back to its pool once the worker is done with it
+struct PooledEncoder<'p, 'a> {
+    pool: &'p EncoderPool<'a>,
+    encoder: Option<Encoder<'a>>,
+}
+
+impl<'a> Deref for PooledEncoder<'_, 'a> {
+    type Target = Encoder<'a>;
+
+    fn deref(&self) -> &Self::Target {
+        self.encoder.as_ref().expect("Pooled encoders are only taken when dropped")
+    }
+}
+
+impl DerefMut for PooledEncoder<'_, '_> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.encoder.as_mut().expect("Pooled encoders are only taken when dropped")
+    }
+}
+
+impl Drop for PooledEncoder<'_, '_> {
+    fn drop(&mut self) {
+        if let Some(encoder) = self.encoder.take() {
+            self.pool.0.lock().unwrap().push(encoder);
+        }
+    }
+}
+
+fn compressed_size(encoder: &mut Encoder, data: &[u8], output: &mut Vec<u8>) -> ClmResult<usize> {
+    encoder.reinit().map_err(zstd_error)?;
+    output.clear();
+    output.reserve(zstd_safe::compress_bound(data.len()));
+
+    // feed the input first and end the frame afterwards, exactly like the streaming writer does,
+    // so the frame header (which has no content size in that mode) matches as well
+    let mut input = InBuffer::around(data);
+    let mut output = OutBuffer::around(output);
+    while input.pos() < data.len() {
+        encoder.run(&mut input, &mut output).map_err(zstd_error)?;
+    }
+    while encoder.finish(&mut output, true).map_err(zstd_error)? != 0 {}
+
+    Ok(output.pos())
+}
+
+#[cfg(test)]
+mod tests {
+    use itertools::Itertools;
+
+    use crate::backend::clm_model::ClmModel;
+    use crate::backend::generation::CancelToken;
+    use crate::backend::tests::random_tokens;
+    use crate::backend::trainer::train_model;
+    use crate::backend::training_options::TrainingOptions;
+    use crate::backend::Token;
+
+    // What every candidate costs when the whole prompt is compressed again with it
+    fn full_sizes(model: &ClmModel, prompt: &[Token]) -> Vec<(Token, usize)> {
+        model.tokenizer.candidate_tokens().into_iter().map(|token| (token, model.compress_together(prompt, &[token]).unwrap())).collect()
+    }
+
+    fn assert_same_as_full_compression(model: &ClmModel, prompt: &[Token]) {
+        let scorer = model.prefix_scorer(prompt).unwrap();
+        assert_eq!(scorer.prefix_size(), model.compress(prompt).unwrap().len());
+        assert_eq!(scorer.candidate_sizes(model.tokenizer.candidate_tokens()).unwrap(), full_sizes(model, prompt));
+    }
+
+    #[test]
+    fn short_prompts_match_full_compression() {
+        let data: Vec<Token> = random_tokens(200);
+        let training_data = (0usize..10).map(|_| data.clone()).collect_vec();
+        let model = train_model(&training_data, &TrainingOptions::new()).unwrap();
+        assert_same_as_full_compression(&model, &data[150..170]);
+    }
+
+    #[test]
+    fn long_prompts_match_full_compression() {
+        let model = ClmModel::from_checkpoint("model.zstd_dict").unwrap();
+        let text = std::fs::read_to_string("data/tests.txt").unwrap()
+            .lines()
+            .take(100)
+            .filter_map(|line| line.split_once('\t'))
+            .map(|(_, sentence)| sentence)
+            .join(" ");
+        let prompt = model.tokenizer.encode(&text).unwrap();
+        assert_same_as_full_compression(&model, &prompt);
+
+        // the next step reuses the encoders of this one
+        let mut longer = prompt.clone();
+        longer.push(prompt[0]);
+        assert_same_as_full_compression(&model, &longer);
+    }
+
+    #[test]
+    fn prompts_match_full_compression_without_dictionary() {
+        let model = train_model(&Vec::new(), &TrainingOptions::new()).unwrap();
+        assert_same_as_full_compression(&model, &random_tokens(1000));
+    }
+
+    #[test]
+    fn cancelled_scoring_returns_early() {
+        let model = train_model(&Vec::new(), &TrainingOptions::new()).unwrap();
+        let scorer = model.prefix_scorer(&random_tokens(100)).unwrap();
+
+        let cancel = CancelToken::new();
+        cancel.cancel();
+        assert!(scorer.candidate_sizes_until(model.tokenizer.candidate_tokens(), &cancel).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,192 @@
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::{Rng, SeedableRng};
+
+use crate::backend::Token;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct SamplingOptions {
+    pub temperature: f64, /* 0.0 means greedy, higher values flatten the distribution */
+    pub top_k: Option<usize>,
+    pub top_p: Option<f64>,
+    pub min_p: Option<f64>,
+    pub seed: Option<u64>,
+}
+
+impl SamplingOptions {
+    pub fn new() -> Self {
+        SamplingOptions {
+            temperature: 1.0,
+            top_k: None,
+            top_p: None,
+            min_p: None,
+            seed: None,
+        }
+    }
+
+    pub fn greedy() -> Self {
+        SamplingOptions {
+            temperature: 0.0,
+            ..SamplingOptions::new()
+        }
+    }
+}
+
+impl Default for SamplingOptions {
+    fn default() -> Self {
+        SamplingOptions::new()
+    }
+}
+
+// Turns compressed sizes into a probability distribution where smaller sizes are more likely.
+// Every byte a candidate adds divides its weight by e^(1 / temperature).
+// The result is sorted by descending probability, ties by token, so seeded sampling does not
+// depend on the order the candidates were scored in.
+pub fn size_distribution(sizes: &[(Token, f64)], temperature: f64) -> Vec<(Token, f64)> {
+    if sizes.is_empty() {
+        return Vec::new();
+    }
+
+    let min_size = sizes.iter().map(|(_, size)| *size).fold(f64::INFINITY, f64::min);
+    let mut distribution: Vec<(Token, f64)> = if temperature <= 0.0 {
+        sizes.iter().map(|(token, size)| (*token, if *size == min_size { 1.0 } else { 0.0 })).collect()
+    } else {
+        sizes.iter().map(|(token, size)| (*token, (-(size - min_size) / temperature).exp())).collect()
+    };
+
+    normalize(&mut distribution);
+    distribution.sort_by(|a, b| b.1.total_cmp(&a.1).then(a.0.cmp(&b.0)));
+    distribution
+}
+
+fn normalize(distribution: &mut [(Token, f64)]) {
+    let sum: f64 = distribution.iter().map(|(_, p)| *p).sum();
+    if sum > 0.0 {
+        distribution.iter_mut().for_each(|(_, p)| *p /= sum);
+    }
+}
+
+pub struct Sampler {
+    options: SamplingOptions,
+    rng: StdRng,
+}
+
+impl Sampler {
+    pub fn new(options: SamplingOptions) -> Self {
+        let rng = match options.seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_entropy(),
+        };
+        Sampler { options, rng }
+    }
+
+    pub fn options(&self) -> &SamplingOptions {
+        &self.options
+    }
+
+    // The distribution the sampler draws from after temperature, top-k, min-p and top-p are applied
+    pub fn distribution(&self, sizes: &[(Token, f64)]) -> Vec<(Token, f64)> {
+        let mut distribution = size_distribution(sizes, self.options.temperature);
+        distribution.retain(|(_, p)| *p > 0.0);
+
+        if let Some(top_k) = self.options.top_k {
+            distribution.truncate(top_k.max(1));
+        }
+
+        if let Some(min_p) = self.options.min_p {
+            let threshold = distribution.first().map(|(_, p)| p * min_p).unwrap_or(0.0);
+            distribution.retain(|(_, p)| *p >= threshold);
+        }
+
+        if let Some(top_p) = self.options.top_p {
+            let mut cumulative = 0.0;
+            let keep = distribution.iter().take_while(|(_, p)| {
+                let include = cumulative < top_p;
+                cumulative += p;
+                include
+            }).count();
+            distribution.truncate(keep.max(1));
+        }
+
+        normalize(&mut distribution);
+        distribution
+    }
+
+    pub fn sample(&mut self, sizes: &[(Token, f64)]) -> Option<Token> {
+        let mut distribution = self.distribution(sizes);
+
+        // candidates are sorted by probability, shuffle first so ties don't always favour the lowest token
+        distribution.shuffle(&mut self.rng);
+
+        let mut target = self.rng.gen::<f64>();
+        for (token, p) in distribution.iter() {
+            if target < *p {
+                return Some(*token);
+            }
+            target -= p;
+        }
+        distribution.last().map(|(token, _)| *token)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::backend::sampling::{Sampler, SamplingOptions, size_distribution};
+    use crate::backend::Token;
+
+    fn sizes() -> Vec<(Token, f64)> {
+        vec![(1, 10.0), (2, 11.0), (3, 12.0), (4, 10.0), (5, 15.0)]
+    }
+
+    #[test]
+    fn distribution_is_normalized() {
+        let distribution = size_distribution(&sizes(), 1.0);
+        let sum: f64 = distribution.iter().map(|(_, p)| *p).sum();
+        assert!((sum - 1.0).abs() < 1e-9);
+        // smaller sizes come first
+        assert!(distribution[0].0 == 1 || distribution[0].0 == 4);
+        assert_eq!(distribution.last().unwrap().0, 5);
+    }
+
+    #[test]
+    fn greedy_picks_smallest_size() {
+        let mut sampler = Sampler::new(SamplingOptions::greedy());
+        for _ in 0..50 {
+            let token = sampler.sample(&sizes()).unwrap();
+            assert!(token == 1 || token == 4);
+        }
+    }
+
+    #[test]
+    fn top_k_limits_candidates() {
+        let sampler = Sampler::new(SamplingOptions { top_k: Some(3), ..SamplingOptions::new() });
+        let distribution = sampler.distribution(&sizes());
+        assert_eq!(distribution.len(), 3);
+        assert!(distribution.iter().all(|(token, _)| [1, 2, 4].contains(token)));
+    }
+
+    #[test]
+    fn top_p_keeps_nucleus() {
+        let sampler = Sampler::new(SamplingOptions { top_p: Some(0.5), ..SamplingOptions::new() });
+        let distribution = sampler.distribution(&sizes());
+        // the two smallest candidates each hold ~0.4 of the mass, so two are needed to reach 0.5
+        assert_eq!(distribution.len(), 2);
+    }
+
+    #[test]
+    fn min_p_drops_unlikely_candidates() {
+        let sampler = Sampler::new(SamplingOptions { min_p: Some(0.2), ..SamplingOptions::new() });
+        let distribution = sampler.distribution(&sizes());
+        assert!(distribution.iter().all(|(token, _)| *token != 5 && *token != 3));
+    }
+
+    #[test]
+    fn seeded_sampling_is_reproducible() {
+        let options = SamplingOptions { seed: Some(42), temperature: 2.0, ..SamplingOptions::new() };
+        let mut a = Sampler::new(options.clone());
+        let mut b = Sampler::new(options);
+        let first: Vec<Token> = (0..20).map(|_| a.sample(&sizes()).unwrap()).collect();
+        let second: Vec<Token> = (0..20).map(|_| b.sample(&sizes()).unwrap()).collect();
+        assert_eq!(first, second);
+    }
+}
@@ -1,83 +1,209 @@
+use std::str::FromStr;
+
 use itertools::Itertools;
 use tiktoken_rs::{CoreBPE, p50k_base};
 use tokenizers::tokenizer::Tokenizer;
 
-use crate::backend::{MAX_TOKEN, Token};
+use crate::backend::{Token, TokenWidth};
+use crate::backend::bundle::TokenizerDefinition;
+use crate::backend::error::{ClmError, ClmResult};
 
 static TOKENIZER_PATH: &str = "tokenizer.json";
 
+// p50k_base has no way to ask for its size, these are the 50256 merges, <|endoftext|> and the 24
+// whitespace tokens it adds on top of r50k_base
+const P50K_VOCAB_SIZE: usize = 50281;
+const P50K_END_OF_TEXT: Token = 50256;
+
 #[derive(Clone)]
 pub enum ClmTokenizer {
     GPT2(CoreBPE),
-    Custom(Tokenizer),
+    Custom(Box<Tokenizer>),
 }
 
 impl ClmTokenizer {
-    #[allow(dead_code)]
-    fn new_gpt2() -> Self {
-        let tokenizer = p50k_base().unwrap();
-        ClmTokenizer::GPT2(tokenizer)
+    pub fn new_gpt2() -> ClmResult<Self> {
+        let tokenizer = p50k_base().map_err(|error| ClmError::Tokenizer(error.to_string()))?;
+        Ok(ClmTokenizer::GPT2(tokenizer))
     }
 
-    pub fn get_max_token(&self) -> Token {
+    pub fn vocab_size(&self) -> usize {
         match self {
-            ClmTokenizer::GPT2(_) => MAX_TOKEN as Token,
-            ClmTokenizer::Custom(tokenizer) => (tokenizer.get_vocab_size(true) - 1) as Token,
+            ClmTokenizer::GPT2(_) => P50K_VOCAB_SIZE,
+            ClmTokenizer::Custom(tokenizer) => tokenizer.get_vocab_size(true),
+        }
+    }
+
+    pub fn token_width(&self) -> ClmResult<TokenWidth> {
+        TokenWidth::for_vocab_size(self.vocab_size())
+    }
+
+    // All tokens the model may generate, that is the whole vocabulary without special tokens
+    pub fn candidate_tokens(&self) -> Vec<Token> {
+        let special_tokens: Vec<Token> = match self {
+            ClmTokenizer::GPT2(_) => vec![P50K_END_OF_TEXT],
+            ClmTokenizer::Custom(tokenizer) => tokenizer.get_added_tokens_decoder()
+                .into_iter()
+                .filter(|(_, token)| token.special)
+                .map(|(id, _)| id as Token)
+                .collect(),
+        };
+
+        (0..self.vocab_size())
+            .map(|id| id as Token)
+            .filter(|id| !special_tokens.contains(id))
+            .collect()
+    }
+
+    pub fn new_custom() -> ClmResult<Self> {
+        Self::from_file(TOKENIZER_PATH)
+    }
+
+    pub fn from_file(path: &str) -> ClmResult<Self> {
+        let tokenizer = Tokenizer::from_file(path)
+            .map_err(|error| ClmError::Tokenizer(format!("Failed to load {}: {}", path, error)))?;
+        Self::custom(tokenizer)
+    }
+
+    pub fn from_definition(definition: &TokenizerDefinition) -> ClmResult<Self> {
+        match definition {
+            TokenizerDefinition::Gpt2 => ClmTokenizer::new_gpt2(),
+            TokenizerDefinition::Custom(json) => {
+                let tokenizer = Tokenizer::from_str(json).map_err(|error| ClmError::Tokenizer(error.to_string()))?;
+                Self::custom(tokenizer)
+            }
         }
     }
 
-    pub(crate) fn new_custom() -> Self {
-        let tokenizer = Tokenizer::from_file(TOKENIZER_PATH).unwrap();
-        ClmTokenizer::Custom(tokenizer)
+    fn custom(tokenizer: Tokenizer) -> ClmResult<Self> {
+        // fail on load rather than on the first token that does not fit
+        TokenWidth::for_vocab_size(tokenizer.get_vocab_size(true))?;
+        Ok(ClmTokenizer::Custom(Box::new(tokenizer)))
     }
 
-    pub(crate) fn encode(&self, text: &str) -> Vec<Token> {
+    pub fn to_definition(&self) -> ClmResult<TokenizerDefinition> {
         match self {
-            ClmTokenizer::GPT2(tokenizer) => tokenizer.encode_ordinary(text).iter().map(|&x| x as Token).collect(),
+            ClmTokenizer::GPT2(_) => Ok(TokenizerDefinition::Gpt2),
+            ClmTokenizer::Custom(tokenizer) => tokenizer.to_string(false)
+                .map(TokenizerDefinition::Custom)
+                .map_err(|error| ClmError::Tokenizer(error.to_string())),
+        }
+    }
+
+    pub(crate) fn encode(&self, text: &str) -> ClmResult<Vec<Token>> {
+        match self {
+            ClmTokenizer::GPT2(tokenizer) => tokenizer.encode_ordinary(text).into_iter().map(to_token).collect(),
             ClmTokenizer::Custom(tokenizer) => {
-                let encoding = tokenizer.encode(text, false).unwrap();
+                let encoding = tokenizer.encode(text, false).map_err(|error| ClmError::Tokenizer(error.to_string()))?;
                 let ids = encoding.get_ids();
-                ids.iter().map(|&x| x as Token).collect()
+                ids.iter().map(|&x| to_token(x as usize)).collect()
             }
         }
     }
 
-    pub(crate) fn decode(&self, tokens: Vec<Token>) -> String {
+    pub(crate) fn decode(&self, tokens: Vec<Token>) -> ClmResult<String> {
         match self {
-            ClmTokenizer::GPT2(tokenizer) => tokenizer.decode(tokens.iter().map(|&x| x as usize).collect_vec()).unwrap(),
-            ClmTokenizer::Custom(tokenizer) => {
-                tokenizer.decode(tokens.iter().map(|&x| x as u32).collect_vec().as_ref(), false).unwrap()
+            ClmTokenizer::GPT2(tokenizer) => tokenizer.decode(tokens.iter().map(|&x| x as usize).collect_vec())
+                .map_err(|error| ClmError::Tokenizer(error.to_string())),
+            ClmTokenizer::Custom(tokenizer) => tokenizer.decode(tokens.iter().map(|&x| x as u32).collect_vec().as_ref(), false)
+                .map_err(|error| ClmError::Tokenizer(error.to_string())),
+        }
+    }
+
+    // Like `decode`, but a character split across tokens is held back until the token that
+    // completes it arrives instead of failing, other invalid bytes are replaced
+    pub(crate) fn decode_complete(&self, tokens: &[Token]) -> ClmResult<String> {
+        match self {
+            ClmTokenizer::GPT2(tokenizer) => {
+                let bytes = tokenizer._decode_native(&tokens.iter().map(|&x| x as usize).collect_vec());
+                let complete = match std::str::from_utf8(&bytes) {
+                    Err(error) if error.error_len().is_none() => &bytes[..error.valid_up_to()],
+                    _ => &bytes[..],
+                };
+                Ok(String::from_utf8_lossy(complete).into_owned())
             }
+            // byte level decoders already replace invalid bytes, a trailing replacement is a split character
+            ClmTokenizer::Custom(_) => Ok(self.decode(tokens.to_vec())?.trim_end_matches(char::REPLACEMENT_CHARACTER).to_string()),
         }
     }
 }
 
+fn to_token(id: usize) -> ClmResult<Token> {
+    Token::try_from(id).map_err(|_| ClmError::Tokenizer(format!("Token id {} does not fit into a token", id)))
+}
+
 #[cfg(test)]
 mod tests {
+    use crate::backend::error::ClmError;
     use crate::backend::tokenizer::ClmTokenizer;
+    use crate::backend::TokenWidth;
 
     #[test]
     fn custom_tokenizer_works() {
-        let tokenizer = ClmTokenizer::new_custom();
-        let encoding = tokenizer.encode("Hello, world!");
-        let decoded = tokenizer.decode(encoding);
+        let tokenizer = ClmTokenizer::new_custom().unwrap();
+        let encoding = tokenizer.encode("Hello, world!").unwrap();
+        let decoded = tokenizer.decode(encoding).unwrap();
         assert_eq!(decoded, "hello, world!");
     }
 
+    #[test]
+    fn split_characters_are_held_back() {
+        let tokenizer = ClmTokenizer::new_gpt2().unwrap();
+        let tokens = tokenizer.encode("日本").unwrap();
+        let split = (1..tokens.len())
+            .find(|&end| tokenizer.decode(tokens[..end].to_vec()).is_err())
+            .expect("some prefix ends inside a character");
+
+        let held_back = tokenizer.decode_complete(&tokens[..split]).unwrap();
+        assert!("日本".starts_with(&held_back));
+        assert!(held_back.len() < "日本".len());
+        assert_eq!(tokenizer.decode_complete(&tokens).unwrap(), "日本");
+    }
+
     #[test]
     fn gpt2_tokenizer_works() {
-        let tokenizer = ClmTokenizer::new_gpt2();
-        let encoding = tokenizer.encode("Hello, world!");
-        let decoded = tokenizer.decode(encoding);
+        let tokenizer = ClmTokenizer::new_gpt2().unwrap();
+        let encoding = tokenizer.encode("Hello, world!").unwrap();
+        let decoded = tokenizer.decode(encoding).unwrap();
         assert_eq!(decoded, "Hello, world!");
     }
 
     #[test]
     fn custom_tokenizer_special_characters() {
-        let tokenizer = ClmTokenizer::new_custom();
-        let encoding = tokenizer.encode("Hello, world! 🌍");
-        let decoded = tokenizer.decode(encoding);
+        let tokenizer = ClmTokenizer::new_custom().unwrap();
+        let encoding = tokenizer.encode("Hello, world! 🌍").unwrap();
+        let decoded = tokenizer.decode(encoding).unwrap();
         assert_eq!(decoded, "hello, world! [UNK]");
     }
-}
 
+    #[test]
+    fn token_width_follows_vocabulary() {
+        assert_eq!(ClmTokenizer::new_custom().unwrap().token_width().unwrap(), TokenWidth::U8);
+        assert_eq!(ClmTokenizer::new_gpt2().unwrap().token_width().unwrap(), TokenWidth::U16);
+    }
+
+    #[test]
+    fn candidates_skip_special_tokens() {
+        let tokenizer = ClmTokenizer::new_custom().unwrap();
+        let candidates = tokenizer.candidate_tokens();
+        assert_eq!(candidates.len(), tokenizer.vocab_size() - 1);
+        assert!(!candidates.contains(&0));
+
+        let gpt2 = ClmTokenizer::new_gpt2().unwrap();
+        assert!(!gpt2.candidate_tokens().contains(&50256));
+        assert_eq!(gpt2.decode(gpt2.encode("Encyclopaedia").unwrap()).unwrap(), "Encyclopaedia");
+    }
+
+    #[test]
+    fn missing_tokenizer_is_an_error() {
+        assert!(matches!(ClmTokenizer::from_file("missing_tokenizer.json"), Err(ClmError::Tokenizer(_))));
+    }
+
+    #[test]
+    fn partial_characters_fail_to_decode() {
+        let gpt2 = ClmTokenizer::new_gpt2().unwrap();
+        let tokens = gpt2.encode("🌍").unwrap();
+        assert!(tokens.len() > 1);
+        assert!(gpt2.decode(tokens[..1].to_vec()).is_err());
+    }
+}
@@ -1,18 +1,35 @@
-use std::ffi::{c_uint, c_void};
+use std::ffi::{c_uint, c_void, CStr};
 use itertools::Itertools;
-use zstd_sys::{ZDICT_isError, ZDICT_optimizeTrainFromBuffer_fastCover};
-use crate::backend::{BYTES_PER_TOKEN, Token, tokens_to_bytes};
+use zstd_sys::{ZDICT_getErrorName, ZDICT_isError, ZDICT_optimizeTrainFromBuffer_fastCover};
+use crate::backend::{INFERENCE_COMPRESSION_LEVEL, Token, tokens_to_bytes};
+use crate::backend::bundle::{CorpusStatistics, ModelMetadata};
 use crate::backend::clm_model::ClmModel;
+use crate::backend::error::{ClmError, ClmResult};
+use crate::backend::tokenizer::ClmTokenizer;
 use crate::backend::training_options::TrainingOptions;
 
-pub fn train_model<'a>(input_tokens: &Vec<Vec<Token>>, training_options: &TrainingOptions) -> ClmModel<'a> {
-    
+pub fn train_model<'a>(input_tokens: &[Vec<Token>], training_options: &TrainingOptions) -> ClmResult<ClmModel<'a>> {
+    train_model_with_tokenizer(input_tokens, training_options, ClmTokenizer::new_custom()?)
+}
+
+pub fn train_model_with_tokenizer<'a>(input_tokens: &[Vec<Token>], training_options: &TrainingOptions, tokenizer: ClmTokenizer) -> ClmResult<ClmModel<'a>> {
+
+    let token_width = tokenizer.token_width()?;
+    let raw_data = input_tokens.iter().map(|tokens| tokens_to_bytes(tokens, token_width)).collect::<ClmResult<Vec<_>>>()?.concat();
+    let metadata = ModelMetadata {
+        training_options: Some(training_options.clone()),
+        corpus: Some(CorpusStatistics {
+            sentences: input_tokens.len(),
+            tokens: input_tokens.iter().map(|tokens| tokens.len()).sum(),
+            bytes: raw_data.len(),
+        }),
+    };
+
     if input_tokens.is_empty() {
-        return ClmModel::from_buffer(vec![]);
+        return ClmModel::from_parts(vec![], tokenizer, INFERENCE_COMPRESSION_LEVEL, metadata);
     }
-    
-    let raw_data = input_tokens.iter().flat_map(tokens_to_bytes).collect_vec();
-    let sizes = input_tokens.iter().map(|x| x.len() * BYTES_PER_TOKEN).collect_vec();
+
+    let sizes = input_tokens.iter().map(|x| x.len() * token_width.bytes()).collect_vec();
     let buffer_size = (raw_data.len() as f64 * training_options.dictionary_size_percentage) as usize;
     assert_eq!(sizes.iter().sum::<usize>(), raw_data.len(), "Sizes sum doesn't match raw data size");
     let mut buffer = vec![0u8; buffer_size];
@@ -29,9 +46,10 @@ pub fn train_model<'a>(input_tokens: &Vec<Vec<Token>>, training_options: &Traini
         );
 
         if ZDICT_isError(size) != 0 {
-            panic!("Failed to train dictionary");
+            let reason = CStr::from_ptr(ZDICT_getErrorName(size)).to_string_lossy();
+            return Err(ClmError::Training(format!("Failed to train dictionary: {}", reason)));
         }
     }
     buffer.resize(size, 0);
-    ClmModel::from_buffer(buffer)
-}
\ No newline at end of file
+    ClmModel::from_parts(buffer, tokenizer, INFERENCE_COMPRESSION_LEVEL, metadata)
+}
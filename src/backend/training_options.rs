@@ -1,5 +1,8 @@
 use std::ffi::c_int;
 
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct TrainingOptions {
     pub d: u32,
     pub f: u32,
@@ -51,8 +54,10 @@ impl TrainingOptions {
             },
         }
     }
+}
 
-    pub fn default() -> Self {
+impl Default for TrainingOptions {
+    fn default() -> Self {
         TrainingOptions::new()
     }
 }
@@ -1,14 +1,68 @@
-#[derive(Debug, Clone, PartialEq, Eq)]
+use crate::model::GenerationSettings;
+use leptos::{server, ServerFnError};
+use serde::{Deserialize, Serialize};
+
+pub const UNTITLED_CONVERSATION: &str = "New chat";
+
+pub fn now_iso() -> String {
+    chrono::Local::now().format("%Y-%m-%d %H:%M:%S").to_string()
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub enum Sender {
     User,
     ChatCLM,
 }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct Message {
     pub message: String,
     pub time_iso: String,
     pub sender: Sender,
+    pub stopped: bool,
+    #[serde(default)]
+    pub variants: Variants,
+    #[serde(default)]
+    pub tokens: Vec<TokenInfo>, /* how the answer was generated, empty for prompts */
+}
+
+// A generated token with the number of bytes it added to the compressed conversation
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TokenInfo {
+    pub text: String,
+    pub bytes_added: usize,
+    #[serde(default)]
+    pub alternatives: Vec<TokenAlternative>, /* the best scoring candidates for its position, smallest first */
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TokenAlternative {
+    pub text: String,
+    pub bytes_added: usize,
+}
+
+// The other branches a conversation has at a message, each one starting with its own version of
+// the message. They are kept on the message that is shown in its place.
+// URL encoded server function arguments leave out empty lists, hence the defaults.
+#[derive(Debug, Clone, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Variants {
+    pub others: Vec<Vec<Message>>,
+    pub current: usize, /* position of the shown branch among all of them */
+}
+
+impl Variants {
+    pub fn count(&self) -> usize {
+        self.others.len() + 1
+    }
+}
+
+// What can be done to a message of the chat, by its index in the shown branch
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MessageAction {
+    Regenerate(usize),
+    Edit(usize, String),
+    SelectVariant(usize, usize),
 }
 
 impl Message {
@@ -19,13 +73,16 @@ impl Message {
     pub fn new(message: String, sender: Sender) -> Self {
         Self {
             message,
-            time_iso: chrono::Local::now().format("%Y-%m-%d %H:%M:%S").to_string(),
+            time_iso: now_iso(),
             sender,
+            stopped: false,
+            variants: Variants::default(),
+            tokens: Vec::new(),
         }
     }
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, Default)]
+#[derive(Debug, Clone, PartialEq, Eq, Default, Serialize, Deserialize)]
 pub struct ChatHistory {
     pub messages: Vec<Message>,
 }
@@ -50,4 +107,208 @@ impl ChatHistory {
             }
         }
     }
+
+    pub fn set_last_server_tokens(&mut self, tokens: Vec<TokenInfo>) {
+        if let Some(last_message) = self.messages.last_mut() {
+            if last_message.sender == Sender::ChatCLM {
+                last_message.tokens = tokens;
+            }
+        }
+    }
+
+    // Replaces the message at `index` and everything after it with a new branch starting with
+    // `message`, the old branch is kept as a variant
+    fn fork(&mut self, index: usize, mut message: Message) {
+        let mut branch = self.messages.split_off(index);
+        let Some(first) = branch.first_mut() else {
+            self.add_message(message);
+            return;
+        };
+        let Variants { mut others, current } = std::mem::take(&mut first.variants);
+        others.insert(current, branch);
+        message.variants = Variants { current: others.len(), others };
+        self.add_message(message);
+    }
+
+    // Starts a new answer in place of the answer at `index`, returns the conversation it answers
+    pub fn regenerate(&mut self, index: usize) -> Option<ChatHistory> {
+        if self.messages.get(index)?.is_user_msg() {
+            return None;
+        }
+        self.fork(index, Message::new(String::new(), Sender::ChatCLM));
+        Some(self.before(index))
+    }
+
+    // Asks the prompt at `index` again with another text, returns the conversation up to it
+    pub fn edit(&mut self, index: usize, message: String) -> Option<ChatHistory> {
+        if !self.messages.get(index)?.is_user_msg() {
+            return None;
+        }
+        self.fork(index, Message::new(message, Sender::User));
+        Some(self.before(index + 1))
+    }
+
+    // Shows another branch at `index`
+    pub fn select_variant(&mut self, index: usize, variant: usize) {
+        let Some(first) = self.messages.get_mut(index) else {
+            return;
+        };
+        if variant == first.variants.current || variant >= first.variants.count() {
+            return;
+        }
+        let Variants { mut others, current } = std::mem::take(&mut first.variants);
+        let mut branch = self.messages.split_off(index);
+        others.insert(current, std::mem::take(&mut branch));
+        let mut branch = others.remove(variant);
+        branch[0].variants = Variants { others, current: variant };
+        self.messages.extend(branch);
+    }
+
+    // The first `length` messages of the shown branch, without the other branches
+    pub fn before(&self, length: usize) -> ChatHistory {
+        let messages = self.messages[..length.min(self.messages.len())].iter()
+            .map(|message| Message { variants: Variants::default(), ..message.clone() })
+            .collect();
+        ChatHistory { messages }
+    }
+
+    // keeps the partial answer of a cancelled generation
+    pub fn stop_last_server_message(&mut self) {
+        if let Some(last_message) = self.messages.last_mut() {
+            if last_message.sender == Sender::ChatCLM {
+                last_message.stopped = true;
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ConversationSummary {
+    pub id: String,
+    pub title: String,
+    pub model_id: String,
+    pub created_iso: String,
+    pub updated_iso: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Conversation {
+    pub summary: ConversationSummary,
+    pub settings: GenerationSettings,
+    pub history: ChatHistory,
+}
+
+// The browser asking, conversations of other browsers do not exist for it
+#[cfg(feature = "ssr")]
+async fn conversation_owner() -> Result<String, ServerFnError> {
+    let crate::api::OwnerId(owner) = leptos_axum::extract().await?;
+    Ok(owner)
+}
+
+// Most recently updated first
+#[server(ListConversations, "/api")]
+pub async fn list_conversations() -> Result<Vec<ConversationSummary>, ServerFnError> {
+    let owner = conversation_owner().await?;
+    Ok(crate::storage::store()?.list(&owner)?)
+}
+
+#[server(GetConversation, "/api")]
+pub async fn get_conversation(id: String) -> Result<Option<Conversation>, ServerFnError> {
+    let owner = conversation_owner().await?;
+    Ok(crate::storage::store()?.get(&owner, &id)?)
+}
+
+#[server(CreateConversation, "/api")]
+pub async fn create_conversation(model_id: String, #[server(default)] settings: GenerationSettings) -> Result<ConversationSummary, ServerFnError> {
+    let owner = conversation_owner().await?;
+    Ok(crate::storage::store()?.create(&owner, &model_id, &settings)?)
+}
+
+#[server(RenameConversation, "/api")]
+pub async fn rename_conversation(id: String, title: String) -> Result<(), ServerFnError> {
+    let owner = conversation_owner().await?;
+    crate::storage::store()?.rename(&owner, &id, title.trim())?;
+    Ok(())
+}
+
+#[server(SaveConversationSettings, "/api")]
+pub async fn save_conversation_settings(id: String, #[server(default)] settings: GenerationSettings) -> Result<(), ServerFnError> {
+    let owner = conversation_owner().await?;
+    crate::storage::store()?.set_settings(&owner, &id, &settings)?;
+    Ok(())
+}
+
+#[server(DeleteConversation, "/api")]
+pub async fn delete_conversation(id: String) -> Result<(), ServerFnError> {
+    let owner = conversation_owner().await?;
+    crate::storage::store()?.delete(&owner, &id)?;
+    Ok(())
+}
+
+// Replaces the stored messages with `history`, untitled conversations are named after the first prompt
+#[server(SaveConversation, "/api")]
+pub async fn save_conversation(id: String, model_id: String, history: ChatHistory) -> Result<(), ServerFnError> {
+    let owner = conversation_owner().await?;
+    crate::storage::store()?.save(&owner, &id, &model_id, &history)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::chat::{ChatHistory, Message, Sender};
+
+    fn texts(history: &ChatHistory) -> Vec<&str> {
+        history.messages.iter().map(|message| message.message.as_str()).collect()
+    }
+
+    fn conversation() -> ChatHistory {
+        let mut history = ChatHistory::default();
+        history.new_user_message("Hi".to_string());
+        history.new_server_message("Hello".to_string());
+        history.new_user_message("How are you".to_string());
+        history.new_server_message("Fine".to_string());
+        history
+    }
+
+    #[test]
+    fn regenerated_answers_keep_the_old_branch() {
+        let mut history = conversation();
+        let prompt = history.regenerate(1).unwrap();
+        assert_eq!(texts(&prompt), vec!["Hi"]);
+        history.replace_last_server_message("Hey".to_string());
+        assert_eq!(texts(&history), vec!["Hi", "Hey"]);
+        assert_eq!((history.messages[1].variants.current, history.messages[1].variants.count()), (1, 2));
+
+        history.select_variant(1, 0);
+        assert_eq!(texts(&history), vec!["Hi", "Hello", "How are you", "Fine"]);
+        assert_eq!(history.messages[1].variants.current, 0);
+        history.select_variant(1, 1);
+        assert_eq!(texts(&history), vec!["Hi", "Hey"]);
+
+        // prompts cannot be regenerated, answers cannot be edited
+        assert!(history.regenerate(0).is_none());
+        assert!(history.edit(1, "Hello".to_string()).is_none());
+    }
+
+    #[test]
+    fn edited_prompts_branch_below_the_earlier_ones() {
+        let mut history = conversation();
+        history.regenerate(3);
+        history.replace_last_server_message("Good".to_string());
+
+        let prompt = history.edit(2, "Who are you".to_string()).unwrap();
+        assert_eq!(texts(&prompt), vec!["Hi", "Hello", "Who are you"]);
+        assert!(prompt.messages.iter().all(|message| message.variants.others.is_empty()));
+
+        // the old branch keeps its own variants
+        history.select_variant(2, 0);
+        assert_eq!(texts(&history), vec!["Hi", "Hello", "How are you", "Good"]);
+        history.select_variant(3, 0);
+        assert_eq!(texts(&history), vec!["Hi", "Hello", "How are you", "Fine"]);
+
+        let unchanged = history.clone();
+        history.select_variant(2, 5);
+        assert_eq!(history, unchanged);
+        assert_eq!(Message::new("x".to_string(), Sender::User).variants.count(), 1);
+    }
 }
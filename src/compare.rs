@@ -0,0 +1,117 @@
+#[cfg(feature = "ssr")]
+use crate::chat::ChatHistory;
+use crate::chat::TokenInfo;
+#[cfg(feature = "ssr")]
+use crate::model::find_model;
+use leptos::{server, ServerFnError};
+use serde::{Deserialize, Serialize};
+
+// Every compared model answers at the same time and takes a worker while doing so
+pub const MAX_COMPARED_MODELS: usize = 4;
+
+// One prompt sent to several models, each answer is streamed like an answer of the chat
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Comparison {
+    pub id: String,
+    pub generations: Vec<ComparedGeneration>, /* in the order the models were asked for */
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct ComparedGeneration {
+    pub model_id: String,
+    pub generation_id: String,
+}
+
+// A thumbs up or down for one answer of a comparison, kept for analysing the models later
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Preference {
+    pub comparison_id: String,
+    pub prompt: String,
+    pub model_id: String,
+    #[serde(default)]
+    pub compared: Vec<String>, /* every model the prompt was sent to */
+    pub answer: ComparedAnswer,
+    pub preferred: bool,
+}
+
+// What a compared model answered, recorded by the server once the generation ends
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct ComparedAnswer {
+    pub text: String,
+    pub first_token_ms: Option<f64>, /* from the start of the stream, including the wait for a worker */
+    pub total_ms: f64,
+    pub bytes_per_token: Option<f64>,
+}
+
+// Compressed bytes an answer added per token, lower means the model found its answer more predictable
+pub fn bytes_per_token(tokens: &[TokenInfo]) -> Option<f64> {
+    if tokens.is_empty() {
+        return None;
+    }
+    Some(tokens.iter().map(|token| token.bytes_added).sum::<usize>() as f64 / tokens.len() as f64)
+}
+
+// Registers an answer to `prompt` for every model, which are then streamed from `generation_events_url`
+#[server(StartComparison, "/api")]
+pub async fn start_comparison(model_ids: Vec<String>, prompt: String) -> Result<Comparison, ServerFnError> {
+    if !(2..=MAX_COMPARED_MODELS).contains(&model_ids.len()) {
+        return Err(ServerFnError::new(format!("Compare between 2 and {} models", MAX_COMPARED_MODELS)));
+    }
+    if model_ids.iter().enumerate().any(|(index, id)| model_ids[..index].contains(id)) {
+        return Err(ServerFnError::new("Every model can only be compared once"));
+    }
+    // all models have to exist before any of them starts
+    let models = model_ids.iter().map(|id| find_model(id)).collect::<Result<Vec<_>, _>>()?;
+
+    // votes are only taken for what the server itself asked and answered
+    let id = format!("{:016x}", rand::random::<u64>());
+    let model_ids: Vec<String> = models.iter().map(|model| model.id().to_string()).collect();
+    let mut history = ChatHistory::default();
+    history.new_user_message(prompt.trim().to_string());
+    let crate::api::ClientId(client) = leptos_axum::extract().await?;
+    let generation_ids = crate::api::chat::register_compared_generations(&client, &id, &models, history)?;
+    crate::storage::store()?.create_comparison(&id, prompt.trim(), &model_ids)?;
+
+    let generations = model_ids.into_iter()
+        .zip(generation_ids)
+        .map(|(model_id, generation_id)| ComparedGeneration { generation_id, model_id })
+        .collect();
+
+    Ok(Comparison { id, generations })
+}
+
+// Stores the answer of a compared model, a failure only loses the comparison for the analysis
+#[cfg(feature = "ssr")]
+pub fn record_answer(comparison_id: &str, model_id: &str, answer: &ComparedAnswer) {
+    let recorded = crate::storage::store().map_err(ToString::to_string)
+        .and_then(|store| store.record_answer(comparison_id, model_id, answer).map_err(|error| error.to_string()));
+    if let Err(error) = recorded {
+        tracing::warn!(%error, comparison_id, model_id, "failed to record a compared answer");
+    }
+}
+
+// Voting again on the same answer replaces the earlier vote. Only answers the server recorded for
+// the comparison can be voted on.
+#[server(RecordPreference, "/api")]
+pub async fn record_preference(comparison_id: String, model_id: String, preferred: bool) -> Result<(), ServerFnError> {
+    if !crate::storage::store()?.record_preference(&comparison_id, &model_id, preferred)? {
+        return Err(ServerFnError::new(format!("The comparison has no answer of the model `{}`", model_id)));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::chat::TokenInfo;
+    use crate::compare::bytes_per_token;
+
+    fn token(bytes_added: usize) -> TokenInfo {
+        TokenInfo { text: " a".to_string(), bytes_added, alternatives: Vec::new() }
+    }
+
+    #[test]
+    fn bytes_per_token_averages_the_answer() {
+        assert_eq!(bytes_per_token(&[]), None);
+        assert_eq!(bytes_per_token(&[token(1), token(2), token(0), token(5)]), Some(2.0));
+    }
+}
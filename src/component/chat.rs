@@ -1,16 +1,32 @@
-use crate::chat::ChatHistory;
+use crate::chat::{ChatHistory, MessageAction};
 use crate::component::chat_message::ChatMessage;
-use leptos::{component, view, For, IntoView, ReadSignal};
+use leptos::{component, view, Callback, For, IntoView, ReadSignal, Show, Signal};
 
 #[component]
-pub fn Chat(chat: ReadSignal<ChatHistory>) -> impl IntoView {
+pub fn Chat(
+    chat: ReadSignal<ChatHistory>,
+    #[prop(into)] generating: Signal<bool>,
+    on_action: Callback<MessageAction>,
+) -> impl IntoView {
     view! {
         <section class="chat">
+            <Show when=move || chat().messages.is_empty()>
+                <p class="chat__welcome">Welcome to ChatCLM! Type a message and press Enter to chat.</p>
+            </Show>
             <For
                 each=move || chat().messages.into_iter().enumerate()
-                key=|(idx, it)| format!("{}: {}", idx, it.message.clone())
-                children=move |(_, it)| {
-                    view! { <ChatMessage msg=it/> }
+                key=|(idx, it)| {
+                    format!(
+                        "{}: {} {} {}/{}",
+                        idx,
+                        it.message.clone(),
+                        it.stopped,
+                        it.variants.current,
+                        it.variants.count(),
+                    )
+                }
+                children=move |(idx, it)| {
+                    view! { <ChatMessage msg=it index=idx generating=generating on_action=on_action/> }
                 }
             />
 
@@ -1,9 +1,35 @@
-use crate::chat::Message;
-use leptos::{component, view, IntoView, Show};
+use crate::chat::{Message, MessageAction, TokenInfo};
+use leptos::{
+    component, create_node_ref, create_signal, html, view, Callback, CollectView, IntoView,
+    NodeRef, Show, Signal, SignalGet, SignalUpdate,
+};
 
 #[component]
-pub fn ChatMessage(msg: Message) -> impl IntoView {
+pub fn ChatMessage(
+    msg: Message,
+    index: usize,
+    #[prop(into)] generating: Signal<bool>,
+    on_action: Callback<MessageAction>,
+) -> impl IntoView {
     let is_user_msg = msg.is_user_msg();
+    let stopped = msg.stopped;
+    let variant = msg.variants.current;
+    let variants = msg.variants.count();
+    let has_variants = variants > 1;
+    let has_tokens = !msg.tokens.is_empty();
+    let (editing, set_editing) = create_signal(false);
+    let (inspecting, set_inspecting) = create_signal(false);
+    let edit_element: NodeRef<html::Div> = create_node_ref();
+
+    let text = msg.message.clone();
+    let send_edit = move || {
+        let edited = edit_element().unwrap().inner_text().trim().to_string();
+        set_editing(false);
+        // an unchanged prompt would only ask the same question again
+        if !edited.is_empty() && edited != text {
+            on_action(MessageAction::Edit(index, edited));
+        }
+    };
 
     view! {
         <div class="chat_message" class=("chat_message--machine", move || !is_user_msg)>
@@ -13,7 +39,157 @@ pub fn ChatMessage(msg: Message) -> impl IntoView {
                 </div>
             </Show>
 
-            <p class=("chat_message__bubble", move || is_user_msg)>{msg.message}</p>
+            <div class="chat_message__body">
+                <Show
+                    when=move || editing()
+                    fallback={
+                        let msg = msg.clone();
+                        move || {
+                            view! {
+                                <p class=("chat_message__bubble", move || is_user_msg)>
+                                    {
+                                        let message = msg.message.clone();
+                                        let tokens = msg.tokens.clone();
+                                        move || {
+                                            if inspecting() {
+                                                token_heatmap(&tokens).into_view()
+                                            } else {
+                                                message.clone().into_view()
+                                            }
+                                        }
+                                    }
+
+                                    <Show when=move || stopped>
+                                        <span class="chat_message__stopped">(stopped)</span>
+                                    </Show>
+                                    <time class="chat_message__time" datetime=msg.time_iso.clone()>
+                                        {msg.time_iso.clone()}
+                                    </time>
+                                </p>
+                            }
+                        }
+                    }
+                >
+
+                    <div class="chat_message__editor">
+                        <div class="chat_message__edit_text" contenteditable node_ref=edit_element>
+                            {msg.message.clone()}
+                        </div>
+                        <div class="chat_message__editor_buttons">
+                            <button class="chat_message__action" on:click=move |_| set_editing(false)>
+                                Cancel
+                            </button>
+                            <button
+                                class="chat_message__action"
+                                disabled=move || generating.get()
+                                on:click={
+                                    let send_edit = send_edit.clone();
+                                    move |_| send_edit()
+                                }
+                            >
+
+                                Send
+                            </button>
+                        </div>
+                    </div>
+                </Show>
+
+                <div class="chat_message__actions">
+                    // another branch cannot be shown while this one is being answered
+                    <Show when=move || has_variants>
+                        <button
+                            class="chat_message__action"
+                            title="Previous version"
+                            disabled=move || generating.get() || variant == 0
+                            on:click=move |_| on_action(MessageAction::SelectVariant(index, variant.saturating_sub(1)))
+                        >
+                            "‹"
+                        </button>
+                        <span>{variant + 1} / {variants}</span>
+                        <button
+                            class="chat_message__action"
+                            title="Next version"
+                            disabled=move || generating.get() || variant + 1 == variants
+                            on:click=move |_| on_action(MessageAction::SelectVariant(index, variant + 1))
+                        >
+                            "›"
+                        </button>
+                    </Show>
+                    <Show
+                        when=move || is_user_msg
+                        fallback=move || {
+                            view! {
+                                <Show when=move || has_tokens>
+                                    <button
+                                        class="chat_message__action"
+                                        class=("chat_message__action--active", inspecting)
+                                        title="Inspect tokens"
+                                        on:click=move |_| set_inspecting.update(|inspecting| *inspecting = !*inspecting)
+                                    >
+                                        "▦"
+                                    </button>
+                                </Show>
+                                <button
+                                    class="chat_message__action"
+                                    title="Regenerate"
+                                    disabled=move || generating.get()
+                                    on:click=move |_| on_action(MessageAction::Regenerate(index))
+                                >
+                                    "↻"
+                                </button>
+                            }
+                        }
+                    >
+
+                        <button
+                            class="chat_message__action"
+                            title="Edit"
+                            disabled=move || generating.get()
+                            on:click=move |_| set_editing(true)
+                        >
+                            "✎"
+                        </button>
+                    </Show>
+                </div>
+            </div>
         </div>
     }
 }
+
+// Every token coloured from green to red by the bytes it added to the compressed conversation,
+// relative to the most expensive token of the answer. Hovering shows the candidates it beat.
+fn token_heatmap(tokens: &[TokenInfo]) -> impl IntoView {
+    let max_bytes = tokens.iter().map(|token| token.bytes_added).max().unwrap_or(0).max(1);
+    tokens
+        .iter()
+        .map(|token| {
+            let hue = 120 - 120 * token.bytes_added.min(max_bytes) / max_bytes;
+            let alternatives = token
+                .alternatives
+                .iter()
+                .map(|alternative| {
+                    let picked = alternative.text == token.text;
+                    view! {
+                        <li
+                            class="chat_message__alternative"
+                            class=("chat_message__alternative--picked", move || picked)
+                        >
+                            <span>{format!("{:?}", alternative.text)}</span>
+                            <span>+{alternative.bytes_added} B</span>
+                        </li>
+                    }
+                })
+                .collect_view();
+
+            view! {
+                <span class="chat_message__token" style=format!("background-color: hsl({} 55% 30%)", hue)>
+                    {token.text.clone()}
+                    <span class="chat_message__token_details">
+                        <span>+{token.bytes_added} B</span>
+                        <ol>{alternatives}</ol>
+                    </span>
+                </span>
+            }
+        })
+        .collect_view()
+}
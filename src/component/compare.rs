@@ -0,0 +1,305 @@
+use crate::chat::TokenInfo;
+use crate::compare::{bytes_per_token, record_preference, start_comparison, MAX_COMPARED_MODELS};
+use crate::component::prompt_input::PromptInput;
+use crate::component::prompt_section::stream_generation;
+use crate::component::sidebar::Sidebar;
+use crate::model::{cancel_generation, list_models, GenerationEvent};
+use futures::future::{AbortHandle, Abortable};
+use leptos::{
+    component, create_effect, create_resource, create_signal, spawn_local, store_value, untrack,
+    view, Callback, For, IntoView, RwSignal, Show, Signal, SignalGet, SignalGetUntracked,
+    SignalSet, SignalUpdate, SignalWith, SignalWithUntracked, WriteSignal,
+};
+
+// One answer of a comparison while it is streamed
+#[derive(Clone, Debug, Default)]
+struct Column {
+    model_id: String,
+    model_name: String,
+    generation_id: String,
+    text: String,
+    tokens: Vec<TokenInfo>,
+    notice: Option<String>, /* shown in place of the answer while it waits for a worker */
+    first_token_ms: Option<f64>,
+    total_ms: Option<f64>,
+    done: bool,
+    stopped: bool,
+    error: Option<String>,
+    vote: Option<bool>,
+}
+
+// One prompt answered by several models side by side, with a vote on every answer
+#[component]
+pub fn ComparePage(
+    conversation_id: RwSignal<Option<String>>,
+    conversations_changed: RwSignal<usize>,
+) -> impl IntoView {
+    let models = create_resource(|| (), |_| list_models());
+    let (selected, set_selected) = create_signal(Vec::<String>::new());
+    let (comparison_id, set_comparison_id) = create_signal(None::<String>);
+    let (columns, set_columns) = create_signal(Vec::<Column>::new());
+    let (error, set_error) = create_signal(None::<String>);
+    let abort_handles = store_value(Vec::<AbortHandle>::new());
+    let generating = Signal::derive(move || columns.with(|columns| columns.iter().any(|column| !column.done)));
+
+    // the first two models are compared until others are picked
+    create_effect(move |_| {
+        if let Some(Ok(models)) = models.get() {
+            if selected.get_untracked().is_empty() {
+                set_selected.set(models.iter().take(2).map(|model| model.id.clone()).collect());
+            }
+        }
+    });
+
+    let toggle = move |id: String| {
+        set_selected.update(|selected| match selected.iter().position(|selected| *selected == id) {
+            Some(position) => {
+                selected.remove(position);
+            }
+            None if selected.len() < MAX_COMPARED_MODELS => selected.push(id),
+            None => {}
+        });
+    };
+
+    let compare = move |prompt: String| {
+        set_error.set(None);
+        let model_ids = selected.get_untracked();
+        let names = untrack(move || models.get()).and_then(Result::ok).unwrap_or_default();
+        spawn_local(async move {
+            let comparison = match start_comparison(model_ids, prompt).await {
+                Ok(comparison) => comparison,
+                Err(error) => {
+                    set_error.set(Some(error.to_string()));
+                    return;
+                }
+            };
+            set_comparison_id.set(Some(comparison.id.clone()));
+            set_columns.set(
+                comparison
+                    .generations
+                    .iter()
+                    .map(|generation| Column {
+                        model_id: generation.model_id.clone(),
+                        model_name: names
+                            .iter()
+                            .find(|model| model.id == generation.model_id)
+                            .map(|model| model.name.clone())
+                            .unwrap_or_else(|| generation.model_id.clone()),
+                        generation_id: generation.generation_id.clone(),
+                        ..Column::default()
+                    })
+                    .collect(),
+            );
+
+            let mut handles = Vec::new();
+            for (index, generation) in comparison.generations.into_iter().enumerate() {
+                let (handle, registration) = AbortHandle::new_pair();
+                handles.push(handle);
+                spawn_local(async move {
+                    let started = now_ms();
+                    let stream = stream_generation(&generation.generation_id, move |event| {
+                        update_column(set_columns, index, |column| match event {
+                            GenerationEvent::Queued { position } => {
+                                column.notice = Some(format!("Waiting for a free slot, {} ahead in line…", position - 1));
+                            }
+                            GenerationEvent::Token(token) => {
+                                column.first_token_ms.get_or_insert(now_ms() - started);
+                                column.notice = None;
+                                column.text.push_str(&token.text);
+                                column.tokens.push(token);
+                            }
+                            GenerationEvent::Done { finish_reason } => {
+                                column.notice = None;
+                                column.total_ms = Some(now_ms() - started);
+                                column.stopped = finish_reason == "cancelled";
+                                column.done = true;
+                            }
+                            // errors end the stream before they get here
+                            GenerationEvent::Error { .. } => {}
+                        })
+                    });
+                    // an aborted stream was already marked as stopped
+                    if let Ok(Err(error)) = Abortable::new(stream, registration).await {
+                        update_column(set_columns, index, |column| {
+                            column.error = Some(error);
+                            column.done = true;
+                        });
+                    }
+                });
+            }
+            abort_handles.set_value(handles);
+        });
+    };
+
+    let stop = move || {
+        for handle in abort_handles.get_value() {
+            handle.abort();
+        }
+        set_columns.update(|columns| {
+            for column in columns.iter_mut().filter(|column| !column.done) {
+                let id = column.generation_id.clone();
+                spawn_local(async move {
+                    let _ = cancel_generation(id).await;
+                });
+                column.notice = None;
+                column.stopped = true;
+                column.done = true;
+            }
+        });
+    };
+
+    // the server recorded the answer and its timings, the vote only names it
+    let vote = move |index: usize, preferred: bool| {
+        let Some(comparison_id) = comparison_id.get_untracked() else {
+            return;
+        };
+        let Some(model_id) = columns.with_untracked(|columns| columns.get(index).map(|column| column.model_id.clone())) else {
+            return;
+        };
+        spawn_local(async move {
+            match record_preference(comparison_id, model_id, preferred).await {
+                Ok(()) => update_column(set_columns, index, |column| column.vote = Some(preferred)),
+                Err(error) => set_error.set(Some(error.to_string())),
+            }
+        });
+    };
+
+    view! {
+        <Sidebar conversation_id=conversation_id conversations_changed=conversations_changed/>
+
+        <div class="compare">
+            <nav class="compare__models">
+                <span class="compare__title">Compare up to {MAX_COMPARED_MODELS} models</span>
+                <For
+                    each=move || models.get().and_then(Result::ok).unwrap_or_default()
+                    key=|model| model.id.clone()
+                    children=move |model| {
+                        let id = model.id.clone();
+                        let is_selected = {
+                            let id = id.clone();
+                            move || selected.with(|selected| selected.contains(&id))
+                        };
+                        let is_full = move || selected.with(Vec::len) >= MAX_COMPARED_MODELS;
+                        view! {
+                            <label class="compare__model">
+                                <input
+                                    type="checkbox"
+                                    prop:checked=is_selected.clone()
+                                    disabled=move || generating.get() || (is_full() && !is_selected())
+                                    on:change=move |_| toggle(id.clone())
+                                />
+                                {model.name}
+                            </label>
+                        }
+                    }
+                />
+
+            </nav>
+
+            <section class="compare__columns">
+                <Show when=move || error.get().is_some()>
+                    <p class="compare__error">{move || error.get()}</p>
+                </Show>
+                <Show when=move || columns.with(Vec::is_empty)>
+                    <p class="compare__welcome">
+                        Pick two or more models and send a prompt to see their answers side by side.
+                    </p>
+                </Show>
+                <For
+                    each=move || columns.get().into_iter().enumerate()
+                    key=|(index, column)| {
+                        format!(
+                            "{}: {} {} {:?} {:?} {:?} {:?}",
+                            index,
+                            column.generation_id,
+                            column.text,
+                            column.notice,
+                            column.total_ms,
+                            column.error,
+                            column.vote,
+                        )
+                    }
+                    children=move |(index, column)| {
+                        let votable = column.done && column.error.is_none() && !column.text.trim().is_empty();
+                        let vote_class = move |preferred: bool| {
+                            if column.vote == Some(preferred) {
+                                "compare__vote compare__vote--chosen"
+                            } else {
+                                "compare__vote"
+                            }
+                        };
+                        view! {
+                            <div class="compare__column">
+                                <h2 class="compare__model_name">{column.model_name.clone()}</h2>
+                                <p class="compare__stats">{stats(&column)}</p>
+                                <p class="compare__answer">
+                                    {column.notice.clone().unwrap_or_else(|| column.text.trim().to_string())}
+                                    <Show when=move || column.stopped>
+                                        <span class="chat_message__stopped">(stopped)</span>
+                                    </Show>
+                                </p>
+                                {column.error.clone().map(|error| view! { <p class="compare__error">{error}</p> })}
+                                <Show when=move || votable>
+                                    <div class="compare__votes">
+                                        <button
+                                            class=vote_class(true)
+                                            title="Better answer"
+                                            on:click=move |_| vote(index, true)
+                                        >
+                                            "👍"
+                                        </button>
+                                        <button
+                                            class=vote_class(false)
+                                            title="Worse answer"
+                                            on:click=move |_| vote(index, false)
+                                        >
+                                            "👎"
+                                        </button>
+                                    </div>
+                                </Show>
+                            </div>
+                        }
+                    }
+                />
+
+            </section>
+
+            <section class="prompt">
+                <div class="prompt__wrapper">
+                    <PromptInput
+                        generating=generating
+                        on_stop=Callback::new(move |_| stop())
+                        on_submit=Callback::new(compare)
+                    />
+                </div>
+            </section>
+        </div>
+    }
+}
+
+fn update_column(set_columns: WriteSignal<Vec<Column>>, index: usize, change: impl FnOnce(&mut Column)) {
+    set_columns.update(|columns| {
+        if let Some(column) = columns.get_mut(index) {
+            change(column);
+        }
+    });
+}
+
+// Latency as the browser saw it and how predictable the model found its own answer
+fn stats(column: &Column) -> String {
+    let mut stats = Vec::new();
+    if let Some(first_token_ms) = column.first_token_ms {
+        stats.push(format!("{:.0} ms to the first token", first_token_ms));
+    }
+    if let Some(total_ms) = column.total_ms {
+        stats.push(format!("{:.1} s in total", total_ms / 1000.0));
+    }
+    if let Some(bytes_per_token) = bytes_per_token(&column.tokens) {
+        stats.push(format!("{:.2} bytes per token", bytes_per_token));
+    }
+    stats.join(" · ")
+}
+
+fn now_ms() -> f64 {
+    chrono::Utc::now().timestamp_millis() as f64
+}
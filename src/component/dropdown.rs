@@ -1,14 +1,25 @@
 use leptos::{
-    component, create_signal, view, For, IntoView, ReadSignal, SignalSet, SignalUpdate, WriteSignal,
+    component, create_signal, view, For, IntoView, ReadSignal, Signal, SignalGet, SignalSet,
+    SignalUpdate, WriteSignal,
 };
 
 #[component]
-pub fn Dropdown<const N: usize>(
-    options: [&'static str; N],
-    selected_option_index: ReadSignal<usize>,
-    set_selected_option_index: WriteSignal<usize>,
+pub fn Dropdown(
+    #[prop(into)] options: Signal<Vec<(String, String)>>, /* value and label */
+    selected_option: ReadSignal<String>,
+    set_selected_option: WriteSignal<String>,
 ) -> impl IntoView {
     let (is_open, set_open) = create_signal(false);
+    // nothing selected yet shows the first option
+    let selected_label = move || {
+        let options = options.get();
+        options
+            .iter()
+            .find(|(value, _)| *value == selected_option())
+            .or(options.first())
+            .map(|(_, label)| label.clone())
+            .unwrap_or_default()
+    };
 
     view! {
         <div class="dropdown">
@@ -19,24 +30,24 @@ pub fn Dropdown<const N: usize>(
                 }
             >
 
-                <div>{move || options[selected_option_index()]}</div>
+                <div>{selected_label}</div>
                 <div class="dropdown__icon">></div>
             </div>
             <div class="dropdown__options" class=("hidden", move || !is_open())>
                 <For
-                    each=move || options.into_iter().enumerate()
-                    key=|(_, option)| *option
-                    children=move |(index, option)| {
+                    each=move || options.get()
+                    key=|(value, _)| value.clone()
+                    children=move |(value, label)| {
                         view! {
                             <div
                                 class="dropdown__option"
                                 on:click=move |_| {
                                     set_open.set(false);
-                                    set_selected_option_index.set(index);
+                                    set_selected_option.set(value.clone());
                                 }
                             >
 
-                                {option}
+                                {label}
                             </div>
                         }
                     }
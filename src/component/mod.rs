@@ -1,6 +1,9 @@
 pub mod chat;
 pub mod chat_message;
+pub mod compare;
 pub mod dropdown;
 pub mod navbar;
 pub mod prompt_input;
 pub mod prompt_section;
+pub mod settings_panel;
+pub mod sidebar;
@@ -1,25 +1,50 @@
 use crate::component::dropdown::Dropdown;
-use crate::model::FrontendModel;
-use leptos::{component, view, IntoView, ReadSignal, WriteSignal};
+use crate::component::settings_panel::SettingsPanel;
+use crate::model::{list_models, GenerationSettings};
+use leptos::{
+    component, create_effect, create_resource, view, IntoView, ReadSignal, RwSignal, Signal,
+    SignalGet, SignalSet, Transition, WriteSignal,
+};
 
 #[component]
 pub fn NavBar(
-    selected_model_index: ReadSignal<usize>,
-    set_selected_model_index: WriteSignal<usize>,
+    selected_model: ReadSignal<String>,
+    set_selected_model: WriteSignal<String>,
+    settings: RwSignal<GenerationSettings>,
+    conversation_id: RwSignal<Option<String>>,
 ) -> impl IntoView {
+    let models = create_resource(|| (), |_| list_models());
+    let options = Signal::derive(move || {
+        models
+            .get()
+            .and_then(Result::ok)
+            .unwrap_or_default()
+            .into_iter()
+            .map(|model| (model.id, model.name))
+            .collect::<Vec<_>>()
+    });
+
+    // unknown models, like ones removed from the registry, fall back to the default model
+    create_effect(move |_| {
+        let options = options.get();
+        let selected = selected_model.get();
+        if !options.iter().any(|(id, _)| *id == selected) {
+            if let Some((id, _)) = options.first() {
+                set_selected_model.set(id.clone());
+            }
+        }
+    });
+
     view! {
         <nav class="navbar">
-            <Dropdown
-                options=[
-                    FrontendModel::ChatCLM1_0.name(),
-                    FrontendModel::ChatGPT3_5.name(),
-                    FrontendModel::ChatGPT4o.name(),
-                    FrontendModel::ChatRandom.name(),
-                ]
-
-                selected_option_index=selected_model_index
-                set_selected_option_index=set_selected_model_index
-            />
+            <Transition fallback=|| ()>
+                <Dropdown
+                    options=options
+                    selected_option=selected_model
+                    set_selected_option=set_selected_model
+                />
+            </Transition>
+            <SettingsPanel settings=settings conversation_id=conversation_id/>
         </nav>
     }
 }
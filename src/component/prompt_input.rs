@@ -1,15 +1,25 @@
 use leptos::{
     component, create_node_ref, create_signal, html, view, Callback, IntoView, NodeRef, Show,
+    Signal, SignalGet,
 };
 
 #[component]
-pub fn PromptInput(on_submit: Callback<String>) -> impl IntoView {
+pub fn PromptInput(
+    on_submit: Callback<String>,
+    on_stop: Callback<()>,
+    #[prop(into)] generating: Signal<bool>,
+) -> impl IntoView {
     let (show_placeholder, set_show_placeholder) = create_signal(true);
     let textarea_element: NodeRef<html::Div> = create_node_ref();
 
     let submit = move || {
+        // one answer at a time, the running one has to be stopped first
+        if generating.get() {
+            return;
+        }
+
         let input = textarea_element().unwrap().inner_text().trim().to_string();
-        if input.len() == 0 {
+        if input.is_empty() {
             return;
         }
 
@@ -29,7 +39,7 @@ pub fn PromptInput(on_submit: Callback<String>) -> impl IntoView {
                     }
 
                     on:blur=move |_| {
-                        set_show_placeholder(textarea_element().unwrap().inner_text().len() == 0);
+                        set_show_placeholder(textarea_element().unwrap().inner_text().is_empty());
                     }
 
                     on:keydown=move |ev| {
@@ -45,9 +55,24 @@ pub fn PromptInput(on_submit: Callback<String>) -> impl IntoView {
                     <div class="prompt_input__textarea_placeholder">Message ChatCLM</div>
                 </Show>
 
-                <button on:click=move |_| submit() class="prompt_input__send_button">
+                <Show
+                    when=move || generating.get()
+                    fallback=move || {
+                        view! {
+                            <button on:click=move |_| submit() class="prompt_input__send_button">
+                                >
+                            </button>
+                        }
+                    }
+                >
+
+                    <button
+                        on:click=move |_| on_stop(())
+                        class="prompt_input__send_button prompt_input__send_button--stop"
                     >
-                </button>
+                        x
+                    </button>
+                </Show>
             </div>
         </div>
     }
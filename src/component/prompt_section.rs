@@ -1,55 +1,246 @@
-use crate::chat::ChatHistory;
+use crate::chat::{create_conversation, save_conversation, ChatHistory, MessageAction};
 use crate::component::prompt_input::PromptInput;
-use crate::model::{cut_prompt, get_next_token};
+use crate::model::{
+    cancel_generation, generation_events_url, start_generation, GenerationEvent, GenerationSettings,
+};
+use futures::future::{AbortHandle, Abortable};
+use futures::StreamExt;
+use gloo_net::eventsource::futures::EventSource;
 use leptos::{
-    component, spawn_local, view, Callback, IntoView, ReadSignal, SignalUpdate, WriteSignal,
+    component, create_effect, spawn_local, store_value, view, Callback, IntoView,
+    ReadSignal, RwSignal, SignalGet, SignalGetUntracked, SignalSet, SignalUpdate, StoredValue,
+    WriteSignal,
 };
+use leptos_router::use_navigate;
+
+// Shown in a server message until the first token of its answer arrives
+const THINKING: &str = "thinking...";
 
 #[component]
 pub fn PromptSection(
+    chat: ReadSignal<ChatHistory>,
     set_chat: WriteSignal<ChatHistory>,
-    selected_model_index: ReadSignal<usize>,
+    selected_model: ReadSignal<String>,
+    settings: RwSignal<GenerationSettings>,
+    conversation_id: RwSignal<Option<String>>,
+    conversations_changed: RwSignal<usize>,
+    generating: RwSignal<bool>,
+    actions: ReadSignal<Option<MessageAction>>,
 ) -> impl IntoView {
-    view! {
-        <section class="prompt">
-            <div class="prompt__wrapper">
-                <PromptInput on_submit=Callback::new(move |prompt: String| {
-                    let model_idx = selected_model_index();
+    let abort_handle = store_value(None::<AbortHandle>);
+    let generation_id = store_value(None::<String>);
+    let navigate = store_value(use_navigate());
+
+    let save = move |history: ChatHistory| {
+        let Some(id) = conversation_id.get_untracked() else {
+            return;
+        };
+        let model_id = selected_model.get_untracked();
+        spawn_local(async move {
+            if save_conversation(id, model_id, history).await.is_ok() {
+                conversations_changed.update(|version| *version += 1);
+            }
+        });
+    };
+
+    let stop = move || {
+        // dropping the stream closes the connection, the server is told explicitly in case it is
+        // still scoring and has nothing to send
+        if let Some(handle) = abort_handle.get_value() {
+            handle.abort();
+        }
+        if let Some(id) = generation_id.get_value() {
+            spawn_local(async move {
+                let _ = cancel_generation(id).await;
+            });
+        }
+        set_chat
+            .update(|chat| {
+                chat.stop_last_server_message();
+            });
+        generating.set(false);
+    };
+
+    // Streams the answer to `history` into the last server message of the chat
+    let answer = move |history: ChatHistory| {
+        let model_id = selected_model.get_untracked();
+        let settings = settings.get_untracked();
+        let (handle, registration) = AbortHandle::new_pair();
+        abort_handle.set_value(Some(handle));
+        generation_id.set_value(None);
+        generating.set(true);
+        let navigate = navigate.get_value();
+        spawn_local(async move {
+            let response = async move {
+                // a new chat is only stored once something was said in it
+                if conversation_id.get_untracked().is_none() {
+                    let conversation = create_conversation(model_id.clone(), settings.clone())
+                        .await
+                        .map_err(|error| error.to_string())?;
+                    conversation_id.set(Some(conversation.id.clone()));
+                    navigate(&format!("/c/{}", conversation.id), Default::default());
+                }
+                save(chat.get_untracked());
+                stream_response(model_id, history, settings, generation_id, set_chat).await
+            };
+            // an aborted response was already cleaned up by the stop button
+            if let Ok(result) = Abortable::new(response, registration).await {
+                if let Err(error) = result {
                     set_chat
                         .update(|chat| {
-                            chat.new_user_message(prompt.clone());
+                            chat.replace_last_server_message(format!("Error: {}", error));
                         });
-                    spawn_local(async move {
+                }
+                generating.set(false);
+                save(chat.get_untracked());
+            }
+        });
+    };
+
+    // regenerating and editing fork the conversation and answer the new branch
+    create_effect(move |_| {
+        let Some(action) = actions.get() else {
+            return;
+        };
+        if generating.get_untracked() {
+            return;
+        }
+
+        let mut history = None;
+        set_chat
+            .update(|chat| match action {
+                MessageAction::Regenerate(index) => {
+                    history = chat.regenerate(index);
+                    if history.is_some() {
+                        chat.replace_last_server_message(THINKING.to_string());
+                    }
+                }
+                MessageAction::Edit(index, message) => {
+                    history = chat.edit(index, message);
+                    if history.is_some() {
+                        chat.new_server_message(THINKING.to_string());
+                    }
+                }
+                MessageAction::SelectVariant(index, variant) => chat.select_variant(index, variant),
+            });
+        match history {
+            Some(history) => answer(history),
+            None => save(chat.get_untracked()),
+        }
+    });
+
+    // an answer belongs to the conversation it was asked in, a new chat getting stored keeps it
+    create_effect(move |previous: Option<Option<String>>| {
+        let id = conversation_id.get();
+        if let Some(Some(previous)) = previous {
+            if id.as_ref() != Some(&previous) && generating.get_untracked() {
+                stop();
+            }
+        }
+        id
+    });
+
+    view! {
+        <section class="prompt">
+            <div class="prompt__wrapper">
+                <PromptInput
+                    generating=generating
+                    on_stop=Callback::new(move |_| {
+                        stop();
+                        save(chat.get_untracked());
+                    })
+
+                    on_submit=Callback::new(move |prompt: String| {
+                        set_chat
+                            .update(|chat| {
+                                chat.new_user_message(prompt);
+                            });
+                        // the model answers the whole conversation, not only the last prompt
+                        let history = chat.get_untracked();
                         set_chat
                             .update(|chat| {
-                                chat.new_server_message("thinking...".to_string());
+                                chat.new_server_message(THINKING.to_string());
                             });
-                        if let Ok(Some(response)) = get_next_token(model_idx, prompt.clone()).await
-                        {
-                            set_chat
-                                .update(|chat| {
-                                    chat.replace_last_server_message(
-                                        cut_prompt(&prompt, &response),
-                                    );
-                                });
-                            let mut response = response;
-                            while let Ok(Some(stream)) = get_next_token(model_idx, response.clone())
-                                .await
-                            {
-                                set_chat
-                                    .update(|chat| {
-                                        chat.replace_last_server_message(
-                                            cut_prompt(&prompt, &stream),
-                                        );
-                                    });
-                                response = stream;
-                            }
-                        }
-                    });
-                })/>
+                        answer(history);
+                    })
+                />
 
                 <p class="prompt__disclaimer">ChatCLM can make mistakes. Check important info.</p>
             </div>
         </section>
     }
 }
+
+// Writes the answer into the last server message while the server generates it
+async fn stream_response(
+    model_id: String,
+    history: ChatHistory,
+    settings: GenerationSettings,
+    generation_id: StoredValue<Option<String>>,
+    set_chat: WriteSignal<ChatHistory>,
+) -> Result<(), String> {
+    let id = start_generation(model_id, history, settings)
+        .await
+        .map_err(|error| error.to_string())?;
+    generation_id.set_value(Some(id.clone()));
+
+    let mut response = String::new();
+    let mut tokens = Vec::new();
+    stream_generation(&id, |event| match event {
+        GenerationEvent::Queued { position } => {
+            let message = format!("Waiting for a free slot, {} ahead in line…", position - 1);
+            set_chat
+                .update(|chat| {
+                    chat.replace_last_server_message(message);
+                });
+        }
+        GenerationEvent::Token(token) => {
+            response.push_str(&token.text);
+            tokens.push(token);
+            let message = response.trim().to_string();
+            set_chat
+                .update(|chat| {
+                    chat.replace_last_server_message(message);
+                    chat.set_last_server_tokens(tokens.clone());
+                });
+        }
+        GenerationEvent::Done { finish_reason } => {
+            // an answer without tokens still replaces the queue notice
+            let message = response.trim().to_string();
+            set_chat
+                .update(|chat| {
+                    chat.replace_last_server_message(message);
+                    if finish_reason == "cancelled" {
+                        chat.stop_last_server_message();
+                    }
+                });
+        }
+        // errors end the stream before they get here
+        GenerationEvent::Error { .. } => {}
+    })
+    .await
+}
+
+// Hands the events of the generation `id` to `on_event` until it is done, an error event ends
+// the stream with its message instead
+pub async fn stream_generation(id: &str, mut on_event: impl FnMut(GenerationEvent)) -> Result<(), String> {
+    // the connection is closed when `events` is dropped, otherwise the browser would reconnect
+    let mut events = EventSource::new(&generation_events_url(id)).map_err(|error| error.to_string())?;
+    let mut messages = events.subscribe("message").map_err(|error| error.to_string())?;
+
+    while let Some(message) = messages.next().await {
+        let (_, message) = message.map_err(|_| "Lost connection to the server".to_string())?;
+        let data = message.data().as_string().unwrap_or_default();
+
+        match serde_json::from_str(&data).map_err(|error| error.to_string())? {
+            GenerationEvent::Error { message } => return Err(message),
+            event @ GenerationEvent::Done { .. } => {
+                on_event(event);
+                break;
+            }
+            event => on_event(event),
+        }
+    }
+
+    Ok(())
+}
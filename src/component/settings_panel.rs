@@ -0,0 +1,136 @@
+use crate::chat::save_conversation_settings;
+use crate::model::{GenerationSettings, MAX_STOP_SEQUENCES, MAX_TEMPERATURE};
+use leptos::{
+    component, create_signal, event_target_value, spawn_local, view, IntoView, RwSignal,
+    SignalGet, SignalGetUntracked, SignalSet, SignalUpdate,
+};
+use std::str::FromStr;
+
+#[component]
+pub fn SettingsPanel(
+    settings: RwSignal<GenerationSettings>,
+    conversation_id: RwSignal<Option<String>>,
+) -> impl IntoView {
+    let (is_open, set_open) = create_signal(false);
+
+    // a conversation that is not stored yet is created with the settings of its first answer
+    let change = move |changed: GenerationSettings| {
+        settings.set(changed.clone());
+        if let Some(id) = conversation_id.get_untracked() {
+            spawn_local(async move {
+                let _ = save_conversation_settings(id, changed).await;
+            });
+        }
+    };
+    let current = move || settings.get_untracked();
+
+    view! {
+        <div class="settings">
+            <button
+                class="settings__toggle"
+                title="Generation settings"
+                on:click=move |_| set_open.update(|is_currently_open| *is_currently_open = !*is_currently_open)
+            >
+                "⚙"
+            </button>
+            <div class="settings__drawer" class=("hidden", move || !is_open())>
+                {number_field(
+                    "Temperature",
+                    "0.1",
+                    MAX_TEMPERATURE.to_string(),
+                    move || show(settings.get().temperature),
+                    move |text| change(GenerationSettings { temperature: parse(&text), ..current() }),
+                )}
+                {number_field(
+                    "Top-k",
+                    "1",
+                    String::new(),
+                    move || show(settings.get().top_k),
+                    move |text| change(GenerationSettings { top_k: parse(&text), ..current() }),
+                )}
+                {number_field(
+                    "Max tokens",
+                    "1",
+                    String::new(),
+                    move || show(settings.get().max_tokens),
+                    move |text| change(GenerationSettings { max_tokens: parse(&text), ..current() }),
+                )}
+                {number_field(
+                    "Lookahead depth",
+                    "1",
+                    String::new(),
+                    move || show(settings.get().depth),
+                    move |text| change(GenerationSettings { depth: parse(&text), ..current() }),
+                )}
+                {number_field(
+                    "Beam width",
+                    "1",
+                    String::new(),
+                    move || show(settings.get().width),
+                    move |text| change(GenerationSettings { width: parse(&text), ..current() }),
+                )}
+                {number_field(
+                    "Seed",
+                    "1",
+                    String::new(),
+                    move || show(settings.get().seed),
+                    move |text| change(GenerationSettings { seed: parse(&text), ..current() }),
+                )}
+                <label class="settings__field settings__field--wide">
+                    <span>Stop sequences, one per line (at most {MAX_STOP_SEQUENCES})</span>
+                    <textarea
+                        rows="3"
+                        prop:value=move || settings.get().stop.join("\n")
+                        on:change=move |event| {
+                            let stop = event_target_value(&event)
+                                .lines()
+                                .filter(|line| !line.is_empty())
+                                .take(MAX_STOP_SEQUENCES)
+                                .map(str::to_string)
+                                .collect();
+                            change(GenerationSettings { stop, ..current() });
+                        }
+                    ></textarea>
+                </label>
+                <p class="settings__hint">
+                    Empty fields keep the defaults of the model. The lookahead is only used with temperature 0.
+                </p>
+                <button class="settings__reset" on:click=move |_| change(GenerationSettings::default())>
+                    Reset to model defaults
+                </button>
+            </div>
+        </div>
+    }
+}
+
+fn number_field(
+    label: &'static str,
+    step: &'static str,
+    max: String,
+    value: impl Fn() -> String + 'static,
+    on_change: impl Fn(String) + 'static,
+) -> impl IntoView {
+    view! {
+        <label class="settings__field">
+            <span>{label}</span>
+            <input
+                type="number"
+                min="0"
+                max=max
+                step=step
+                placeholder="default"
+                prop:value=value
+                on:change=move |event| on_change(event_target_value(&event))
+            />
+        </label>
+    }
+}
+
+fn show<T: ToString>(value: Option<T>) -> String {
+    value.map(|value| value.to_string()).unwrap_or_default()
+}
+
+// an empty or unreadable field falls back to the default
+fn parse<T: FromStr>(text: &str) -> Option<T> {
+    text.trim().parse().ok()
+}
@@ -0,0 +1,115 @@
+use crate::chat::{delete_conversation, list_conversations, rename_conversation, ConversationSummary};
+use leptos::{
+    component, create_resource, spawn_local, view, window, CollectView, IntoView, RwSignal,
+    SignalGet, SignalGetUntracked, SignalUpdate, Transition,
+};
+use leptos_router::{use_navigate, A};
+
+#[component]
+pub fn Sidebar(
+    conversation_id: RwSignal<Option<String>>,
+    conversations_changed: RwSignal<usize>,
+) -> impl IntoView {
+    let conversations = create_resource(move || conversations_changed.get(), |_| list_conversations());
+
+    view! {
+        <aside class="sidebar">
+            <A href="/" class="sidebar__new">
+                New chat
+            </A>
+            <A href="/compare" class="sidebar__new">
+                Compare models
+            </A>
+
+            <Transition fallback=|| ()>
+                {move || {
+                    conversations
+                        .get()
+                        .map(|conversations| match conversations {
+                            Ok(conversations) => {
+                                conversations
+                                    .into_iter()
+                                    .map(|conversation| {
+                                        view! {
+                                            <SidebarItem
+                                                conversation=conversation
+                                                conversation_id=conversation_id
+                                                conversations_changed=conversations_changed
+                                            />
+                                        }
+                                    })
+                                    .collect_view()
+                            }
+                            Err(error) => {
+                                view! { <p class="sidebar__error">{error.to_string()}</p> }
+                                    .into_view()
+                            }
+                        })
+                }}
+
+            </Transition>
+        </aside>
+    }
+}
+
+#[component]
+fn SidebarItem(
+    conversation: ConversationSummary,
+    conversation_id: RwSignal<Option<String>>,
+    conversations_changed: RwSignal<usize>,
+) -> impl IntoView {
+    let navigate = use_navigate();
+    let id = conversation.id.clone();
+    let is_active = move || conversation_id.get().as_ref() == Some(&id);
+
+    let rename = {
+        let id = conversation.id.clone();
+        let title = conversation.title.clone();
+        move |_| {
+            let Ok(Some(new_title)) =
+                window().prompt_with_message_and_default("Rename conversation", &title)
+            else {
+                return;
+            };
+            let id = id.clone();
+            spawn_local(async move {
+                if rename_conversation(id, new_title).await.is_ok() {
+                    conversations_changed.update(|version| *version += 1);
+                }
+            });
+        }
+    };
+
+    let delete = {
+        let id = conversation.id.clone();
+        move |_| {
+            if !window().confirm_with_message("Delete this conversation?").unwrap_or(false) {
+                return;
+            }
+            let id = id.clone();
+            let navigate = navigate.clone();
+            spawn_local(async move {
+                if delete_conversation(id.clone()).await.is_ok() {
+                    if conversation_id.get_untracked().as_ref() == Some(&id) {
+                        navigate("/", Default::default());
+                    }
+                    conversations_changed.update(|version| *version += 1);
+                }
+            });
+        }
+    };
+
+    view! {
+        <div class="sidebar__item" class=("sidebar__item--active", is_active)>
+            <A href=format!("/c/{}", conversation.id) class="sidebar__title">
+                {conversation.title}
+            </A>
+            <button class="sidebar__action" on:click=rename title="Rename">
+                "✎"
+            </button>
+            <button class="sidebar__action" on:click=delete title="Delete">
+                "×"
+            </button>
+        </div>
+    }
+}
@@ -0,0 +1,327 @@
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::OnceLock;
+
+use clap::Parser;
+use serde::Deserialize;
+
+use crate::backend::error::{ClmError, ClmResult};
+use crate::rate_limit::RateLimitConfig;
+use crate::registry::GenerationDefaults;
+use crate::request_log::RequestLogConfig;
+
+// Read when it exists and no other file is given
+const DEFAULT_CONFIG_PATH: &str = "chatclm.toml";
+
+/// Serves the ChatCLM chat and its OpenAI compatible API.
+/// Flags and environment variables override the configuration file.
+#[derive(Parser, Debug, Default)]
+#[command(name = "chatclm")]
+pub struct Cli {
+    /// Configuration file [default: chatclm.toml if it exists]
+    #[arg(long, env = "CHATCLM_CONFIG")]
+    pub config: Option<PathBuf>,
+    /// Address to listen on [default: LEPTOS_SITE_ADDR]
+    #[arg(long, env = "CHATCLM_ADDRESS")]
+    pub address: Option<SocketAddr>,
+    /// Directory that relative model registry and database paths are resolved against
+    #[arg(long, env = "CHATCLM_DATA_DIR")]
+    pub data_dir: Option<PathBuf>,
+    /// Model registry file
+    #[arg(long, env = "CHATCLM_MODELS")]
+    pub models: Option<PathBuf>,
+    /// SQLite database of the conversations
+    #[arg(long, env = "CHATCLM_DATABASE")]
+    pub database: Option<PathBuf>,
+    /// Threads answering HTTP requests, 0 means one per core
+    #[arg(long, env = "CHATCLM_HTTP_THREADS")]
+    pub http_threads: Option<usize>,
+    /// Threads scoring candidate tokens, 0 means one per core
+    #[arg(long, env = "CHATCLM_COMPUTE_THREADS")]
+    pub compute_threads: Option<usize>,
+    /// Reverse proxies whose X-Forwarded-For header names the client, comma separated
+    #[arg(long, env = "CHATCLM_TRUSTED_PROXIES", value_delimiter = ',')]
+    pub trusted_proxies: Vec<IpAddr>,
+
+    /// Tokens of a chat conversation the model sees
+    #[arg(long, env = "CHATCLM_CONTEXT_TOKENS", help_heading = "Limits")]
+    pub context_tokens: Option<usize>,
+    /// Tokens an API request or chat answer may ask for
+    #[arg(long, env = "CHATCLM_MAX_TOKENS", help_heading = "Limits")]
+    pub max_tokens: Option<usize>,
+    /// Choices an API request may ask for
+    #[arg(long, env = "CHATCLM_MAX_CHOICES", help_heading = "Limits")]
+    pub max_choices: Option<usize>,
+    /// Prompts of a completion request
+    #[arg(long, env = "CHATCLM_MAX_PROMPTS", help_heading = "Limits")]
+    pub max_prompts: Option<usize>,
+    /// Beam search lookahead the chat settings may ask for
+    #[arg(long, env = "CHATCLM_MAX_DEPTH", help_heading = "Limits")]
+    pub max_depth: Option<usize>,
+    /// Beam width the chat settings may ask for
+    #[arg(long, env = "CHATCLM_MAX_WIDTH", help_heading = "Limits")]
+    pub max_width: Option<usize>,
+    /// Generations running at the same time
+    #[arg(long, env = "CHATCLM_CONCURRENT_GENERATIONS", help_heading = "Limits")]
+    pub concurrent_generations: Option<usize>,
+    /// Generations waiting for a worker, more are turned away
+    #[arg(long, env = "CHATCLM_MAX_QUEUE", help_heading = "Limits")]
+    pub max_queue: Option<usize>,
+    /// Generations one client may have waiting, so it cannot fill the queue for everyone else
+    #[arg(long, env = "CHATCLM_MAX_CLIENT_QUEUE", help_heading = "Limits")]
+    pub max_client_queue: Option<usize>,
+    /// Seconds a generation may wait for a worker
+    #[arg(long, env = "CHATCLM_MAX_QUEUE_WAIT_SECS", help_heading = "Limits")]
+    pub max_queue_wait_secs: Option<u64>,
+
+    /// Tokens of an answer unless the request asks for another number
+    #[arg(long, env = "CHATCLM_MAX_NEW_TOKENS", help_heading = "Generation defaults")]
+    pub max_new_tokens: Option<usize>,
+    /// Beam search lookahead
+    #[arg(long, env = "CHATCLM_DEPTH", help_heading = "Generation defaults")]
+    pub depth: Option<usize>,
+    /// Beam width
+    #[arg(long, env = "CHATCLM_WIDTH", help_heading = "Generation defaults")]
+    pub width: Option<usize>,
+    /// Sampling temperature, 0 picks the best token
+    #[arg(long, env = "CHATCLM_TEMPERATURE", help_heading = "Generation defaults")]
+    pub temperature: Option<f64>,
+    /// Seconds a generation may take
+    #[arg(long, env = "CHATCLM_TIMEOUT_SECS", help_heading = "Generation defaults")]
+    pub timeout_secs: Option<u64>,
+}
+
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub address: Option<SocketAddr>,
+    pub data_dir: PathBuf,
+    pub models: PathBuf,
+    pub database: PathBuf,
+    pub threads: ThreadConfig,
+    pub trusted_proxies: Vec<IpAddr>, /* clients are told apart by the forwarded address only behind these */
+    pub limits: Limits,
+    pub generation: GenerationDefaults, /* for registry models without their own generation settings */
+    pub request_log: RequestLogConfig,
+    pub rate_limits: RateLimitConfig,
+}
+
+#[derive(Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ThreadConfig {
+    pub http: usize, /* tokio workers, 0 means one per core */
+    pub compute: usize, /* rayon pool the models score candidates on, 0 means one per core */
+}
+
+#[derive(Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Limits {
+    pub context_tokens: usize, /* how much of a chat conversation the model sees */
+    pub max_tokens: usize, /* every token scores the whole vocabulary, keep API requests bounded */
+    pub max_choices: usize,
+    pub max_prompts: usize, /* per completion request, every prompt is answered `n` times */
+    pub max_depth: usize, /* beam search lookahead the chat can ask for, every step multiplies the scoring by the width */
+    pub max_width: usize,
+    pub concurrent_generations: usize, /* worker threads, each runs one generation at a time */
+    pub max_queue: usize, /* generations waiting for a worker, more are turned away */
+    pub max_client_queue: usize, /* of those, waiting for the same client */
+    pub max_queue_wait_secs: u64,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            address: None,
+            data_dir: PathBuf::from("."),
+            models: PathBuf::from("models.toml"),
+            database: PathBuf::from("chatclm.sqlite"),
+            threads: ThreadConfig::default(),
+            trusted_proxies: Vec::new(),
+            limits: Limits::default(),
+            generation: GenerationDefaults::default(),
+            request_log: RequestLogConfig::default(),
+            rate_limits: RateLimitConfig::default(),
+        }
+    }
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            context_tokens: 256,
+            max_tokens: 512,
+            max_choices: 8,
+            max_prompts: 4,
+            max_depth: 3,
+            max_width: 8,
+            concurrent_generations: 2,
+            max_queue: 32,
+            max_client_queue: 8,
+            max_queue_wait_secs: 30,
+        }
+    }
+}
+
+impl ServerConfig {
+    pub fn from_toml(text: &str) -> ClmResult<Self> {
+        toml::from_str(text).map_err(|error| ClmError::Config(error.to_string()))
+    }
+
+    // The configuration file given on the command line has to exist, the default one does not
+    pub fn from_cli(cli: &Cli) -> ClmResult<Self> {
+        let path = cli.config.clone().unwrap_or_else(|| PathBuf::from(DEFAULT_CONFIG_PATH));
+        let mut config = match std::fs::read_to_string(&path) {
+            Ok(text) => Self::from_toml(&text)
+                .map_err(|error| ClmError::Config(format!("{}: {}", path.display(), error)))?,
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound && cli.config.is_none() => ServerConfig::default(),
+            Err(error) => return Err(ClmError::Config(format!("{}: {}", path.display(), error))),
+        };
+
+        config.address = cli.address.or(config.address);
+        config.data_dir = cli.data_dir.clone().unwrap_or(config.data_dir);
+        config.models = cli.models.clone().unwrap_or(config.models);
+        config.database = cli.database.clone().unwrap_or(config.database);
+        config.threads.http = cli.http_threads.unwrap_or(config.threads.http);
+        config.threads.compute = cli.compute_threads.unwrap_or(config.threads.compute);
+        if !cli.trusted_proxies.is_empty() {
+            config.trusted_proxies = cli.trusted_proxies.clone();
+        }
+
+        let limits = &mut config.limits;
+        limits.context_tokens = cli.context_tokens.unwrap_or(limits.context_tokens);
+        limits.max_tokens = cli.max_tokens.unwrap_or(limits.max_tokens);
+        limits.max_choices = cli.max_choices.unwrap_or(limits.max_choices);
+        limits.max_prompts = cli.max_prompts.unwrap_or(limits.max_prompts);
+        limits.max_depth = cli.max_depth.unwrap_or(limits.max_depth);
+        limits.max_width = cli.max_width.unwrap_or(limits.max_width);
+        limits.concurrent_generations = cli.concurrent_generations.unwrap_or(limits.concurrent_generations);
+        limits.max_queue = cli.max_queue.unwrap_or(limits.max_queue);
+        limits.max_client_queue = cli.max_client_queue.unwrap_or(limits.max_client_queue);
+        limits.max_queue_wait_secs = cli.max_queue_wait_secs.unwrap_or(limits.max_queue_wait_secs);
+
+        let generation = &mut config.generation;
+        generation.max_new_tokens = cli.max_new_tokens.unwrap_or(generation.max_new_tokens);
+        generation.depth = cli.depth.unwrap_or(generation.depth);
+        generation.width = cli.width.unwrap_or(generation.width);
+        generation.temperature = cli.temperature.unwrap_or(generation.temperature);
+        generation.timeout_secs = cli.timeout_secs.or(generation.timeout_secs);
+
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn validate(&self) -> ClmResult<()> {
+        if !self.data_dir.is_dir() {
+            return Err(ClmError::Config(format!("the data directory {} does not exist", self.data_dir.display())));
+        }
+        for (name, value) in [
+            ("limits.context_tokens", self.limits.context_tokens),
+            ("limits.max_tokens", self.limits.max_tokens),
+            ("limits.max_choices", self.limits.max_choices),
+            ("limits.max_prompts", self.limits.max_prompts),
+            ("limits.max_width", self.limits.max_width),
+            ("limits.concurrent_generations", self.limits.concurrent_generations),
+            ("limits.max_client_queue", self.limits.max_client_queue),
+            ("generation.width", self.generation.width),
+        ] {
+            if value == 0 {
+                return Err(ClmError::Config(format!("{} must be at least 1", name)));
+            }
+        }
+        // the defaults are what a chat without its own settings asks for, they have to pass the limits
+        for (name, value, limit, limit_name) in [
+            ("generation.depth", self.generation.depth, self.limits.max_depth, "limits.max_depth"),
+            ("generation.width", self.generation.width, self.limits.max_width, "limits.max_width"),
+        ] {
+            if value > limit {
+                return Err(ClmError::Config(format!("{} must be at most {} ({})", name, limit_name, limit)));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn models_path(&self) -> PathBuf {
+        self.data_dir.join(&self.models)
+    }
+
+    pub fn database_path(&self) -> PathBuf {
+        self.data_dir.join(&self.database)
+    }
+}
+
+static CONFIG: OnceLock<ServerConfig> = OnceLock::new();
+
+// Set once at startup, before anything reads it
+pub fn set_config(config: ServerConfig) {
+    CONFIG.set(config).expect("The server configuration is only set once");
+}
+
+// The defaults until the server set its configuration, which is what tests get
+pub fn config() -> &'static ServerConfig {
+    CONFIG.get_or_init(ServerConfig::default)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use crate::config::{Cli, ServerConfig};
+
+    #[test]
+    fn flags_override_the_file() {
+        let path = std::env::temp_dir().join(format!("chatclm-config-{:016x}.toml", rand::random::<u64>()));
+        std::fs::write(&path, r#"
+            address = "127.0.0.1:3000"
+            models = "registry.toml"
+
+            [threads]
+            compute = 4
+
+            [limits]
+            max_tokens = 128
+            max_depth = 2
+
+            [generation]
+            depth = 2
+            width = 4
+        "#).unwrap();
+
+        let cli = Cli {
+            config: Some(path.clone()),
+            address: Some("0.0.0.0:8080".parse().unwrap()),
+            compute_threads: Some(2),
+            max_tokens: Some(64),
+            depth: Some(1),
+            ..Cli::default()
+        };
+        let config = ServerConfig::from_cli(&cli).unwrap();
+        // an override is checked like the file, before the server starts
+        let zero_width = ServerConfig::from_cli(&Cli { config: Some(path.clone()), width: Some(0), ..Cli::default() });
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(config.address, Some("0.0.0.0:8080".parse().unwrap()));
+        assert_eq!(config.models_path(), PathBuf::from("./registry.toml"));
+        assert_eq!(config.threads.compute, 2);
+        assert_eq!(config.limits.max_tokens, 64);
+        assert_eq!(config.limits.max_depth, 2);
+        assert_eq!(config.limits.max_choices, 8);
+        assert_eq!(config.generation.depth, 1);
+        assert_eq!(config.generation.width, 4);
+        assert!(zero_width.is_err());
+    }
+
+    #[test]
+    fn invalid_configurations_are_rejected() {
+        assert!(ServerConfig::from_toml("adress = \"127.0.0.1:3000\"").is_err());
+        assert!(ServerConfig::from_toml("address = \"localhost\"").is_err());
+        assert!(ServerConfig::from_toml("[limits]\nmax_tokens = 0").unwrap().validate().is_err());
+        assert!(ServerConfig::from_toml("[generation]\ndepth = 4").unwrap().validate().is_err());
+        assert!(ServerConfig::from_toml("[limits]\nmax_width = 2\n[generation]\nwidth = 3").unwrap().validate().is_err());
+        assert!(ServerConfig::from_toml("[generation]\ndepth = 0").unwrap().validate().is_ok());
+
+        let missing = Cli { config: Some(PathBuf::from("missing/chatclm.toml")), ..Cli::default() };
+        assert!(ServerConfig::from_cli(&missing).is_err());
+        let no_data = Cli { data_dir: Some(PathBuf::from("missing")), ..Cli::default() };
+        assert!(ServerConfig::from_cli(&no_data).is_err());
+    }
+}
@@ -1,13 +1,30 @@
-#![feature(lazy_cell)]
-
+#[cfg(feature = "ssr")]
+pub mod api;
 pub mod app;
 pub mod chat;
+pub mod compare;
 pub mod component;
+#[cfg(feature = "ssr")]
+pub mod config;
 pub mod error_template;
 #[cfg(feature = "ssr")]
 pub mod fileserv;
 pub mod model;
 #[cfg(feature = "ssr")]
+pub mod metrics;
+#[cfg(feature = "ssr")]
+pub mod prompt;
+#[cfg(feature = "ssr")]
+pub mod rate_limit;
+#[cfg(feature = "ssr")]
+pub mod registry;
+#[cfg(feature = "ssr")]
+pub mod request_log;
+#[cfg(feature = "ssr")]
+pub mod scheduler;
+#[cfg(feature = "ssr")]
+pub mod storage;
+#[cfg(feature = "ssr")]
 pub mod backend;
 
 
@@ -5,8 +5,7 @@ use chatclm::fileserv::file_and_error_handler;
 
 
 #[cfg(feature = "ssr")]
-#[tokio::main]
-async fn leptos_main() {
+async fn leptos_main(address: Option<std::net::SocketAddr>) {
     use axum::Router;
     use leptos::*;
     use leptos_axum::{generate_route_list, LeptosRoutes};
@@ -17,25 +16,72 @@ async fn leptos_main() {
     // Alternately a file can be specified such as Some("Cargo.toml")
     // The file would need to be included with the executable when moved to deployment
     let conf = get_configuration(None).await.unwrap();
-    let leptos_options = conf.leptos_options;
+    let mut leptos_options = conf.leptos_options;
+    if let Some(address) = address {
+        leptos_options.site_addr = address;
+    }
     let addr = leptos_options.site_addr;
     let routes = generate_route_list(App);
 
+    // /readyz reports ready once every model loaded, checkpoints retrained while the server
+    // runs are swapped in
+    chatclm::registry::load_models();
+    chatclm::registry::watch_checkpoints(std::time::Duration::from_secs(5));
+
     // build our application with a route
     let app = Router::new()
         .leptos_routes(&leptos_options, routes, App)
+        .merge(chatclm::api::router())
         .fallback(file_and_error_handler)
+        .layer(axum::middleware::from_fn(chatclm::api::assign_owner))
         .with_state(leptos_options);
 
     let listener = tokio::net::TcpListener::bind(&addr).await.unwrap();
     logging::log!("listening on http://{}", &addr);
-    axum::serve(listener, app.into_make_service())
+    axum::serve(listener, app.into_make_service_with_connect_info::<std::net::SocketAddr>())
         .await
         .unwrap();
 }
+
 #[cfg(feature = "ssr")]
 fn main() {
-    leptos_main();
+    use chatclm::config::{set_config, Cli, ServerConfig};
+    use clap::Parser;
+    use tracing_subscriber::EnvFilter;
+
+    // RUST_LOG picks what is traced, e.g. `chatclm=debug` for every generation
+    tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")))
+        .init();
+
+    let config = ServerConfig::from_cli(&Cli::parse()).unwrap_or_else(|error| exit_with(error));
+    set_config(config.clone());
+
+    // a broken registry or database should stop the server now, not fail every request later
+    if let Err(error) = chatclm::registry::registry() {
+        exit_with(error);
+    }
+    if let Err(error) = chatclm::rate_limit::rate_limiter() {
+        exit_with(error);
+    }
+    if let Err(error) = chatclm::storage::store() {
+        exit_with(format!("{}: {}", config.database_path().display(), error));
+    }
+
+    if config.threads.compute > 0 {
+        rayon::ThreadPoolBuilder::new().num_threads(config.threads.compute).build_global().unwrap();
+    }
+    let mut runtime = tokio::runtime::Builder::new_multi_thread();
+    if config.threads.http > 0 {
+        runtime.worker_threads(config.threads.http);
+    }
+    runtime.enable_all().build().unwrap().block_on(leptos_main(config.address));
+}
+
+#[cfg(feature = "ssr")]
+fn exit_with(error: impl std::fmt::Display) -> ! {
+    eprintln!("chatclm: {}", error);
+    std::process::exit(2);
 }
 
 #[cfg(not(feature = "ssr"))]
@@ -0,0 +1,206 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::backend::error::ClmError;
+use crate::request_log::TimedGeneration;
+
+// Prometheus metrics of the server, rendered in the text exposition format on `/metrics`.
+// A series is keyed by its label values, in the order of the label names of its metric.
+
+pub static REQUESTS: Values = Values::new(
+    "chatclm_requests_total", "counter", "Generation requests by model, source and outcome", &["model", "source", "outcome"]);
+pub static GENERATED_TOKENS: Values = Values::new(
+    "chatclm_generated_tokens_total", "counter", "Tokens generated by model", &["model"]);
+pub static GENERATION_SECONDS: Histograms = Histograms::new(
+    "chatclm_generation_seconds", "Time to generate a whole answer", &["model"],
+    &[0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0]);
+pub static TOKEN_SECONDS: Histograms = Histograms::new(
+    "chatclm_token_seconds", "Time to generate a single token, the first one includes reading the prompt", &["model"],
+    &[0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0]);
+pub static CANDIDATE_COMPRESSIONS: Values = Values::new(
+    "chatclm_candidate_compressions_total", "counter", "Candidate tokens compressed to score them", &[]);
+pub static SCORING_JOBS: Values = Values::new(
+    "chatclm_rayon_scoring_jobs", "gauge", "Candidate scoring passes queued or running on the rayon pool", &[]);
+pub static RAYON_THREADS: Values = Values::new(
+    "chatclm_rayon_threads", "gauge", "Threads of the rayon pool", &[]);
+pub static RATE_LIMITED: Values = Values::new(
+    "chatclm_rate_limited_total", "counter", "Generation requests refused by the rate limits, by tier", &["tier"]);
+pub static MODEL_LOADS: Values = Values::new(
+    "chatclm_model_loads_total", "counter", "Checkpoint loads by model and outcome", &["model", "outcome"]);
+pub static MODEL_LOAD_SECONDS: Values = Values::new(
+    "chatclm_model_load_seconds", "gauge", "Time the last successful checkpoint load took", &["model"]);
+pub static CHECKPOINT_BYTES: Values = Values::new(
+    "chatclm_checkpoint_bytes", "gauge", "Size of the checkpoint that is serving", &["model"]);
+
+pub fn record_generation(source: &str, model: &str, generation: Result<&TimedGeneration, &ClmError>) {
+    let Ok(timed) = generation else {
+        REQUESTS.add(&[model, source, "error"], 1.0);
+        return;
+    };
+
+    REQUESTS.add(&[model, source, timed.generation.finish_reason.name()], 1.0);
+    GENERATED_TOKENS.add(&[model], timed.generation.tokens.len() as f64);
+    GENERATION_SECONDS.observe(&[model], Duration::from_secs_f64(timed.total_ms / 1000.0));
+    for latency in &timed.token_latencies_ms {
+        TOKEN_SECONDS.observe(&[model], Duration::from_secs_f64(latency / 1000.0));
+    }
+}
+
+pub fn render() -> String {
+    RAYON_THREADS.set(&[], rayon::current_num_threads() as f64);
+
+    let mut text = String::new();
+    for values in [&REQUESTS, &GENERATED_TOKENS, &CANDIDATE_COMPRESSIONS, &SCORING_JOBS, &RAYON_THREADS, &RATE_LIMITED, &MODEL_LOADS, &MODEL_LOAD_SECONDS, &CHECKPOINT_BYTES] {
+        values.render(&mut text);
+    }
+    for histograms in [&GENERATION_SECONDS, &TOKEN_SECONDS] {
+        histograms.render(&mut text);
+    }
+    text
+}
+
+// Counters and gauges
+pub struct Values {
+    name: &'static str,
+    kind: &'static str,
+    help: &'static str,
+    labels: &'static [&'static str],
+    series: Mutex<BTreeMap<Vec<String>, f64>>,
+}
+
+impl Values {
+    pub const fn new(name: &'static str, kind: &'static str, help: &'static str, labels: &'static [&'static str]) -> Self {
+        Values { name, kind, help, labels, series: Mutex::new(BTreeMap::new()) }
+    }
+
+    pub fn add(&self, labels: &[&str], value: f64) {
+        *self.series.lock().unwrap().entry(label_values(labels)).or_default() += value;
+    }
+
+    pub fn set(&self, labels: &[&str], value: f64) {
+        self.series.lock().unwrap().insert(label_values(labels), value);
+    }
+
+    fn render(&self, text: &mut String) {
+        write_header(text, self.name, self.kind, self.help);
+        for (values, value) in self.series.lock().unwrap().iter() {
+            writeln!(text, "{}{} {}", self.name, label_set(self.labels, values, None), value).unwrap();
+        }
+    }
+}
+
+pub struct Histograms {
+    name: &'static str,
+    help: &'static str,
+    labels: &'static [&'static str],
+    buckets: &'static [f64], /* upper bounds, ascending */
+    series: Mutex<BTreeMap<Vec<String>, Histogram>>,
+}
+
+#[derive(Default)]
+struct Histogram {
+    counts: Vec<u64>, /* per bucket, not cumulative */
+    sum: f64,
+    count: u64,
+}
+
+impl Histograms {
+    pub const fn new(name: &'static str, help: &'static str, labels: &'static [&'static str], buckets: &'static [f64]) -> Self {
+        Histograms { name, help, labels, buckets, series: Mutex::new(BTreeMap::new()) }
+    }
+
+    pub fn observe(&self, labels: &[&str], duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        let mut series = self.series.lock().unwrap();
+        let histogram = series.entry(label_values(labels)).or_default();
+        histogram.counts.resize(self.buckets.len(), 0);
+        if let Some(bucket) = self.buckets.iter().position(|bound| seconds <= *bound) {
+            histogram.counts[bucket] += 1;
+        }
+        histogram.sum += seconds;
+        histogram.count += 1;
+    }
+
+    fn render(&self, text: &mut String) {
+        write_header(text, self.name, "histogram", self.help);
+        for (values, histogram) in self.series.lock().unwrap().iter() {
+            let mut cumulative = 0;
+            for (bound, count) in self.buckets.iter().zip(&histogram.counts) {
+                cumulative += count;
+                writeln!(text, "{}_bucket{} {}", self.name, label_set(self.labels, values, Some(&bound.to_string())), cumulative).unwrap();
+            }
+            writeln!(text, "{}_bucket{} {}", self.name, label_set(self.labels, values, Some("+Inf")), histogram.count).unwrap();
+            writeln!(text, "{}_sum{} {}", self.name, label_set(self.labels, values, None), histogram.sum).unwrap();
+            writeln!(text, "{}_count{} {}", self.name, label_set(self.labels, values, None), histogram.count).unwrap();
+        }
+    }
+}
+
+fn label_values(labels: &[&str]) -> Vec<String> {
+    labels.iter().map(|label| label.to_string()).collect()
+}
+
+fn write_header(text: &mut String, name: &str, kind: &str, help: &str) {
+    writeln!(text, "# HELP {} {}", name, help).unwrap();
+    writeln!(text, "# TYPE {} {}", name, kind).unwrap();
+}
+
+// `{model="chatclm",le="0.5"}`, nothing without labels
+fn label_set(names: &[&str], values: &[String], bucket: Option<&str>) -> String {
+    let mut pairs: Vec<String> = names.iter().zip(values)
+        .map(|(name, value)| format!("{}=\"{}\"", name, escape(value)))
+        .collect();
+    if let Some(bucket) = bucket {
+        pairs.push(format!("le=\"{}\"", bucket));
+    }
+    if pairs.is_empty() {
+        String::new()
+    } else {
+        format!("{{{}}}", pairs.join(","))
+    }
+}
+
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::metrics::{Histograms, Values};
+
+    #[test]
+    fn renders_the_text_format() {
+        let requests = Values::new("requests_total", "counter", "Requests", &["model", "outcome"]);
+        requests.add(&["chatclm", "stop"], 1.0);
+        requests.add(&["chatclm", "stop"], 2.0);
+        requests.add(&["say \"hi\"", "error"], 1.0);
+        let mut text = String::new();
+        requests.render(&mut text);
+        assert_eq!(text, concat!(
+            "# HELP requests_total Requests\n",
+            "# TYPE requests_total counter\n",
+            "requests_total{model=\"chatclm\",outcome=\"stop\"} 3\n",
+            "requests_total{model=\"say \\\"hi\\\"\",outcome=\"error\"} 1\n",
+        ));
+
+        let latency = Histograms::new("latency_seconds", "Latency", &[], &[0.1, 1.0]);
+        latency.observe(&[], Duration::from_millis(50));
+        latency.observe(&[], Duration::from_millis(500));
+        latency.observe(&[], Duration::from_secs(5));
+        let mut text = String::new();
+        latency.render(&mut text);
+        assert_eq!(text, concat!(
+            "# HELP latency_seconds Latency\n",
+            "# TYPE latency_seconds histogram\n",
+            "latency_seconds_bucket{le=\"0.1\"} 1\n",
+            "latency_seconds_bucket{le=\"1\"} 2\n",
+            "latency_seconds_bucket{le=\"+Inf\"} 3\n",
+            "latency_seconds_sum 5.55\n",
+            "latency_seconds_count 3\n",
+        ));
+    }
+}
@@ -1,101 +1,201 @@
-use std::sync::LazyLock;
-use leptos::{server, ServerFnError};
 #[cfg(feature = "ssr")]
-use crate::backend::clm_model::ClmModel;
-#[derive(Copy, Clone)]
-pub enum FrontendModel {
-    ChatCLM1_0,
-    ChatGPT3_5,
-    ChatGPT4o,
-    ChatRandom,
+use crate::backend::generation::{CancelToken, FinishReason, GeneratedToken, GenerationConfig};
+use crate::chat::{ChatHistory, TokenInfo};
+#[cfg(feature = "ssr")]
+use crate::backend::sampling::SamplingOptions;
+#[cfg(feature = "ssr")]
+use crate::chat::TokenAlternative;
+#[cfg(feature = "ssr")]
+use crate::config::Limits;
+#[cfg(feature = "ssr")]
+use crate::prompt::{ContextBudget, PromptTemplate};
+#[cfg(feature = "ssr")]
+use crate::registry::{registry, RegisteredModel};
+#[cfg(feature = "ssr")]
+use crate::request_log::generate_logged;
+use leptos::{server, ServerFnError};
+use serde::{Deserialize, Serialize};
+
+// A model of the server registry as the chat knows it
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct ModelInfo {
+    pub id: String,
+    pub name: String,
 }
 
-impl FrontendModel {
-    pub fn name(&self) -> &'static str {
-        match self {
-            FrontendModel::ChatCLM1_0 => "ChatCLM 0.1-pre-alpha",
-            FrontendModel::ChatGPT3_5 => "ChatGPT 3.5",
-            FrontendModel::ChatGPT4o => "ChatGPT 4o",
-            FrontendModel::ChatRandom => "ChatRandom",
-        }
-    }
+// The default model comes first
+#[server(ListModels, "/api")]
+pub async fn list_models() -> Result<Vec<ModelInfo>, ServerFnError> {
+    Ok(registry()?.models().iter().map(RegisteredModel::info).collect())
+}
 
-    pub fn from_index(index: usize) -> Self {
-        match index {
-            0 => FrontendModel::ChatCLM1_0,
-            1 => FrontendModel::ChatGPT3_5,
-            2 => FrontendModel::ChatGPT4o,
-            3 => FrontendModel::ChatRandom,
-            _ => panic!("Invalid model index"),
-        }
-    }
+// Stop sequences a conversation can add to the ones ending a chat turn
+pub const MAX_STOP_SEQUENCES: usize = 4;
+// Beyond this every candidate is about as likely as any other
+pub const MAX_TEMPERATURE: f64 = 2.0;
+
+// How the chat wants the answers of a conversation generated, unset values keep the defaults of
+// the model. They are stored with the conversation and sent with every answer.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(default)]
+pub struct GenerationSettings {
+    pub temperature: Option<f64>,
+    pub top_k: Option<usize>,
+    pub max_tokens: Option<usize>,
+    pub depth: Option<usize>, /* beam search lookahead, only used with temperature 0 */
+    pub width: Option<usize>,
+    pub seed: Option<u64>,
+    pub stop: Vec<String>,
+}
 
+#[cfg(feature = "ssr")]
+impl GenerationSettings {
+    // `defaults` with these settings applied, clamped to what the server allows
+    pub fn apply(&self, defaults: GenerationConfig, limits: &Limits) -> GenerationConfig {
+        let mut stop_strings = defaults.stop_strings;
+        stop_strings.extend(self.stop.iter().filter(|stop| !stop.is_empty()).take(MAX_STOP_SEQUENCES).cloned());
+        let temperature = self.temperature
+            .filter(|temperature| temperature.is_finite())
+            .unwrap_or(defaults.sampling.temperature)
+            .clamp(0.0, MAX_TEMPERATURE);
 
-    #[cfg(feature = "ssr")]
-    pub async fn predict_next_token(model_idx: usize, prompt: String) -> Option<String> {
-        match Self::from_index(model_idx) {
-            FrontendModel::ChatCLM1_0 => chat_clm_next_token(prompt).await,
-            FrontendModel::ChatGPT4o => gpt4o_next_token(prompt).await,
-            FrontendModel::ChatRandom => random_next_token(prompt).await,
-            _ => random_next_token(prompt).await,
+        GenerationConfig {
+            max_new_tokens: self.max_tokens.unwrap_or(defaults.max_new_tokens).clamp(1, limits.max_tokens),
+            stop_strings,
+            sampling: SamplingOptions {
+                temperature,
+                top_k: self.top_k.or(defaults.sampling.top_k).map(|top_k| top_k.max(1)),
+                seed: self.seed.or(defaults.sampling.seed),
+                ..defaults.sampling
+            },
+            depth: self.depth.unwrap_or(defaults.depth).min(limits.max_depth),
+            width: self.width.unwrap_or(defaults.width).clamp(1, limits.max_width),
+            ..defaults
         }
     }
 }
 
-pub async fn random_next_token(prompt: String) -> Option<String> {
-    // sleep 200 ms
-    let random_number = rand::random::<u8>() % 7 + 1;
-    if random_number == 1 {
-        None
-    } else {
-        Some(format!("{} next", prompt))
+// Every answer is scored against the whole prompt, so the budget also bounds the generation time
+#[cfg(feature = "ssr")]
+pub fn chat_prompt_template() -> PromptTemplate {
+    PromptTemplate {
+        system: Some("A conversation between a User and ChatCLM, a helpful assistant.".to_string()),
+        budget: ContextBudget::Tokens(crate::config::config().limits.context_tokens),
     }
 }
 
+// Candidates the chat shows for every token of an answer when it is inspected
 #[cfg(feature = "ssr")]
-static CLM: LazyLock<ClmModel> = LazyLock::new(|| ClmModel::from_checkpoint("clm_model.bin"));
+const INSPECTED_ALTERNATIVES: usize = 5;
 
+// Generates the whole next answer in `history`, handing every token to `on_token` as soon as it exists
 #[cfg(feature = "ssr")]
-pub async fn chat_clm_next_token(prompt: String ) -> Option<String> {
+pub fn generate_response(model_id: &str, history: &ChatHistory, settings: &GenerationSettings, cancel: &CancelToken, mut on_token: impl FnMut(TokenInfo)) -> Result<FinishReason, ServerFnError> {
+    let model = find_model(model_id)?;
+    // a model that failed to load fails the request instead of the server
+    let language_model = model.load()?;
+    let prompt = language_model.render_prompt(&chat_prompt_template(), history)?;
+    let defaults = GenerationConfig {
+        stop_strings: PromptTemplate::stop_strings(),
+        alternatives: INSPECTED_ALTERNATIVES,
+        cancel: cancel.clone(),
+        ..model.generation.config()
+    };
+    let config = settings.apply(defaults, &crate::config::config().limits);
+    let generation = generate_logged("chat", model.id(), &language_model, &prompt, &config, |token| on_token(token_info(token)))?;
+    Ok(generation.finish_reason)
+}
 
-    if prompt.len() > 250 {
-        return None;
+#[cfg(feature = "ssr")]
+fn token_info(token: &GeneratedToken) -> TokenInfo {
+    TokenInfo {
+        text: token.text.clone(),
+        bytes_added: token.bytes_added,
+        alternatives: token.alternatives.iter()
+            .map(|alternative| TokenAlternative { text: alternative.text.clone(), bytes_added: alternative.bytes_added })
+            .collect(),
     }
+}
 
-    Some(CLM.predict_next(prompt, 1, 3))
-
+// What the server sends over the stream of a generation, one event per message
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum GenerationEvent {
+    Queued { position: usize }, /* waiting for a free worker, 1 means next in line */
+    Token(TokenInfo), /* the text of the token and how it was scored */
+    Done { finish_reason: String },
+    Error { message: String },
 }
 
-pub async fn gpt4o_next_token(_prompt: String) -> Option<String> {
-    /*let client = Client::new();
+pub fn generation_events_url(id: &str) -> String {
+    format!("/api/chat/generations/{}/events", id)
+}
 
-    let request = CreateCompletionRequestArgs::default()
-        .model("gpt-3.5-turbo-instruct")
-        .prompt(prompt)
-        .max_tokens(40_u16)
-        .build().unwrap();
+// Registers the next answer in `history`, which is then streamed from `generation_events_url`.
+// An empty `model_id` picks the default model.
+#[server(StartGeneration, "/api")]
+pub async fn start_generation(model_id: String, history: ChatHistory, #[server(default)] settings: GenerationSettings) -> Result<String, ServerFnError> {
+    let model = find_model(&model_id)?;
+    let crate::api::ClientId(client) = leptos_axum::extract().await?;
+    crate::api::chat::register_generation(&client, model, history, settings)
+}
 
-    let result = client.completions().create(request).await;
-    if let Ok(response) = result {
-        return Some(response.choices.clone()[0].clone().text);
-    }*/
-    None
+#[cfg(feature = "ssr")]
+pub(crate) fn find_model(model_id: &str) -> Result<&'static RegisteredModel, ServerFnError> {
+    registry()?
+        .find(Some(model_id).filter(|id| !id.is_empty()))
+        .ok_or_else(|| ServerFnError::new(format!("The model `{}` does not exist", model_id)))
 }
 
-#[server(GetNextToken, "/api")]
-pub async fn get_next_token(
-    model_idx: usize,
-    prompt: String,
-) -> Result<Option<String>, ServerFnError> {
-    Ok(FrontendModel::predict_next_token(model_idx, prompt).await)
+// Stops the generation and the scoring it is doing right now, the chat keeps what was streamed so far
+#[server(CancelGeneration, "/api")]
+pub async fn cancel_generation(id: String) -> Result<(), ServerFnError> {
+    crate::api::chat::cancel_generation(&id);
+    Ok(())
 }
 
-pub fn cut_prompt(prompt: &String, response: &String) -> String {
-    let prompt = prompt.trim();
-    let response = response.trim();
-    if response.starts_with(prompt) {
-        response[prompt.len()..].trim().to_string()
-    } else {
-        response.to_string()
+#[cfg(test)]
+mod tests {
+    use crate::backend::generation::GenerationConfig;
+    use crate::config::Limits;
+    use crate::model::{GenerationSettings, MAX_STOP_SEQUENCES, MAX_TEMPERATURE};
+
+    #[test]
+    fn unset_settings_keep_the_model_defaults() {
+        let defaults = GenerationConfig { depth: 1, width: 3, stop_strings: vec!["User:".to_string()], ..GenerationConfig::new() };
+
+        let config = GenerationSettings::default().apply(defaults.clone(), &Limits::default());
+
+        assert_eq!(config.max_new_tokens, defaults.max_new_tokens);
+        assert_eq!((config.depth, config.width), (1, 3));
+        assert_eq!(config.sampling, defaults.sampling);
+        assert_eq!(config.stop_strings, defaults.stop_strings);
+    }
+
+    #[test]
+    fn settings_are_clamped_to_the_limits() {
+        let limits = Limits { max_tokens: 100, max_depth: 2, max_width: 4, ..Limits::default() };
+        let settings = GenerationSettings {
+            temperature: Some(9.0),
+            top_k: Some(0),
+            max_tokens: Some(1000),
+            depth: Some(5),
+            width: Some(0),
+            seed: Some(7),
+            stop: vec!["".to_string(), "a".to_string(), "b".to_string(), "c".to_string(), "d".to_string(), "e".to_string()],
+        };
+
+        let config = settings.apply(GenerationConfig { stop_strings: vec!["User:".to_string()], ..GenerationConfig::new() }, &limits);
+
+        assert_eq!(config.max_new_tokens, 100);
+        assert_eq!((config.depth, config.width), (2, 1));
+        assert_eq!(config.sampling.temperature, MAX_TEMPERATURE);
+        assert_eq!(config.sampling.top_k, Some(1));
+        assert_eq!(config.sampling.seed, Some(7));
+        assert_eq!(config.stop_strings.len(), 1 + MAX_STOP_SEQUENCES);
+        assert_eq!(config.stop_strings[0], "User:");
+
+        let settings = GenerationSettings { temperature: Some(f64::NAN), ..GenerationSettings::default() };
+        assert_eq!(settings.apply(GenerationConfig::new(), &limits).sampling.temperature, 0.0);
     }
 }
@@ -0,0 +1,150 @@
+use crate::backend::clm_model::ClmModel;
+use crate::backend::error::ClmResult;
+use crate::chat::{ChatHistory, Message, Sender};
+
+// How much of the conversation the model gets to see, the oldest turns are dropped first
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ContextBudget {
+    Unlimited,
+    Tokens(usize),
+    CompressedBytes(usize), /* size of the prompt compressed with the model dictionary */
+}
+
+impl ContextBudget {
+    pub fn measure(&self, model: &ClmModel, text: &str) -> ClmResult<usize> {
+        match self {
+            ContextBudget::Unlimited => Ok(0),
+            ContextBudget::Tokens(_) => Ok(model.tokenizer.encode(text)?.len()),
+            ContextBudget::CompressedBytes(_) => Ok(model.compress(&model.tokenizer.encode(text)?)?.len()),
+        }
+    }
+
+    fn fits(&self, size: usize) -> bool {
+        match *self {
+            ContextBudget::Unlimited => true,
+            ContextBudget::Tokens(limit) | ContextBudget::CompressedBytes(limit) => size <= limit,
+        }
+    }
+}
+
+// Turns a chat history into the text the model continues as ChatCLM
+#[derive(Clone, Debug)]
+pub struct PromptTemplate {
+    pub system: Option<String>, /* preamble in front of the conversation, never truncated */
+    pub budget: ContextBudget,
+}
+
+impl PromptTemplate {
+    pub fn new() -> Self {
+        PromptTemplate {
+            system: None,
+            budget: ContextBudget::Unlimited,
+        }
+    }
+
+    pub fn role_marker(sender: &Sender) -> &'static str {
+        match sender {
+            Sender::User => "User:",
+            Sender::ChatCLM => "ChatCLM:",
+        }
+    }
+
+    // Text that means the model started to write the next user turn
+    pub fn stop_strings() -> Vec<String> {
+        vec![format!("\n{}", Self::role_marker(&Sender::User))]
+    }
+
+    pub fn render(&self, history: &ChatHistory, model: &ClmModel) -> ClmResult<String> {
+        self.render_with(history, |text| self.budget.measure(model, text))
+    }
+
+    pub fn render_with(&self, history: &ChatHistory, measure: impl FnMut(&str) -> ClmResult<usize>) -> ClmResult<String> {
+        Ok(self.assemble(&self.fitting_turns(history, measure)?))
+    }
+
+    // The part of `history` that `render_with` would show the model
+    pub fn truncate_with(&self, history: &ChatHistory, measure: impl FnMut(&str) -> ClmResult<usize>) -> ClmResult<ChatHistory> {
+        let messages = self.fitting_turns(history, measure)?.into_iter().cloned().collect();
+        Ok(ChatHistory { messages })
+    }
+
+    // Keeps as many of the latest turns as fit the budget, but always the last one
+    fn fitting_turns<'h>(&self, history: &'h ChatHistory, mut measure: impl FnMut(&str) -> ClmResult<usize>) -> ClmResult<Vec<&'h Message>> {
+        let turns: Vec<&Message> = history.messages.iter()
+            .filter(|message| !message.message.trim().is_empty())
+            .collect();
+
+        let mut start = 0;
+        while start + 1 < turns.len() && !self.budget.fits(measure(&self.assemble(&turns[start..]))?) {
+            start += 1;
+        }
+
+        Ok(turns[start..].to_vec())
+    }
+
+    fn assemble(&self, turns: &[&Message]) -> String {
+        let mut prompt = String::new();
+        if let Some(system) = &self.system {
+            prompt.push_str(system.trim());
+            prompt.push('\n');
+        }
+        for turn in turns {
+            prompt.push_str(Self::role_marker(&turn.sender));
+            prompt.push(' ');
+            prompt.push_str(turn.message.trim());
+            prompt.push('\n');
+        }
+        prompt.push_str(Self::role_marker(&Sender::ChatCLM));
+        prompt
+    }
+}
+
+impl Default for PromptTemplate {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::chat::ChatHistory;
+    use crate::prompt::{ContextBudget, PromptTemplate};
+
+    fn conversation() -> ChatHistory {
+        let mut history = ChatHistory::default();
+        history.new_user_message("Hi".to_string());
+        history.new_server_message("Hello there. ".to_string());
+        history.new_user_message("How are you?".to_string());
+        history
+    }
+
+    #[test]
+    fn renders_roles_and_preamble() {
+        let template = PromptTemplate {
+            system: Some("A friendly chat.".to_string()),
+            ..PromptTemplate::new()
+        };
+        let prompt = template.render_with(&conversation(), |text| Ok(text.len())).unwrap();
+        assert_eq!(prompt, "A friendly chat.\nUser: Hi\nChatCLM: Hello there.\nUser: How are you?\nChatCLM:");
+    }
+
+    #[test]
+    fn drops_oldest_turns_first() {
+        let template = PromptTemplate {
+            system: Some("A friendly chat.".to_string()),
+            budget: ContextBudget::Tokens(50),
+        };
+        let prompt = template.render_with(&conversation(), |text| Ok(text.len())).unwrap();
+        assert_eq!(prompt, "A friendly chat.\nUser: How are you?\nChatCLM:");
+    }
+
+    #[test]
+    fn keeps_last_turn_over_budget() {
+        let template = PromptTemplate {
+            budget: ContextBudget::Tokens(1),
+            ..PromptTemplate::new()
+        };
+        let prompt = template.render_with(&conversation(), |text| Ok(text.len())).unwrap();
+        assert_eq!(prompt, "User: How are you?\nChatCLM:");
+    }
+}
@@ -0,0 +1,442 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{LazyLock, Mutex};
+use std::time::{Duration, Instant};
+
+use axum::http::{HeaderMap, HeaderValue};
+use serde::Deserialize;
+
+use crate::api::admin::constant_time_eq;
+use crate::backend::error::{ClmError, ClmResult};
+use crate::config::config;
+use crate::metrics;
+
+// Tiers callers are put in, keys name their own tier or get the default one. A tier that is not
+// configured has no limits.
+pub const WEB_TIER: &str = "web";
+pub const ANONYMOUS_TIER: &str = "anonymous";
+pub const DEFAULT_TIER: &str = "default";
+
+// Every client address gets buckets, the ones that filled up again are dropped once there are this
+// many, and the ones used longest ago as well if that leaves more than half
+const PRUNE_BUCKETS_AT: usize = 10_000;
+
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimitConfig {
+    pub enabled: bool,
+    pub require_api_key: bool, /* API requests without a key are refused, the web chat is not affected */
+    pub api_keys: PathBuf, /* relative to the data directory, optional unless keys are required */
+    pub tiers: HashMap<String, RateTier>,
+}
+
+// A missing limit means no limit
+#[derive(Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateTier {
+    pub requests_per_minute: Option<u32>,
+    pub tokens_per_minute: Option<u32>, /* generated tokens */
+    pub models: HashMap<String, ModelRate>, /* models with their own limits also get their own buckets */
+}
+
+#[derive(Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ModelRate {
+    pub requests_per_minute: Option<u32>,
+    pub tokens_per_minute: Option<u32>,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        let tier = |requests, tokens| RateTier { requests_per_minute: Some(requests), tokens_per_minute: Some(tokens), models: HashMap::new() };
+        RateLimitConfig {
+            enabled: true,
+            require_api_key: false,
+            api_keys: PathBuf::from("api_keys.toml"),
+            tiers: HashMap::from([
+                (WEB_TIER.to_string(), tier(30, 2000)),
+                (ANONYMOUS_TIER.to_string(), tier(10, 1000)),
+                (DEFAULT_TIER.to_string(), tier(60, 10000)),
+            ]),
+        }
+    }
+}
+
+impl RateTier {
+    // The limits of the tier for a model and the model whose buckets they use, "" for the tier's own
+    fn limits(&self, model: &str) -> (Option<u32>, Option<u32>, &str) {
+        match self.models.get_key_value(model) {
+            Some((model, rate)) => (rate.requests_per_minute, rate.tokens_per_minute, model),
+            None => (self.requests_per_minute, self.tokens_per_minute, ""),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct ApiKeysFile {
+    #[serde(default)]
+    keys: Vec<ApiKey>,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct ApiKey {
+    pub name: String, /* shows up in logs instead of the key */
+    key: String,
+    pub tier: Option<String>,
+}
+
+// Who the limits apply to: an API key or the address of an anonymous client
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Caller {
+    pub subject: String, /* "key:<name>" or "ip:<address>" */
+    pub tier: String,
+}
+
+impl Caller {
+    pub fn web(client: &str) -> Self {
+        Caller { subject: format!("ip:{}", client), tier: WEB_TIER.to_string() }
+    }
+
+    pub fn anonymous(client: &str) -> Self {
+        Caller { subject: format!("ip:{}", client), tier: ANONYMOUS_TIER.to_string() }
+    }
+
+    pub fn key(key: &ApiKey) -> Self {
+        Caller { subject: format!("key:{}", key.name), tier: key.tier.clone().unwrap_or_else(|| DEFAULT_TIER.to_string()) }
+    }
+}
+
+// Refills continuously up to a minute's worth. Generated tokens are only known afterwards, so
+// generations start while any tokens are left and may take the bucket below zero.
+#[derive(Clone, Debug)]
+struct TokenBucket {
+    per_minute: u32,
+    level: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    fn new(per_minute: u32, now: Instant) -> Self {
+        TokenBucket { per_minute, level: per_minute as f64, updated: now }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let seconds = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.level = (self.level + seconds * self.per_minute as f64 / 60.0).min(self.per_minute as f64);
+        self.updated = now;
+    }
+
+    fn full_at(&self, now: Instant) -> bool {
+        self.time_until(self.per_minute as f64) <= now.saturating_duration_since(self.updated)
+    }
+
+    // Time until the bucket holds `level`
+    fn time_until(&self, level: f64) -> Duration {
+        Duration::from_secs_f64(((level - self.level) * 60.0 / self.per_minute as f64).max(0.0))
+    }
+
+    fn status(&self) -> LimitStatus {
+        LimitStatus {
+            limit: self.per_minute,
+            remaining: self.level.max(0.0).floor() as u32,
+            reset: self.time_until(self.per_minute as f64),
+        }
+    }
+}
+
+#[derive(Default)]
+struct Buckets {
+    requests: Option<TokenBucket>,
+    tokens: Option<TokenBucket>,
+}
+
+impl Buckets {
+    fn updated(&self) -> Option<Instant> {
+        [&self.requests, &self.tokens].into_iter().flatten().map(|bucket| bucket.updated).max()
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LimitStatus {
+    pub limit: u32, /* per minute */
+    pub remaining: u32,
+    pub reset: Duration, /* until the bucket is full again */
+}
+
+// Where a caller stands after asking to generate, None for limits the tier does not have
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Allowance {
+    pub requests: Option<LimitStatus>,
+    pub tokens: Option<LimitStatus>,
+    pub retry_after: Option<Duration>, /* set if the request was refused */
+}
+
+impl Allowance {
+    // The rate limit headers OpenAI clients understand
+    pub fn headers(&self) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (kind, status) in [("requests", self.requests), ("tokens", self.tokens)] {
+            let Some(status) = status else {
+                continue;
+            };
+            for (name, value) in [
+                ("limit", status.limit.to_string()),
+                ("remaining", status.remaining.to_string()),
+                ("reset", format!("{}s", status.reset.as_secs_f64().ceil())),
+            ] {
+                let name = format!("x-ratelimit-{}-{}", name, kind);
+                headers.insert(axum::http::HeaderName::try_from(name).unwrap(), HeaderValue::from_str(&value).unwrap());
+            }
+        }
+        if let Some(retry_after) = self.retry_after {
+            headers.insert("retry-after", HeaderValue::from(retry_after.as_secs_f64().ceil() as u64));
+        }
+        headers
+    }
+
+    pub fn message(&self) -> String {
+        let seconds = self.retry_after.unwrap_or_default().as_secs_f64().ceil();
+        format!("Rate limit reached, try again in {} seconds", seconds)
+    }
+}
+
+pub struct RateLimiter {
+    config: RateLimitConfig,
+    keys: Vec<ApiKey>,
+    buckets: Mutex<HashMap<(Caller, String), Buckets>>, /* by caller and model, "" for the tier's own */
+}
+
+impl RateLimiter {
+    pub fn new(config: RateLimitConfig, keys: Vec<ApiKey>) -> ClmResult<Self> {
+        // a typo in the tier of a key should not lift its limits
+        for tier in keys.iter().filter_map(|key| key.tier.as_deref()) {
+            if !config.tiers.contains_key(tier) {
+                return Err(ClmError::Config(format!("the rate limit tier `{}` is not configured", tier)));
+            }
+        }
+        for (index, key) in keys.iter().enumerate() {
+            if key.key.is_empty() || keys[..index].iter().any(|other| other.name == key.name || other.key == key.key) {
+                return Err(ClmError::Config(format!("the API key `{}` is empty or given twice", key.name)));
+            }
+        }
+        Ok(RateLimiter { config, keys, buckets: Mutex::new(HashMap::new()) })
+    }
+
+    // A keys file that does not exist has no keys
+    pub fn load(config: RateLimitConfig, keys_path: &Path) -> ClmResult<Self> {
+        let keys = match std::fs::read_to_string(keys_path) {
+            Ok(text) => toml::from_str::<ApiKeysFile>(&text)
+                .map_err(|error| ClmError::Config(format!("{}: {}", keys_path.display(), error)))?
+                .keys,
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(error) => return Err(ClmError::Config(format!("{}: {}", keys_path.display(), error))),
+        };
+        if config.require_api_key && keys.is_empty() {
+            return Err(ClmError::Config(format!("API keys are required but {} has none", keys_path.display())));
+        }
+        Self::new(config, keys)
+    }
+
+    pub fn require_api_key(&self) -> bool {
+        self.config.require_api_key
+    }
+
+    // Compares against every key so the time taken does not tell which keys exist
+    pub fn find_key(&self, given: &str) -> Option<&ApiKey> {
+        self.keys.iter().fold(None, |found, key| {
+            if constant_time_eq(key.key.as_bytes(), given.as_bytes()) { Some(key) } else { found }
+        })
+    }
+
+    // Takes a request for each of the `generations` from the caller's bucket if it has one left and
+    // tokens to generate. Like tokens, a request for several generations may take the bucket below zero.
+    pub fn admit(&self, caller: &Caller, model: &str, generations: usize) -> Result<Allowance, Allowance> {
+        self.admit_at(caller, model, generations, Instant::now())
+    }
+
+    // Takes the tokens a generation produced from the caller's bucket
+    pub fn charge_tokens(&self, caller: &Caller, model: &str, tokens: usize) {
+        self.charge_tokens_at(caller, model, tokens, Instant::now())
+    }
+
+    fn admit_at(&self, caller: &Caller, model: &str, generations: usize, now: Instant) -> Result<Allowance, Allowance> {
+        let Some(tier) = self.tier(caller) else {
+            return Ok(Allowance::default());
+        };
+        let (requests_per_minute, tokens_per_minute, scope) = tier.limits(model);
+
+        let mut all_buckets = self.buckets.lock().unwrap();
+        if all_buckets.len() >= PRUNE_BUCKETS_AT {
+            prune(&mut all_buckets, now);
+        }
+        let buckets = all_buckets.entry((caller.clone(), scope.to_string())).or_default();
+        let requests = requests_per_minute.map(|per_minute| fresh(&mut buckets.requests, per_minute, now));
+        let tokens = tokens_per_minute.map(|per_minute| fresh(&mut buckets.tokens, per_minute, now));
+
+        let waits = [
+            requests.as_ref().filter(|bucket| bucket.level < 1.0).map(|bucket| bucket.time_until(1.0)),
+            // any token left lets a generation start, an empty bucket waits for the first one
+            tokens.as_ref().filter(|bucket| bucket.level <= 0.0).map(|bucket| bucket.time_until(1.0)),
+        ];
+        let retry_after = waits.into_iter().flatten().max();
+        if retry_after.is_none() {
+            if let Some(bucket) = buckets.requests.as_mut() {
+                bucket.level -= generations as f64;
+            }
+        }
+
+        let allowance = Allowance {
+            requests: buckets.requests.as_ref().map(TokenBucket::status),
+            tokens: buckets.tokens.as_ref().map(TokenBucket::status),
+            retry_after,
+        };
+        match retry_after {
+            None => Ok(allowance),
+            Some(_) => {
+                metrics::RATE_LIMITED.add(&[&caller.tier], 1.0);
+                Err(allowance)
+            }
+        }
+    }
+
+    fn charge_tokens_at(&self, caller: &Caller, model: &str, tokens: usize, now: Instant) {
+        let Some(tier) = self.tier(caller) else {
+            return;
+        };
+        let (_, _, scope) = tier.limits(model);
+        let mut all_buckets = self.buckets.lock().unwrap();
+        if let Some(bucket) = all_buckets.get_mut(&(caller.clone(), scope.to_string())).and_then(|buckets| buckets.tokens.as_mut()) {
+            bucket.refill(now);
+            bucket.level -= tokens as f64;
+        }
+    }
+
+    fn tier(&self, caller: &Caller) -> Option<&RateTier> {
+        if !self.config.enabled {
+            return None;
+        }
+        self.config.tiers.get(&caller.tier)
+    }
+}
+
+// Clients cycling through addresses keep their buckets draining, so only dropping the full ones
+// would let them grow the map without bound
+fn prune(all_buckets: &mut HashMap<(Caller, String), Buckets>, now: Instant) {
+    all_buckets.retain(|_, buckets| [&buckets.requests, &buckets.tokens].into_iter().flatten().any(|bucket| !bucket.full_at(now)));
+    if all_buckets.len() > PRUNE_BUCKETS_AT / 2 {
+        let mut by_age: Vec<_> = all_buckets.iter().map(|(key, buckets)| (buckets.updated(), key.clone())).collect();
+        by_age.sort_unstable_by_key(|(updated, _)| *updated);
+        for (_, key) in &by_age[..by_age.len() - PRUNE_BUCKETS_AT / 2] {
+            all_buckets.remove(key);
+        }
+    }
+}
+
+// Refills the bucket, or starts a full one if there was none or its limit changed
+fn fresh(bucket: &mut Option<TokenBucket>, per_minute: u32, now: Instant) -> &TokenBucket {
+    match bucket {
+        Some(existing) if existing.per_minute == per_minute => existing.refill(now),
+        _ => *bucket = Some(TokenBucket::new(per_minute, now)),
+    }
+    bucket.as_ref().unwrap()
+}
+
+static RATE_LIMITER: LazyLock<ClmResult<RateLimiter>> = LazyLock::new(|| {
+    let settings = &config().rate_limits;
+    RateLimiter::load(settings.clone(), &config().data_dir.join(&settings.api_keys))
+});
+
+// The API keys and rate limits of the chat and the API
+pub fn rate_limiter() -> Result<&'static RateLimiter, &'static ClmError> {
+    RATE_LIMITER.as_ref()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::time::{Duration, Instant};
+
+    use crate::rate_limit::{ApiKey, Caller, ModelRate, RateLimitConfig, RateLimiter, RateTier, PRUNE_BUCKETS_AT};
+
+    fn limiter() -> RateLimiter {
+        let mut config = RateLimitConfig::default();
+        config.tiers.insert("web".to_string(), RateTier {
+            requests_per_minute: Some(2),
+            tokens_per_minute: Some(60),
+            models: HashMap::from([("ensemble".to_string(), ModelRate { requests_per_minute: Some(1), tokens_per_minute: None })]),
+        });
+        let keys = vec![ApiKey { name: "ci".to_string(), key: "secret".to_string(), tier: None }];
+        RateLimiter::new(config, keys).unwrap()
+    }
+
+    #[test]
+    fn buckets_empty_and_refill() {
+        let limiter = limiter();
+        let caller = Caller::web("10.0.0.1");
+        let start = Instant::now();
+
+        let first = limiter.admit_at(&caller, "chatclm", 1, start).unwrap();
+        assert_eq!(first.requests.unwrap().remaining, 1);
+        limiter.admit_at(&caller, "chatclm", 1, start).unwrap();
+        let refused = limiter.admit_at(&caller, "chatclm", 1, start).unwrap_err();
+        assert_eq!(refused.retry_after, Some(Duration::from_secs(30)));
+        assert_eq!(refused.headers()["retry-after"], "30");
+
+        // other callers and models with their own limits have their own buckets
+        assert!(limiter.admit_at(&Caller::web("10.0.0.2"), "chatclm", 1, start).is_ok());
+        assert!(limiter.admit_at(&caller, "ensemble", 1, start).is_ok());
+        assert!(limiter.admit_at(&caller, "ensemble", 1, start).is_err());
+
+        // spending more tokens than are left waits until there is one again
+        let later = start + Duration::from_secs(60);
+        limiter.charge_tokens_at(&caller, "chatclm", 90, later);
+        let refused = limiter.admit_at(&caller, "chatclm", 1, later).unwrap_err();
+        assert_eq!(refused.tokens.unwrap().remaining, 0);
+        assert_eq!(refused.retry_after, Some(Duration::from_secs(31)));
+        assert!(limiter.admit_at(&caller, "chatclm", 1, later + Duration::from_secs(31)).is_ok());
+    }
+
+    #[test]
+    fn every_generation_of_a_request_takes_a_request() {
+        let limiter = limiter();
+        let caller = Caller::web("10.0.0.1");
+        let start = Instant::now();
+
+        // admitted while one is left, the rest is owed
+        let admitted = limiter.admit_at(&caller, "chatclm", 4, start).unwrap();
+        assert_eq!(admitted.requests.unwrap().remaining, 0);
+        let refused = limiter.admit_at(&caller, "chatclm", 1, start).unwrap_err();
+        assert_eq!(refused.retry_after, Some(Duration::from_secs(90)));
+    }
+
+    #[test]
+    fn draining_buckets_of_many_addresses_are_pruned() {
+        let limiter = limiter();
+        let start = Instant::now();
+        for client in 0..PRUNE_BUCKETS_AT {
+            let now = start + Duration::from_millis(client as u64);
+            limiter.admit_at(&Caller::web(&client.to_string()), "chatclm", 1, now).unwrap();
+        }
+        let now = start + Duration::from_millis(PRUNE_BUCKETS_AT as u64);
+        limiter.admit_at(&Caller::web("10.0.0.1"), "chatclm", 2, now).unwrap();
+        assert_eq!(limiter.buckets.lock().unwrap().len(), PRUNE_BUCKETS_AT / 2 + 1);
+
+        // the latest clients keep what they spent
+        let latest = Caller::web(&(PRUNE_BUCKETS_AT - 1).to_string());
+        assert_eq!(limiter.admit_at(&latest, "chatclm", 1, now).unwrap().requests.unwrap().remaining, 0);
+        assert!(limiter.admit_at(&Caller::web("10.0.0.1"), "chatclm", 1, now).is_err());
+    }
+
+    #[test]
+    fn keys_are_found_and_checked() {
+        let limiter = limiter();
+        let key = limiter.find_key("secret").unwrap();
+        assert_eq!(Caller::key(key), Caller { subject: "key:ci".to_string(), tier: "default".to_string() });
+        assert!(limiter.find_key("secrets").is_none());
+
+        let unknown_tier = vec![ApiKey { name: "ci".to_string(), key: "secret".to_string(), tier: Some("gold".to_string()) }];
+        assert!(RateLimiter::new(RateLimitConfig::default(), unknown_tier).is_err());
+    }
+}
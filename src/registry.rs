@@ -0,0 +1,707 @@
+use std::collections::HashSet;
+use std::panic::AssertUnwindSafe;
+use std::path::Path;
+use std::sync::{Arc, LazyLock, Mutex, PoisonError, RwLock};
+use std::time::{Duration, Instant, SystemTime};
+
+use leptos::logging;
+use serde::{Deserialize, Serialize};
+
+use crate::backend::bundle::CorpusStatistics;
+use crate::backend::clm_model::ClmModel;
+use crate::backend::ensemble_model::EnsembleModel;
+use crate::backend::error::{ClmError, ClmResult};
+use crate::backend::generation::{FinishReason, GeneratedToken, Generation, GenerationConfig};
+use crate::backend::sampling::SamplingOptions;
+use crate::backend::tokenizer::ClmTokenizer;
+use crate::backend::training_options::TrainingOptions;
+use crate::backend::Token;
+use crate::chat::ChatHistory;
+use crate::config::config;
+use crate::metrics;
+use crate::model::ModelInfo;
+use crate::prompt::PromptTemplate;
+
+// A model only serves once it generated a token for this
+const WARM_UP_PROMPT: &str = "Hello";
+
+// What the server offers without a registry file
+const DEFAULT_REGISTRY: &str = r#"
+[[models]]
+id = "chatclm"
+name = "ChatCLM 0.1-pre-alpha"
+kind = "clm"
+checkpoint = "clm_model.bin"
+
+[[models]]
+id = "random"
+name = "ChatRandom"
+kind = "random"
+"#;
+
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct RegistryConfig {
+    pub models: Vec<ModelConfig>,
+}
+
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+pub struct ModelConfig {
+    pub id: String, /* stable, conversations and API requests refer to the model by it */
+    pub name: String,
+    #[serde(flatten)]
+    pub source: ModelSource,
+    pub generation: Option<GenerationDefaults>, /* the server defaults if not set */
+}
+
+#[derive(Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum ModelSource {
+    Clm { checkpoint: String },
+    Ensemble { checkpoint: String },
+    Random, /* baseline that answers " next" until it rolls a stop */
+}
+
+// How a model answers unless a request says otherwise
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct GenerationDefaults {
+    pub max_new_tokens: usize,
+    pub stop_on_sentence_end: bool,
+    pub timeout_secs: Option<u64>,
+    pub depth: usize,
+    pub width: usize,
+    pub temperature: f64,
+    pub top_k: Option<usize>,
+    pub top_p: Option<f64>,
+    pub min_p: Option<f64>,
+}
+
+impl GenerationDefaults {
+    pub fn config(&self) -> GenerationConfig {
+        GenerationConfig {
+            max_new_tokens: self.max_new_tokens,
+            stop_on_sentence_end: self.stop_on_sentence_end,
+            timeout: self.timeout_secs.map(Duration::from_secs),
+            sampling: SamplingOptions {
+                temperature: self.temperature,
+                top_k: self.top_k,
+                top_p: self.top_p,
+                min_p: self.min_p,
+                ..SamplingOptions::new()
+            },
+            depth: self.depth,
+            width: self.width,
+            ..GenerationConfig::new()
+        }
+    }
+}
+
+impl Default for GenerationDefaults {
+    fn default() -> Self {
+        GenerationDefaults {
+            max_new_tokens: 64,
+            stop_on_sentence_end: true,
+            timeout_secs: Some(10),
+            depth: 1,
+            width: 3,
+            temperature: 0.0,
+            top_k: None,
+            top_p: None,
+            min_p: None,
+        }
+    }
+}
+
+pub enum LanguageModel {
+    Clm(Box<ClmModel<'static>>),
+    Ensemble(EnsembleModel<'static>),
+    Random,
+}
+
+impl LanguageModel {
+    pub fn load(source: &ModelSource) -> ClmResult<Self> {
+        match source {
+            ModelSource::Clm { checkpoint } => Ok(LanguageModel::Clm(Box::new(ClmModel::from_checkpoint(checkpoint)?))),
+            ModelSource::Ensemble { checkpoint } => Ok(LanguageModel::Ensemble(EnsembleModel::from_checkpoint(checkpoint)?)),
+            ModelSource::Random => Ok(LanguageModel::Random),
+        }
+    }
+
+    // Runs a short generation to make sure the model can answer at all
+    pub fn warm_up(&self) -> ClmResult<()> {
+        self.generate_with(WARM_UP_PROMPT, &GenerationConfig { max_new_tokens: 1, ..GenerationConfig::new() }, |_| {})?;
+        Ok(())
+    }
+
+    fn kind(&self) -> &'static str {
+        match self {
+            LanguageModel::Clm(_) => "clm",
+            LanguageModel::Ensemble(_) => "ensemble",
+            LanguageModel::Random => "random",
+        }
+    }
+
+    // Every member of an ensemble has a dictionary, they share the tokenizer and the metadata
+    fn members(&self) -> &[ClmModel<'static>] {
+        match self {
+            LanguageModel::Clm(model) => std::slice::from_ref(model.as_ref()),
+            LanguageModel::Ensemble(model) => model.models(),
+            LanguageModel::Random => &[],
+        }
+    }
+
+    // The random baseline has no tokenizer and counts words instead
+    pub fn prompt_tokens(&self, prompt: &str) -> ClmResult<usize> {
+        match self {
+            LanguageModel::Clm(model) => Ok(model.tokenizer.encode(prompt)?.len()),
+            LanguageModel::Ensemble(model) => Ok(model.tokenizer()?.encode(prompt)?.len()),
+            LanguageModel::Random => Ok(prompt.split_whitespace().count()),
+        }
+    }
+
+    pub fn render_prompt(&self, template: &PromptTemplate, history: &ChatHistory) -> ClmResult<String> {
+        template.render_with(history, |text| self.measure(template, text))
+    }
+
+    // The turns of `history` that `render_prompt` keeps
+    pub fn truncate_history(&self, template: &PromptTemplate, history: &ChatHistory) -> ClmResult<ChatHistory> {
+        template.truncate_with(history, |text| self.measure(template, text))
+    }
+
+    // Ensembles measure the context budget with their first member
+    fn measure(&self, template: &PromptTemplate, text: &str) -> ClmResult<usize> {
+        match self {
+            LanguageModel::Clm(model) => template.budget.measure(model, text),
+            LanguageModel::Ensemble(model) => match model.first_model() {
+                Some(model) => template.budget.measure(model, text),
+                None => Ok(text.split_whitespace().count()),
+            },
+            LanguageModel::Random => Ok(text.split_whitespace().count()),
+        }
+    }
+
+    pub fn generate_with(&self, prompt: &str, config: &GenerationConfig, mut on_token: impl FnMut(&GeneratedToken)) -> ClmResult<Generation> {
+        match self {
+            LanguageModel::Clm(model) => {
+                let tokens = model.tokenizer.encode(prompt)?;
+                model.generate_tokens_with(&tokens, config, on_token)
+            }
+            LanguageModel::Ensemble(model) => {
+                let tokens = model.tokenizer()?.encode(prompt)?;
+                model.generate_tokens_with(&tokens, config, on_token)
+            }
+            LanguageModel::Random => Ok(random_generation(config, &mut on_token)),
+        }
+    }
+
+    // Compressed bytes `text` adds when it follows `prompt`, with its number of tokens.
+    // The random baseline compresses nothing.
+    pub fn continuation_cost(&self, prompt: &str, text: &str) -> ClmResult<Option<(f64, usize)>> {
+        let cost = |tokenizer: &ClmTokenizer, compressed_size: &dyn Fn(&Vec<Token>) -> ClmResult<f64>| -> ClmResult<(f64, usize)> {
+            let mut tokens = tokenizer.encode(prompt)?;
+            let prompt_size = compressed_size(&tokens)?;
+            let continuation = tokenizer.encode(text)?;
+            tokens.extend(&continuation);
+            Ok((compressed_size(&tokens)? - prompt_size, continuation.len()))
+        };
+        match self {
+            LanguageModel::Clm(model) => cost(&model.tokenizer, &|tokens| Ok(model.compress(tokens)?.len() as f64)).map(Some),
+            LanguageModel::Ensemble(model) => cost(model.tokenizer()?, &|tokens| model.compressed_size(tokens)).map(Some),
+            LanguageModel::Random => Ok(None),
+        }
+    }
+}
+
+fn random_generation(config: &GenerationConfig, on_token: &mut impl FnMut(&GeneratedToken)) -> Generation {
+    let mut generation = Generation {
+        text: String::new(),
+        tokens: Vec::new(),
+        finish_reason: FinishReason::Length,
+    };
+
+    while generation.tokens.len() < config.max_new_tokens {
+        if config.cancel.is_cancelled() {
+            generation.finish_reason = FinishReason::Cancelled;
+            break;
+        }
+        let random_number = rand::random::<u8>() % 7 + 1;
+        if random_number == 1 {
+            generation.finish_reason = FinishReason::Stop;
+            break;
+        }
+
+        let token = GeneratedToken { token: 0, text: " next".to_string(), bytes_added: 0, alternatives: Vec::new() };
+        on_token(&token);
+        generation.text.push_str(&token.text);
+        generation.tokens.push(token);
+    }
+
+    generation
+}
+
+// The model of a registry entry together with the checkpoint it was loaded from
+struct ModelState {
+    model: Option<Arc<LanguageModel>>,
+    loaded: Option<SystemTime>, /* modification time of the checkpoint that is serving */
+    loaded_at: Option<(SystemTime, Duration)>, /* when it was swapped in and how long loading and warming up took */
+    rejected: Option<(Option<SystemTime>, Arc<ClmError>)>, /* the last checkpoint that failed to load */
+}
+
+pub struct RegisteredModel {
+    pub config: ModelConfig,
+    pub generation: GenerationDefaults,
+    state: RwLock<ModelState>,
+    loading: Mutex<()>, /* one load at a time, a checkpoint is big */
+}
+
+impl RegisteredModel {
+    pub fn new(config: ModelConfig, default_generation: &GenerationDefaults) -> Self {
+        RegisteredModel {
+            generation: config.generation.clone().unwrap_or_else(|| default_generation.clone()),
+            config,
+            state: RwLock::new(ModelState { model: None, loaded: None, loaded_at: None, rejected: None }),
+            loading: Mutex::new(()),
+        }
+    }
+
+    pub fn id(&self) -> &str {
+        &self.config.id
+    }
+
+    pub fn info(&self) -> ModelInfo {
+        ModelInfo { id: self.config.id.clone(), name: self.config.name.clone() }
+    }
+
+    pub fn checkpoint(&self) -> Option<&str> {
+        match &self.config.source {
+            ModelSource::Clm { checkpoint } | ModelSource::Ensemble { checkpoint } => Some(checkpoint),
+            ModelSource::Random => None,
+        }
+    }
+
+    pub fn is_serving(&self) -> bool {
+        self.state.read().unwrap().model.is_some()
+    }
+
+    // What is known about the model without loading it
+    pub fn details(&self) -> ModelDetails {
+        let state = self.state.read().unwrap();
+        let model = state.model.as_deref();
+        let members = model.map(LanguageModel::members).unwrap_or_default();
+        let metadata = members.first().map(|member| member.metadata().clone()).unwrap_or_default();
+        let timestamp = |time: SystemTime| chrono::DateTime::<chrono::Utc>::from(time).to_rfc3339_opts(chrono::SecondsFormat::Secs, true);
+
+        ModelDetails {
+            id: self.config.id.clone(),
+            name: self.config.name.clone(),
+            kind: model.map(LanguageModel::kind),
+            checkpoint: self.checkpoint().map(str::to_string),
+            serving: model.is_some(),
+            loaded_at: state.loaded_at.map(|(time, _)| timestamp(time)),
+            load_seconds: state.loaded_at.map(|(_, duration)| duration.as_secs_f64()),
+            checkpoint_modified: state.loaded.map(timestamp),
+            dictionaries: members.iter()
+                .map(|member| DictionaryInfo { size: member.get_dictionary_size(), id: member.dictionary_id() })
+                .collect(),
+            tokenizer_vocab_size: members.first().map(|member| member.tokenizer.vocab_size()),
+            compression_level: members.first().map(ClmModel::compression_level),
+            training_options: metadata.training_options,
+            corpus: metadata.corpus,
+            error: state.rejected.as_ref().map(|(_, error)| error.to_string()),
+        }
+    }
+
+    fn checkpoint_modified(&self) -> Option<SystemTime> {
+        std::fs::metadata(self.checkpoint()?).and_then(|metadata| metadata.modified()).ok()
+    }
+
+    // The model that is serving right now, loaded on first use. Requests keep the model they got
+    // even if it is reloaded meanwhile. A broken checkpoint fails the requests for it instead of
+    // the server, and is only tried again once it changes.
+    pub fn load(&self) -> Result<Arc<LanguageModel>, Arc<ClmError>> {
+        if let Some(model) = &self.state.read().unwrap().model {
+            return Ok(model.clone());
+        }
+
+        let _loading = self.loading.lock().unwrap_or_else(PoisonError::into_inner);
+        let state = self.state.read().unwrap();
+        if let Some(model) = &state.model {
+            return Ok(model.clone());
+        }
+        if let Some((modified, error)) = &state.rejected {
+            if *modified == self.checkpoint_modified() {
+                return Err(error.clone());
+            }
+        }
+        drop(state);
+        self.swap_in()
+    }
+
+    // Loads the checkpoint again and serves it from now on, unless it fails to load
+    pub fn reload(&self) -> Result<Arc<LanguageModel>, Arc<ClmError>> {
+        let _loading = self.loading.lock().unwrap_or_else(PoisonError::into_inner);
+        self.swap_in()
+    }
+
+    // Reloads a model that is serving once its checkpoint changed on disk, returns whether it tried
+    pub fn reload_if_changed(&self) -> Option<Result<Arc<LanguageModel>, Arc<ClmError>>> {
+        let state = self.state.read().unwrap();
+        let modified = self.checkpoint_modified();
+        let rejected = state.rejected.as_ref().map(|(rejected, _)| *rejected);
+        if state.model.is_none() || modified.is_none() || modified == state.loaded || Some(modified) == rejected {
+            return None;
+        }
+        drop(state);
+        Some(self.reload())
+    }
+
+    #[tracing::instrument(skip_all, fields(model = self.id()))]
+    fn swap_in(&self) -> Result<Arc<LanguageModel>, Arc<ClmError>> {
+        let modified = self.checkpoint_modified();
+        let start = Instant::now();
+        // a checkpoint that loads but cannot generate is as broken as one that does not load, and so
+        // is one that makes a library panic
+        let loaded = std::panic::catch_unwind(AssertUnwindSafe(|| {
+            LanguageModel::load(&self.config.source).and_then(|model| model.warm_up().map(|_| model))
+        }));
+        let loaded = loaded.unwrap_or_else(|panic| {
+            let message = panic.downcast_ref::<&str>().map(|message| message.to_string())
+                .or_else(|| panic.downcast_ref::<String>().cloned())
+                .unwrap_or_default();
+            Err(ClmError::Format(format!("loading the checkpoint panicked: {}", message)))
+        });
+        match loaded {
+            Ok(model) => {
+                metrics::MODEL_LOADS.add(&[self.id(), "loaded"], 1.0);
+                metrics::MODEL_LOAD_SECONDS.set(&[self.id()], start.elapsed().as_secs_f64());
+                if let Some(metadata) = self.checkpoint().and_then(|checkpoint| std::fs::metadata(checkpoint).ok()) {
+                    metrics::CHECKPOINT_BYTES.set(&[self.id()], metadata.len() as f64);
+                }
+                tracing::info!(seconds = start.elapsed().as_secs_f64(), "loaded model");
+
+                let model = Arc::new(model);
+                let mut state = self.state.write().unwrap();
+                state.model = Some(model.clone());
+                state.loaded = modified;
+                state.loaded_at = Some((SystemTime::now(), start.elapsed()));
+                state.rejected = None;
+                Ok(model)
+            }
+            Err(error) => {
+                metrics::MODEL_LOADS.add(&[self.id(), "failed"], 1.0);
+                tracing::warn!(%error, "failed to load model");
+
+                let error = Arc::new(error);
+                self.state.write().unwrap().rejected = Some((modified, error.clone()));
+                Err(error)
+            }
+        }
+    }
+}
+
+// What `/api/models/:id/info` reports, the fields of the checkpoint are empty until it serves
+#[derive(Serialize, Clone, Debug)]
+pub struct ModelDetails {
+    pub id: String,
+    pub name: String,
+    pub kind: Option<&'static str>,
+    pub checkpoint: Option<String>,
+    pub serving: bool,
+    pub loaded_at: Option<String>,
+    pub load_seconds: Option<f64>, /* loading and warming up */
+    pub checkpoint_modified: Option<String>,
+    pub dictionaries: Vec<DictionaryInfo>, /* one per ensemble member */
+    pub tokenizer_vocab_size: Option<usize>,
+    pub compression_level: Option<i32>,
+    pub training_options: Option<TrainingOptions>, /* None for checkpoints from before bundles */
+    pub corpus: Option<CorpusStatistics>,
+    pub error: Option<String>, /* why the last checkpoint was rejected */
+}
+
+#[derive(Serialize, Clone, Debug, PartialEq, Eq)]
+pub struct DictionaryInfo {
+    pub size: usize, /* bytes */
+    pub id: Option<u32>,
+}
+
+pub struct ModelRegistry {
+    models: Vec<RegisteredModel>,
+}
+
+impl ModelRegistry {
+    pub fn from_config(config: RegistryConfig, default_generation: &GenerationDefaults) -> ClmResult<Self> {
+        if config.models.is_empty() {
+            return Err(ClmError::Config("the model registry lists no models".to_string()));
+        }
+        let mut ids = HashSet::new();
+        if let Some(model) = config.models.iter().find(|model| !ids.insert(model.id.as_str())) {
+            return Err(ClmError::Config(format!("the model id `{}` is used twice", model.id)));
+        }
+
+        let models = config.models.into_iter().map(|model| RegisteredModel::new(model, default_generation)).collect();
+        Ok(ModelRegistry { models })
+    }
+
+    pub fn from_toml(text: &str, default_generation: &GenerationDefaults) -> ClmResult<Self> {
+        Self::from_config(parse_registry(text)?, default_generation)
+    }
+
+    // A missing file means the default registry, an invalid one is an error.
+    // Relative checkpoint paths start at the directory of the registry file.
+    pub fn load(path: &Path, default_generation: &GenerationDefaults) -> ClmResult<Self> {
+        let in_file = |error| match error {
+            ClmError::Config(message) => ClmError::Config(format!("{}: {}", path.display(), message)),
+            error => error,
+        };
+        let mut config = match std::fs::read_to_string(path) {
+            Ok(text) => parse_registry(&text).map_err(in_file)?,
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => parse_registry(DEFAULT_REGISTRY)?,
+            Err(error) => return Err(error.into()),
+        };
+
+        let directory = path.parent().unwrap_or(Path::new(""));
+        for model in &mut config.models {
+            if let ModelSource::Clm { checkpoint } | ModelSource::Ensemble { checkpoint } = &mut model.source {
+                *checkpoint = directory.join(&*checkpoint).to_string_lossy().into_owned();
+            }
+        }
+        Self::from_config(config, default_generation).map_err(in_file)
+    }
+
+    pub fn models(&self) -> &[RegisteredModel] {
+        &self.models
+    }
+
+    // Ready once every model serves
+    pub fn is_ready(&self) -> bool {
+        self.models.iter().all(RegisteredModel::is_serving)
+    }
+
+    // The first model is the default
+    pub fn find(&self, id: Option<&str>) -> Option<&RegisteredModel> {
+        match id {
+            Some(id) => self.models.iter().find(|model| model.id() == id),
+            None => self.models.first(),
+        }
+    }
+}
+
+fn parse_registry(text: &str) -> ClmResult<RegistryConfig> {
+    toml::from_str(text).map_err(|error| ClmError::Config(error.to_string()))
+}
+
+static REGISTRY: LazyLock<ClmResult<ModelRegistry>> = LazyLock::new(|| ModelRegistry::load(&config().models_path(), &config().generation));
+
+// The models served to the chat and the API
+pub fn registry() -> Result<&'static ModelRegistry, &'static ClmError> {
+    REGISTRY.as_ref()
+}
+
+// Loads every model of the registry now instead of on first use, so the server gets ready
+pub fn load_models() {
+    std::thread::spawn(|| {
+        let Ok(registry) = registry() else {
+            return;
+        };
+        for model in registry.models() {
+            if let Err(error) = model.load() {
+                logging::error!("model `{}` failed to load: {}", model.id(), error);
+            }
+        }
+    });
+}
+
+// Checks the checkpoints of the models every `interval`, swaps in the ones that changed on disk
+// and tries again to load the ones that failed
+pub fn watch_checkpoints(interval: Duration) {
+    std::thread::spawn(move || loop {
+        std::thread::sleep(interval);
+        let Ok(registry) = registry() else {
+            return;
+        };
+
+        for model in registry.models() {
+            if !model.is_serving() {
+                // a rejected checkpoint is only tried again once it changed
+                if model.load().is_ok() {
+                    logging::log!("loaded model `{}`", model.id());
+                }
+                continue;
+            }
+            match model.reload_if_changed() {
+                Some(Ok(_)) => logging::log!("reloaded model `{}`", model.id()),
+                Some(Err(error)) => logging::error!("kept serving model `{}`, its new checkpoint failed to load: {}", model.id(), error),
+                None => {}
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::{Duration, SystemTime};
+
+    use crate::backend::clm_model::ClmModel;
+    use crate::backend::generation::{FinishReason, GenerationConfig};
+    use crate::registry::{DictionaryInfo, GenerationDefaults, LanguageModel, ModelConfig, ModelRegistry, ModelSource, RegisteredModel, DEFAULT_REGISTRY};
+
+    #[test]
+    fn registry_files_list_models() {
+        let server_defaults = GenerationDefaults { max_new_tokens: 32, ..GenerationDefaults::default() };
+        let registry = ModelRegistry::from_toml(r#"
+            [[models]]
+            id = "small"
+            name = "Small ensemble"
+            kind = "ensemble"
+            checkpoint = "small.ensemble"
+
+            [models.generation]
+            max_new_tokens = 16
+            temperature = 0.7
+
+            [[models]]
+            id = "random"
+            name = "ChatRandom"
+            kind = "random"
+        "#, &server_defaults).unwrap();
+
+        let small = registry.find(None).unwrap();
+        assert_eq!(small.id(), "small");
+        assert_eq!(small.config.source, ModelSource::Ensemble { checkpoint: "small.ensemble".to_string() });
+        assert_eq!(small.generation.max_new_tokens, 16);
+        assert_eq!(small.generation.depth, GenerationDefaults::default().depth);
+
+        let random = registry.find(Some("random")).unwrap();
+        assert_eq!(random.config.source, ModelSource::Random);
+        assert_eq!(random.generation, server_defaults);
+        assert!(registry.find(Some("chatclm")).is_none());
+    }
+
+    #[test]
+    fn invalid_registries_are_rejected() {
+        let defaults = GenerationDefaults::default();
+        assert!(ModelRegistry::from_toml("models = []", &defaults).is_err());
+        assert!(ModelRegistry::from_toml(r#"
+            [[models]]
+            id = "a"
+            name = "A"
+            kind = "transformer"
+        "#, &defaults).is_err());
+
+        let twice = format!("{}{}", DEFAULT_REGISTRY, r#"
+            [[models]]
+            id = "random"
+            name = "Another random"
+            kind = "random"
+        "#);
+        assert!(ModelRegistry::from_toml(&twice, &defaults).is_err());
+    }
+
+    #[test]
+    fn random_model_streams_its_answer() {
+        let config = GenerationConfig { max_new_tokens: 3, ..GenerationConfig::new() };
+        let mut streamed = String::new();
+
+        let generation = LanguageModel::Random.generate_with("hi", &config, |token| streamed.push_str(&token.text)).unwrap();
+
+        assert_eq!(streamed, generation.text);
+        assert!(generation.tokens.len() <= 3);
+        assert_ne!(generation.finish_reason, FinishReason::Cancelled);
+    }
+
+    #[test]
+    fn broken_checkpoints_keep_the_old_model() {
+        let checkpoint = std::env::temp_dir().join(format!("chatclm-reload-{:016x}.bin", rand::random::<u64>()));
+        let checkpoint = checkpoint.to_str().unwrap().to_string();
+        ClmModel::from_buffer(Vec::new()).unwrap().save_checkpoint(&checkpoint).unwrap();
+        let model = RegisteredModel::new(ModelConfig {
+            id: "clm".to_string(),
+            name: "CLM".to_string(),
+            source: ModelSource::Clm { checkpoint: checkpoint.clone() },
+            generation: None,
+        }, &GenerationDefaults::default());
+
+        let serving = model.load().unwrap();
+        std::fs::remove_file(&checkpoint).unwrap();
+        assert!(model.reload().is_err());
+        assert!(model.reload_if_changed().is_none());
+        assert!(Arc::ptr_eq(&serving, &model.load().unwrap()));
+
+        ClmModel::from_buffer(Vec::new()).unwrap().save_checkpoint(&checkpoint).unwrap();
+        let reloaded = model.reload().unwrap();
+        assert!(!Arc::ptr_eq(&serving, &reloaded));
+        assert!(Arc::ptr_eq(&reloaded, &model.load().unwrap()));
+
+        std::fs::remove_file(&checkpoint).unwrap();
+    }
+
+    #[test]
+    fn corrupt_checkpoints_written_over_a_serving_one_are_rejected() {
+        let checkpoint = std::env::temp_dir().join(format!("chatclm-corrupt-{:016x}.bin", rand::random::<u64>()));
+        let checkpoint = checkpoint.to_str().unwrap().to_string();
+        ClmModel::from_buffer(Vec::new()).unwrap().save_checkpoint(&checkpoint).unwrap();
+        let model = RegisteredModel::new(ModelConfig {
+            id: "clm".to_string(),
+            name: "CLM".to_string(),
+            source: ModelSource::Clm { checkpoint: checkpoint.clone() },
+            generation: None,
+        }, &GenerationDefaults::default());
+        let serving = model.load().unwrap();
+
+        // a dictionary magic followed by garbage, the file time moves on as it would for a new checkpoint
+        let mut garbage = vec![0x37, 0xa4, 0x30, 0xec];
+        garbage.extend((0..64u8).map(|byte| byte.wrapping_mul(97)));
+        std::fs::write(&checkpoint, &garbage).unwrap();
+        let file = std::fs::File::options().write(true).open(&checkpoint).unwrap();
+        file.set_modified(SystemTime::now() + Duration::from_secs(5)).unwrap();
+
+        assert!(model.reload_if_changed().unwrap().is_err());
+        assert!(model.details().error.is_some());
+        assert!(Arc::ptr_eq(&serving, &model.load().unwrap()));
+        assert!(model.reload().is_err());
+
+        ClmModel::from_buffer(Vec::new()).unwrap().save_checkpoint(&checkpoint).unwrap();
+        assert!(!Arc::ptr_eq(&serving, &model.reload().unwrap()));
+
+        std::fs::remove_file(&checkpoint).unwrap();
+    }
+
+    #[test]
+    fn details_describe_the_serving_checkpoint() {
+        let checkpoint = std::env::temp_dir().join(format!("chatclm-details-{:016x}.bin", rand::random::<u64>()));
+        let checkpoint = checkpoint.to_str().unwrap().to_string();
+        ClmModel::from_buffer(Vec::new()).unwrap().save_checkpoint(&checkpoint).unwrap();
+        let model = RegisteredModel::new(ModelConfig {
+            id: "clm".to_string(),
+            name: "CLM".to_string(),
+            source: ModelSource::Clm { checkpoint: checkpoint.clone() },
+            generation: None,
+        }, &GenerationDefaults::default());
+
+        let details = model.details();
+        assert!(!details.serving);
+        assert!(details.dictionaries.is_empty());
+
+        model.load().unwrap();
+        let details = model.details();
+        assert!(details.serving);
+        assert_eq!(details.kind, Some("clm"));
+        assert_eq!(details.dictionaries, vec![DictionaryInfo { size: 0, id: None }]);
+        assert!(details.tokenizer_vocab_size.unwrap() > 0);
+        assert!(details.load_seconds.is_some());
+
+        std::fs::remove_file(&checkpoint).unwrap();
+    }
+
+    #[test]
+    fn continuations_cost_compressed_bytes() {
+        let clm = LanguageModel::Clm(Box::new(ClmModel::from_buffer(Vec::new()).unwrap()));
+        let (bytes, tokens) = clm.continuation_cost("The quick brown fox", " jumps over the lazy dog").unwrap().unwrap();
+        assert!(tokens > 0);
+        assert!(bytes > 0.0);
+
+        assert_eq!(LanguageModel::Random.continuation_cost("hi", " next").unwrap(), None);
+    }
+}
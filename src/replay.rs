@@ -0,0 +1,194 @@
+use std::path::PathBuf;
+
+use clap::Parser;
+
+use chatclm::backend::error::{ClmError, ClmResult};
+use chatclm::registry::{LanguageModel, ModelSource};
+use chatclm::request_log::{generate_timed, RequestRecord};
+
+const PROMPT_END_LENGTH: usize = 60;
+
+/// Replays the prompts of a request log against model checkpoints and compares the outputs,
+/// the latencies and how well each model compresses the logged answers.
+#[derive(Parser, Debug)]
+#[command(name = "replay")]
+struct Cli {
+    /// Request log written by the server, one JSON request per line
+    log: PathBuf,
+    /// Single model checkpoint to replay against, can be given several times
+    #[arg(long)]
+    clm: Vec<String>,
+    /// Ensemble checkpoint to replay against, can be given several times
+    #[arg(long)]
+    ensemble: Vec<String>,
+    /// Only replay requests that were answered by this model id
+    #[arg(long)]
+    logged_model: Option<String>,
+    /// Replay at most this many requests
+    #[arg(long)]
+    limit: Option<usize>,
+    /// Differing outputs shown per model
+    #[arg(long, default_value_t = 5)]
+    diffs: usize,
+}
+
+// Results of one model over all replayed requests
+struct Replay {
+    name: String,
+    outputs: Vec<String>,
+    token_latencies_ms: Vec<f64>,
+    total_ms: Vec<f64>,
+    bytes: f64, /* compressed bytes the logged answers cost under the model */
+    tokens: usize,
+}
+
+fn main() {
+    let cli = Cli::parse();
+    if let Err(error) = run(&cli) {
+        eprintln!("replay: {}", error);
+        std::process::exit(1);
+    }
+}
+
+fn run(cli: &Cli) -> ClmResult<()> {
+    let sources = cli.clm.iter()
+        .map(|checkpoint| (format!("clm:{}", checkpoint), ModelSource::Clm { checkpoint: checkpoint.clone() }))
+        .chain(cli.ensemble.iter().map(|checkpoint| (format!("ensemble:{}", checkpoint), ModelSource::Ensemble { checkpoint: checkpoint.clone() })));
+    let models = sources
+        .map(|(name, source)| Ok((name, LanguageModel::load(&source)?)))
+        .collect::<ClmResult<Vec<_>>>()?;
+    if models.is_empty() {
+        return Err(ClmError::Config("give at least one --clm or --ensemble checkpoint".to_string()));
+    }
+
+    let (records, redacted) = read_log(cli)?;
+    println!("Replaying {} requests from {}, skipped {} redacted ones", records.len(), cli.log.display(), redacted);
+
+    let logged = Replay {
+        name: "logged".to_string(),
+        outputs: records.iter().map(|record| record.text.clone().unwrap_or_default()).collect(),
+        token_latencies_ms: records.iter().flat_map(|record| record.token_latencies_ms.iter().copied()).collect(),
+        total_ms: records.iter().map(|record| record.total_ms).collect(),
+        bytes: 0.0,
+        tokens: 0,
+    };
+    let replays = models.iter()
+        .map(|(name, model)| replay(name, model, &records))
+        .collect::<ClmResult<Vec<_>>>()?;
+
+    println!();
+    println!("{:<40} {:>12} {:>10} {:>10} {:>10} {:>10} {:>12}", "model", "same output", "token p50", "token p90", "token p99", "total p50", "bytes/token");
+    print_row(&logged, None);
+    for replayed in &replays {
+        print_row(replayed, Some(&logged.outputs));
+    }
+
+    for replayed in &replays {
+        print_diffs(replayed, &records, cli.diffs);
+    }
+    Ok(())
+}
+
+// Requests without a prompt were logged redacted and cannot be replayed
+fn read_log(cli: &Cli) -> ClmResult<(Vec<RequestRecord>, usize)> {
+    let text = std::fs::read_to_string(&cli.log)?;
+    let mut records = Vec::new();
+    let mut redacted = 0;
+    for (number, line) in text.lines().enumerate().filter(|(_, line)| !line.trim().is_empty()) {
+        let record: RequestRecord = serde_json::from_str(line)
+            .map_err(|error| ClmError::Format(format!("{} line {}: {}", cli.log.display(), number + 1, error)))?;
+        if cli.logged_model.as_ref().is_some_and(|model| *model != record.model) {
+            continue;
+        }
+        if record.prompt.is_none() || record.text.is_none() {
+            redacted += 1;
+            continue;
+        }
+        records.push(record);
+    }
+    records.truncate(cli.limit.unwrap_or(records.len()));
+    Ok((records, redacted))
+}
+
+fn replay(name: &str, model: &LanguageModel, records: &[RequestRecord]) -> ClmResult<Replay> {
+    let mut replayed = Replay {
+        name: name.to_string(),
+        outputs: Vec::new(),
+        token_latencies_ms: Vec::new(),
+        total_ms: Vec::new(),
+        bytes: 0.0,
+        tokens: 0,
+    };
+
+    for record in records {
+        let prompt = record.prompt.as_deref().unwrap_or_default();
+        let timed = generate_timed(model, prompt, &record.settings.config(), |_| {})?;
+        replayed.outputs.push(timed.generation.text);
+        replayed.token_latencies_ms.extend(timed.token_latencies_ms);
+        replayed.total_ms.push(timed.total_ms);
+
+        if let Some((bytes, tokens)) = model.continuation_cost(prompt, record.text.as_deref().unwrap_or_default())? {
+            replayed.bytes += bytes;
+            replayed.tokens += tokens;
+        }
+    }
+    Ok(replayed)
+}
+
+fn print_row(replay: &Replay, logged_outputs: Option<&[String]>) {
+    let same = match logged_outputs {
+        Some(logged) => format!("{}/{}", replay.outputs.iter().zip(logged).filter(|(a, b)| a == b).count(), logged.len()),
+        None => "-".to_string(),
+    };
+    let bytes_per_token = match replay.tokens {
+        0 => "-".to_string(),
+        tokens => format!("{:.3}", replay.bytes / tokens as f64),
+    };
+    println!(
+        "{:<40} {:>12} {:>10} {:>10} {:>10} {:>10} {:>12}",
+        replay.name,
+        same,
+        milliseconds(percentile(&replay.token_latencies_ms, 0.5)),
+        milliseconds(percentile(&replay.token_latencies_ms, 0.9)),
+        milliseconds(percentile(&replay.token_latencies_ms, 0.99)),
+        milliseconds(percentile(&replay.total_ms, 0.5)),
+        bytes_per_token,
+    );
+}
+
+fn print_diffs(replay: &Replay, records: &[RequestRecord], limit: usize) {
+    let diffs: Vec<_> = records.iter()
+        .zip(&replay.outputs)
+        .enumerate()
+        .filter(|(_, (record, output))| record.text.as_ref() != Some(*output))
+        .collect();
+    if diffs.is_empty() || limit == 0 {
+        return;
+    }
+
+    println!();
+    println!("{} of {} outputs differ under {}", diffs.len(), records.len(), replay.name);
+    for (index, (record, output)) in diffs.into_iter().take(limit) {
+        println!("  #{} {} [{}] {:?}", index + 1, record.timestamp, record.model, prompt_end(record.prompt.as_deref().unwrap_or_default()));
+        println!("    - {:?}", record.text.as_deref().unwrap_or_default());
+        println!("    + {:?}", output);
+    }
+}
+
+// Chat prompts hold the whole conversation, its end is what is being answered
+fn prompt_end(prompt: &str) -> String {
+    let chars: Vec<char> = prompt.chars().collect();
+    chars[chars.len().saturating_sub(PROMPT_END_LENGTH)..].iter().collect()
+}
+
+// Nearest rank percentile, None without values
+fn percentile(values: &[f64], fraction: f64) -> Option<f64> {
+    let mut sorted = values.to_vec();
+    sorted.sort_by(f64::total_cmp);
+    let rank = (fraction * sorted.len() as f64).ceil() as usize;
+    sorted.get(rank.saturating_sub(1)).copied()
+}
+
+fn milliseconds(value: Option<f64>) -> String {
+    value.map_or_else(|| "-".to_string(), |value| format!("{:.1} ms", value))
+}
@@ -0,0 +1,288 @@
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{LazyLock, Mutex};
+use std::time::{Duration, Instant};
+
+use leptos::logging;
+use serde::{Deserialize, Serialize};
+
+use crate::backend::error::ClmResult;
+use crate::backend::generation::{FinishReason, GeneratedToken, Generation, GenerationConfig};
+use crate::backend::sampling::SamplingOptions;
+use crate::config::config;
+use crate::metrics;
+use crate::registry::LanguageModel;
+
+// Where and how generation requests are recorded, one JSON line per generation
+#[derive(Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct RequestLogConfig {
+    pub enabled: bool,
+    pub path: PathBuf, /* relative to the data directory */
+    pub max_bytes: u64, /* the log is rotated once it would grow past this */
+    pub keep: usize, /* rotated logs kept next to it as `<path>.1` (the newest) up to `<path>.<keep>` */
+    pub redact: bool, /* leave out prompts and generated text, keep everything else */
+}
+
+impl Default for RequestLogConfig {
+    fn default() -> Self {
+        RequestLogConfig {
+            enabled: true,
+            path: PathBuf::from("logs/requests.jsonl"),
+            max_bytes: 64 * 1024 * 1024,
+            keep: 5,
+            redact: false,
+        }
+    }
+}
+
+// The search settings of a generation, enough to run it again
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct RequestSettings {
+    pub max_new_tokens: usize,
+    pub stop_strings: Vec<String>,
+    pub stop_on_sentence_end: bool,
+    pub timeout_ms: Option<u64>,
+    pub depth: usize,
+    pub width: usize,
+    pub temperature: f64,
+    pub top_k: Option<usize>,
+    pub top_p: Option<f64>,
+    pub min_p: Option<f64>,
+    pub seed: Option<u64>,
+}
+
+impl RequestSettings {
+    pub fn from_config(config: &GenerationConfig) -> Self {
+        RequestSettings {
+            max_new_tokens: config.max_new_tokens,
+            stop_strings: config.stop_strings.clone(),
+            stop_on_sentence_end: config.stop_on_sentence_end,
+            timeout_ms: config.timeout.map(|timeout| timeout.as_millis() as u64),
+            depth: config.depth,
+            width: config.width,
+            temperature: config.sampling.temperature,
+            top_k: config.sampling.top_k,
+            top_p: config.sampling.top_p,
+            min_p: config.sampling.min_p,
+            seed: config.sampling.seed,
+        }
+    }
+
+    pub fn config(&self) -> GenerationConfig {
+        GenerationConfig {
+            max_new_tokens: self.max_new_tokens,
+            stop_strings: self.stop_strings.clone(),
+            stop_on_sentence_end: self.stop_on_sentence_end,
+            timeout: self.timeout_ms.map(Duration::from_millis),
+            sampling: SamplingOptions {
+                temperature: self.temperature,
+                top_k: self.top_k,
+                top_p: self.top_p,
+                min_p: self.min_p,
+                seed: self.seed,
+            },
+            depth: self.depth,
+            width: self.width,
+            ..GenerationConfig::new()
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct RequestRecord {
+    pub timestamp: String,
+    pub source: String, /* "chat" or the API route */
+    pub model: String,
+    pub prompt: Option<String>, /* None if the log is redacted */
+    pub text: Option<String>,
+    pub tokens: usize,
+    pub token_latencies_ms: Vec<f64>,
+    pub total_ms: f64,
+    pub settings: RequestSettings,
+    pub finish_reason: FinishReason,
+}
+
+pub struct TimedGeneration {
+    pub generation: Generation,
+    pub token_latencies_ms: Vec<f64>, /* time until each token, the first one includes reading the prompt */
+    pub total_ms: f64,
+}
+
+pub fn generate_timed(model: &LanguageModel, prompt: &str, config: &GenerationConfig, mut on_token: impl FnMut(&GeneratedToken)) -> ClmResult<TimedGeneration> {
+    let start = Instant::now();
+    let mut last_token = start;
+    let mut token_latencies_ms = Vec::new();
+
+    let generation = model.generate_with(prompt, config, |token| {
+        token_latencies_ms.push(milliseconds(last_token.elapsed()));
+        last_token = Instant::now();
+        on_token(token);
+    })?;
+
+    Ok(TimedGeneration { generation, token_latencies_ms, total_ms: milliseconds(start.elapsed()) })
+}
+
+// Runs a generation of the model with id `model_id` and records it in the request log
+pub fn generate_logged(source: &str, model_id: &str, model: &LanguageModel, prompt: &str, config: &GenerationConfig, on_token: impl FnMut(&GeneratedToken)) -> ClmResult<Generation> {
+    let timed = generate_timed(model, prompt, config, on_token);
+    metrics::record_generation(source, model_id, timed.as_ref());
+    let TimedGeneration { generation, token_latencies_ms, total_ms } = timed?;
+
+    log_request(RequestRecord {
+        timestamp: chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+        source: source.to_string(),
+        model: model_id.to_string(),
+        prompt: Some(prompt.to_string()),
+        text: Some(generation.text.clone()),
+        tokens: generation.tokens.len(),
+        token_latencies_ms,
+        total_ms,
+        settings: RequestSettings::from_config(config),
+        finish_reason: generation.finish_reason,
+    });
+    Ok(generation)
+}
+
+fn milliseconds(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+static REQUEST_LOG: LazyLock<Option<Mutex<RequestLog>>> = LazyLock::new(|| {
+    let settings = &config().request_log;
+    settings.enabled.then(|| Mutex::new(RequestLog::new(config().data_dir.join(&settings.path), settings.clone())))
+});
+
+// A request that cannot be logged is still answered
+pub fn log_request(record: RequestRecord) {
+    let Some(log) = REQUEST_LOG.as_ref() else {
+        return;
+    };
+    if let Err(error) = log.lock().unwrap().write(record) {
+        logging::error!("could not write the request log: {}", error);
+    }
+}
+
+pub struct RequestLog {
+    path: PathBuf,
+    settings: RequestLogConfig,
+    file: Option<(File, u64)>, /* opened on the first write, with its size */
+}
+
+impl RequestLog {
+    pub fn new(path: PathBuf, settings: RequestLogConfig) -> Self {
+        RequestLog { path, settings, file: None }
+    }
+
+    pub fn write(&mut self, mut record: RequestRecord) -> std::io::Result<()> {
+        if self.settings.redact {
+            record.prompt = None;
+            record.text = None;
+        }
+        let mut line = serde_json::to_vec(&record)?;
+        line.push(b'\n');
+
+        let size = match &self.file {
+            Some((_, size)) => *size,
+            None => std::fs::metadata(&self.path).map(|metadata| metadata.len()).unwrap_or(0),
+        };
+        if size > 0 && size + line.len() as u64 > self.settings.max_bytes {
+            self.rotate()?;
+        }
+
+        if self.file.is_none() {
+            self.file = Some(self.open()?);
+        }
+        let (file, size) = self.file.as_mut().unwrap();
+        file.write_all(&line)?;
+        *size += line.len() as u64;
+        Ok(())
+    }
+
+    fn open(&self) -> std::io::Result<(File, u64)> {
+        if let Some(directory) = self.path.parent() {
+            std::fs::create_dir_all(directory)?;
+        }
+        let file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        let size = file.metadata()?.len();
+        Ok((file, size))
+    }
+
+    // Moves every log one number up, the oldest one falls off the end
+    fn rotate(&mut self) -> std::io::Result<()> {
+        self.file = None;
+        if self.settings.keep == 0 {
+            return std::fs::remove_file(&self.path);
+        }
+        for number in (1..self.settings.keep).rev() {
+            let from = rotated_path(&self.path, number);
+            if from.exists() {
+                std::fs::rename(from, rotated_path(&self.path, number + 1))?;
+            }
+        }
+        std::fs::rename(&self.path, rotated_path(&self.path, 1))
+    }
+}
+
+pub fn rotated_path(path: &Path, number: usize) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{}", number));
+    PathBuf::from(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::backend::generation::{FinishReason, GenerationConfig};
+    use crate::request_log::{rotated_path, RequestLog, RequestLogConfig, RequestRecord, RequestSettings};
+
+    fn record() -> RequestRecord {
+        RequestRecord {
+            timestamp: "2024-06-01T12:00:00.000Z".to_string(),
+            source: "chat".to_string(),
+            model: "random".to_string(),
+            prompt: Some("User: Hi\nChatCLM:".to_string()),
+            text: Some(" next".to_string()),
+            tokens: 1,
+            token_latencies_ms: vec![1.5],
+            total_ms: 2.0,
+            settings: RequestSettings::from_config(&GenerationConfig::new()),
+            finish_reason: FinishReason::Stop,
+        }
+    }
+
+    fn read_records(path: &std::path::Path) -> Vec<RequestRecord> {
+        std::fs::read_to_string(path).unwrap().lines().map(|line| serde_json::from_str(line).unwrap()).collect()
+    }
+
+    #[test]
+    fn logs_rotate_and_redact() {
+        let directory = std::env::temp_dir().join(format!("chatclm-requests-{:016x}", rand::random::<u64>()));
+        let path = directory.join("requests.jsonl");
+        let line_length = serde_json::to_vec(&record()).unwrap().len() as u64 + 1;
+        let settings = RequestLogConfig { max_bytes: 2 * line_length, keep: 1, ..RequestLogConfig::default() };
+
+        let mut log = RequestLog::new(path.clone(), settings.clone());
+        for _ in 0..5 {
+            log.write(record()).unwrap();
+        }
+        assert_eq!(read_records(&path), vec![record()]);
+        assert_eq!(read_records(&rotated_path(&path, 1)), vec![record(), record()]);
+        assert!(!rotated_path(&path, 2).exists());
+
+        let mut redacted = RequestLog::new(path.clone(), RequestLogConfig { redact: true, ..settings });
+        redacted.write(record()).unwrap();
+        let last = read_records(&path).pop().unwrap();
+        assert_eq!((last.prompt, last.text), (None, None));
+        assert_eq!(last.settings, record().settings);
+
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn settings_run_the_generation_again() {
+        let config = GenerationConfig { max_new_tokens: 7, depth: 2, stop_strings: vec!["\nUser:".to_string()], ..GenerationConfig::new() };
+        let settings = RequestSettings::from_config(&config);
+        assert_eq!(RequestSettings::from_config(&settings.config()), settings);
+    }
+}
@@ -0,0 +1,277 @@
+use std::collections::VecDeque;
+use std::panic::AssertUnwindSafe;
+use std::sync::{Arc, Condvar, LazyLock, Mutex};
+use std::time::{Duration, Instant};
+
+use futures::channel::oneshot;
+use leptos::logging;
+use thiserror::Error;
+
+use crate::config::config;
+
+// How often waiting generations are checked for having waited too long
+const EXPIRY_INTERVAL: Duration = Duration::from_secs(1);
+
+// Generations score every candidate token on the rayon pool and keep a CPU busy the whole time,
+// so they run on a fixed number of worker threads instead of the tokio runtime. Waiting
+// generations take turns by client, so one client sending many requests only delays itself.
+pub struct Scheduler {
+    queue: Mutex<FairQueue>,
+    available: Condvar,
+    max_queue: usize,
+    max_client_queue: usize,
+    max_wait: Duration,
+}
+
+#[derive(Error, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rejection {
+    #[error("The server is busy, too many generations are waiting")]
+    QueueFull,
+    #[error("Too many of your generations are waiting, wait for them to finish")]
+    ClientQueueFull,
+    #[error("The server is busy, the generation waited too long for its turn")]
+    TimedOut,
+}
+
+struct Job {
+    queued: Instant,
+    on_position: Box<dyn Fn(usize) + Send>, /* 1 means next in line */
+    run: Box<dyn FnOnce(Result<(), Rejection>) + Send>,
+}
+
+// Round robin over the clients, each with its own line of jobs
+#[derive(Default)]
+struct FairQueue {
+    clients: VecDeque<(String, VecDeque<Job>)>,
+}
+
+impl FairQueue {
+    fn len(&self) -> usize {
+        self.clients.iter().map(|(_, jobs)| jobs.len()).sum()
+    }
+
+    fn client_len(&self, client: &str) -> usize {
+        self.clients.iter().find(|(id, _)| id == client).map_or(0, |(_, jobs)| jobs.len())
+    }
+
+    fn push(&mut self, client: &str, job: Job) {
+        match self.clients.iter_mut().find(|(id, _)| id == client) {
+            Some((_, jobs)) => jobs.push_back(job),
+            None => self.clients.push_back((client.to_string(), VecDeque::from([job]))),
+        }
+    }
+
+    // The client whose turn it is gives up its oldest job and goes to the back
+    fn pop(&mut self) -> Option<Job> {
+        let (client, mut jobs) = self.clients.pop_front()?;
+        let job = jobs.pop_front();
+        if !jobs.is_empty() {
+            self.clients.push_back((client, jobs));
+        }
+        job
+    }
+
+    fn remove_expired(&mut self, max_wait: Duration) -> Vec<Job> {
+        let mut expired = Vec::new();
+        for (_, jobs) in &mut self.clients {
+            while jobs.front().is_some_and(|job| job.queued.elapsed() > max_wait) {
+                expired.extend(jobs.pop_front());
+            }
+        }
+        self.clients.retain(|(_, jobs)| !jobs.is_empty());
+        expired
+    }
+
+    // Jobs in the order they will run: the first job of every client, then the second ones, ...
+    fn in_order(&self) -> Vec<&Job> {
+        let longest = self.clients.iter().map(|(_, jobs)| jobs.len()).max().unwrap_or(0);
+        (0..longest)
+            .flat_map(|round| self.clients.iter().filter_map(move |(_, jobs)| jobs.get(round)))
+            .collect()
+    }
+
+    fn announce_positions(&self) {
+        for (index, job) in self.in_order().into_iter().enumerate() {
+            (job.on_position)(index + 1);
+        }
+    }
+}
+
+impl Scheduler {
+    // Starts `workers` threads that run one generation at a time each
+    pub fn start(workers: usize, max_queue: usize, max_client_queue: usize, max_wait: Duration) -> Arc<Self> {
+        let scheduler = Arc::new(Scheduler {
+            queue: Mutex::new(FairQueue::default()),
+            available: Condvar::new(),
+            max_queue,
+            max_client_queue,
+            max_wait,
+        });
+
+        for number in 0..workers.max(1) {
+            let scheduler = scheduler.clone();
+            std::thread::Builder::new()
+                .name(format!("generation-{}", number))
+                .spawn(move || scheduler.work())
+                .expect("Failed to start a generation worker");
+        }
+        let expiring = scheduler.clone();
+        std::thread::spawn(move || loop {
+            std::thread::sleep(EXPIRY_INTERVAL);
+            expiring.expire();
+        });
+
+        scheduler
+    }
+
+    // Queues `run`, which later gets to run on a worker or learns that it waited too long.
+    // `on_position` hears the place in line whenever it changes.
+    pub fn submit(&self, client: &str, on_position: impl Fn(usize) + Send + 'static, run: impl FnOnce(Result<(), Rejection>) + Send + 'static) -> Result<(), Rejection> {
+        let mut queue = self.queue.lock().unwrap();
+        // a client that floods the queue is turned away before it fills it for everyone
+        if queue.client_len(client) >= self.max_client_queue {
+            return Err(Rejection::ClientQueueFull);
+        }
+        if queue.len() >= self.max_queue {
+            return Err(Rejection::QueueFull);
+        }
+        queue.push(client, Job { queued: Instant::now(), on_position: Box::new(on_position), run: Box::new(run) });
+        queue.announce_positions();
+        self.available.notify_one();
+        Ok(())
+    }
+
+    // Runs `work` once it is the turn of `client`
+    pub async fn run<T: Send + 'static>(&self, client: &str, work: impl FnOnce() -> T + Send + 'static) -> Result<T, Rejection> {
+        let (sender, receiver) = oneshot::channel();
+        self.submit(client, |_| {}, move |turn| {
+            let _ = sender.send(turn.map(|_| work()));
+        })?;
+        receiver.await.unwrap_or(Err(Rejection::TimedOut))
+    }
+
+    fn work(&self) {
+        loop {
+            let job = {
+                let mut queue = self.queue.lock().unwrap();
+                let job = loop {
+                    match queue.pop() {
+                        Some(job) => break job,
+                        None => queue = self.available.wait(queue).unwrap(),
+                    }
+                };
+                queue.announce_positions();
+                job
+            };
+
+            // a panicking generation fails its own request, the worker carries on
+            if std::panic::catch_unwind(AssertUnwindSafe(|| (job.run)(Ok(())))).is_err() {
+                logging::error!("a generation panicked");
+            }
+        }
+    }
+
+    fn expire(&self) {
+        let expired = {
+            let mut queue = self.queue.lock().unwrap();
+            let expired = queue.remove_expired(self.max_wait);
+            if !expired.is_empty() {
+                queue.announce_positions();
+            }
+            expired
+        };
+        for job in expired {
+            (job.run)(Err(Rejection::TimedOut));
+        }
+    }
+}
+
+static SCHEDULER: LazyLock<Arc<Scheduler>> = LazyLock::new(|| {
+    let limits = &config().limits;
+    Scheduler::start(limits.concurrent_generations, limits.max_queue, limits.max_client_queue, Duration::from_secs(limits.max_queue_wait_secs))
+});
+
+// The generations of the chat and the API, started on first use
+pub fn scheduler() -> &'static Scheduler {
+    &SCHEDULER
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc;
+    use std::time::{Duration, Instant};
+
+    use crate::scheduler::{FairQueue, Job, Rejection, Scheduler};
+
+    fn job(name: &'static str, order: mpsc::Sender<&'static str>) -> Job {
+        Job {
+            queued: Instant::now(),
+            on_position: Box::new(|_| {}),
+            run: Box::new(move |_| order.send(name).unwrap()),
+        }
+    }
+
+    #[test]
+    fn clients_take_turns() {
+        let (sender, receiver) = mpsc::channel();
+        let mut queue = FairQueue::default();
+        queue.push("busy", job("busy 1", sender.clone()));
+        queue.push("busy", job("busy 2", sender.clone()));
+        queue.push("busy", job("busy 3", sender.clone()));
+        queue.push("other", job("other 1", sender.clone()));
+
+        while let Some(job) = queue.pop() {
+            (job.run)(Ok(()));
+        }
+        let order: Vec<_> = receiver.try_iter().collect();
+        assert_eq!(order, vec!["busy 1", "other 1", "busy 2", "busy 3"]);
+    }
+
+    #[test]
+    fn full_queues_reject_generations() {
+        let scheduler = Scheduler::start(1, 1, 1, Duration::from_secs(60));
+        let (started, running) = mpsc::channel();
+        let (finish, finished) = mpsc::channel::<()>();
+        scheduler.submit("a", |_| {}, move |_| {
+            started.send(()).unwrap();
+            finished.recv().unwrap();
+        }).unwrap();
+        running.recv().unwrap();
+
+        let (positions, position) = mpsc::channel();
+        scheduler.submit("b", move |place| positions.send(place).unwrap(), |_| {}).unwrap();
+        assert_eq!(position.recv().unwrap(), 1);
+        assert_eq!(scheduler.submit("c", |_| {}, |_| {}), Err(Rejection::QueueFull));
+        finish.send(()).unwrap();
+    }
+
+    #[test]
+    fn one_client_cannot_fill_the_queue() {
+        let scheduler = Scheduler::start(1, 4, 2, Duration::from_secs(60));
+        let (started, running) = mpsc::channel();
+        let (finish, finished) = mpsc::channel::<()>();
+        scheduler.submit("a", |_| {}, move |_| {
+            started.send(()).unwrap();
+            finished.recv().unwrap();
+        }).unwrap();
+        running.recv().unwrap();
+
+        scheduler.submit("a", |_| {}, |_| {}).unwrap();
+        scheduler.submit("a", |_| {}, |_| {}).unwrap();
+        assert_eq!(scheduler.submit("a", |_| {}, |_| {}), Err(Rejection::ClientQueueFull));
+        assert_eq!(scheduler.submit("b", |_| {}, |_| {}), Ok(()));
+        finish.send(()).unwrap();
+    }
+
+    #[test]
+    fn waiting_too_long_times_out() {
+        let scheduler = Scheduler::start(1, 8, 8, Duration::ZERO);
+        let (finish, finished) = mpsc::channel::<()>();
+        scheduler.submit("a", |_| {}, move |_| finished.recv().unwrap()).unwrap();
+
+        let (turns, turn) = mpsc::channel();
+        scheduler.submit("b", |_| {}, move |result| turns.send(result).unwrap()).unwrap();
+        assert_eq!(turn.recv_timeout(Duration::from_secs(5)).unwrap(), Err(Rejection::TimedOut));
+        finish.send(()).unwrap();
+    }
+}
@@ -0,0 +1,497 @@
+use std::path::Path;
+use std::sync::{LazyLock, Mutex};
+
+use rusqlite::{params, Connection, OptionalExtension, Row};
+
+use crate::chat::{now_iso, ChatHistory, Conversation, ConversationSummary, Message, Sender, TokenInfo, Variants, UNTITLED_CONVERSATION};
+use crate::compare::{ComparedAnswer, Preference};
+use crate::config::config;
+use crate::model::GenerationSettings;
+
+const GENERATED_TITLE_LENGTH: usize = 40;
+
+// Columns older databases are missing by table, with how they are added. Conversations from before
+// owners belong to nobody.
+const ADDED_COLUMNS: [(&str, &str, &str); 4] = [
+    ("messages", "variants", "ALTER TABLE messages ADD COLUMN variants TEXT NOT NULL DEFAULT ''"),
+    ("messages", "tokens", "ALTER TABLE messages ADD COLUMN tokens TEXT NOT NULL DEFAULT ''"),
+    ("conversations", "settings", "ALTER TABLE conversations ADD COLUMN settings TEXT NOT NULL DEFAULT ''"),
+    ("conversations", "owner", "ALTER TABLE conversations ADD COLUMN owner TEXT NOT NULL DEFAULT ''"),
+];
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS conversations (
+        id TEXT PRIMARY KEY,
+        owner TEXT NOT NULL DEFAULT '', -- the browser that started the conversation, only it gets to see it
+        title TEXT, -- NULL until the conversation is renamed or named after its first prompt
+        model TEXT NOT NULL,
+        settings TEXT NOT NULL DEFAULT '', -- JSON of the generation settings, empty for the model defaults
+        created TEXT NOT NULL,
+        updated TEXT NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS messages (
+        conversation_id TEXT NOT NULL,
+        position INTEGER NOT NULL,
+        sender TEXT NOT NULL,
+        message TEXT NOT NULL,
+        time_iso TEXT NOT NULL,
+        stopped INTEGER NOT NULL,
+        variants TEXT NOT NULL DEFAULT '', -- JSON of the other branches at the message, empty without any
+        tokens TEXT NOT NULL DEFAULT '', -- JSON of the scored tokens of an answer, empty for prompts
+        PRIMARY KEY (conversation_id, position)
+    );
+    CREATE TABLE IF NOT EXISTS comparisons (
+        id TEXT PRIMARY KEY,
+        created TEXT NOT NULL,
+        prompt TEXT NOT NULL,
+        models TEXT NOT NULL -- JSON list of every model the prompt was sent to
+    );
+    CREATE TABLE IF NOT EXISTS compared_answers (
+        comparison_id TEXT NOT NULL,
+        model TEXT NOT NULL,
+        answer TEXT, -- NULL until the generation ended
+        first_token_ms REAL, -- measured by the server from the start of the stream
+        total_ms REAL,
+        bytes_per_token REAL,
+        preferred INTEGER, -- 1 for thumbs up, 0 for thumbs down, NULL until voted on
+        voted TEXT,
+        PRIMARY KEY (comparison_id, model)
+    );
+";
+
+static STORE: LazyLock<rusqlite::Result<ConversationStore>> = LazyLock::new(|| ConversationStore::open(&config().database_path()));
+
+// The conversations of the chat, opened on first use
+pub fn store() -> Result<&'static ConversationStore, &'static rusqlite::Error> {
+    STORE.as_ref()
+}
+
+pub struct ConversationStore {
+    connection: Mutex<Connection>,
+}
+
+impl ConversationStore {
+    pub fn open(path: &Path) -> rusqlite::Result<Self> {
+        Self::with_connection(Connection::open(path)?)
+    }
+
+    pub fn in_memory() -> rusqlite::Result<Self> {
+        Self::with_connection(Connection::open_in_memory()?)
+    }
+
+    fn with_connection(connection: Connection) -> rusqlite::Result<Self> {
+        connection.execute_batch(SCHEMA)?;
+        for (table, column, migration) in ADDED_COLUMNS {
+            let exists: bool = connection.query_row(
+                "SELECT COUNT(*) > 0 FROM pragma_table_info(?) WHERE name = ?", [table, column], |row| row.get(0),
+            )?;
+            if !exists {
+                connection.execute_batch(migration)?;
+            }
+        }
+        Ok(ConversationStore { connection: Mutex::new(connection) })
+    }
+
+    // Every method only sees the conversations of `owner`, the ones of others do not exist for it
+    pub fn list(&self, owner: &str) -> rusqlite::Result<Vec<ConversationSummary>> {
+        let connection = self.connection.lock().unwrap();
+        let mut stmt = connection.prepare("SELECT id, title, model, created, updated FROM conversations WHERE owner = ? ORDER BY updated DESC, created DESC")?;
+        let conversations = stmt.query_map([owner], summary_from_row)?.collect();
+        conversations
+    }
+
+    pub fn get(&self, owner: &str, id: &str) -> rusqlite::Result<Option<Conversation>> {
+        let connection = self.connection.lock().unwrap();
+        let summary = connection
+            .query_row("SELECT id, title, model, created, updated FROM conversations WHERE id = ? AND owner = ?", [id, owner], summary_from_row)
+            .optional()?;
+        let Some(summary) = summary else {
+            return Ok(None);
+        };
+        let settings: String = connection.query_row("SELECT settings FROM conversations WHERE id = ?", [id], |row| row.get(0))?;
+
+        let mut stmt = connection.prepare("SELECT sender, message, time_iso, stopped, variants, tokens FROM messages WHERE conversation_id = ? ORDER BY position")?;
+        let messages = stmt.query_map([id], |row| {
+            Ok(Message {
+                sender: sender_from_text(&row.get::<_, String>(0)?),
+                message: row.get(1)?,
+                time_iso: row.get(2)?,
+                stopped: row.get(3)?,
+                variants: variants_from_text(&row.get::<_, String>(4)?),
+                tokens: tokens_from_text(&row.get::<_, String>(5)?),
+            })
+        })?.collect::<rusqlite::Result<_>>()?;
+
+        Ok(Some(Conversation { summary, settings: settings_from_text(&settings), history: ChatHistory { messages } }))
+    }
+
+    pub fn create(&self, owner: &str, model_id: &str, settings: &GenerationSettings) -> rusqlite::Result<ConversationSummary> {
+        let now = now_iso();
+        let summary = ConversationSummary {
+            id: format!("{:016x}", rand::random::<u64>()),
+            title: UNTITLED_CONVERSATION.to_string(),
+            model_id: model_id.to_string(),
+            created_iso: now.clone(),
+            updated_iso: now,
+        };
+
+        self.connection.lock().unwrap().execute(
+            "INSERT INTO conversations (id, owner, title, model, settings, created, updated) VALUES (?, ?, NULL, ?, ?, ?, ?)",
+            params![summary.id, owner, summary.model_id, settings_to_text(settings), summary.created_iso, summary.updated_iso],
+        )?;
+        Ok(summary)
+    }
+
+    // Returns whether the conversation exists, the same goes for the other changes
+    pub fn rename(&self, owner: &str, id: &str, title: &str) -> rusqlite::Result<bool> {
+        let title = Some(title).filter(|title| !title.is_empty());
+        let changed = self.connection.lock().unwrap()
+            .execute("UPDATE conversations SET title = ? WHERE id = ? AND owner = ?", params![title, id, owner])?;
+        Ok(changed > 0)
+    }
+
+    // Settings are no news, the conversation keeps its place in the list
+    pub fn set_settings(&self, owner: &str, id: &str, settings: &GenerationSettings) -> rusqlite::Result<bool> {
+        let changed = self.connection.lock().unwrap()
+            .execute("UPDATE conversations SET settings = ? WHERE id = ? AND owner = ?", params![settings_to_text(settings), id, owner])?;
+        Ok(changed > 0)
+    }
+
+    pub fn delete(&self, owner: &str, id: &str) -> rusqlite::Result<bool> {
+        let mut connection = self.connection.lock().unwrap();
+        let transaction = connection.transaction()?;
+        let changed = transaction.execute("DELETE FROM conversations WHERE id = ? AND owner = ?", [id, owner])?;
+        if changed == 0 {
+            return Ok(false);
+        }
+        transaction.execute("DELETE FROM messages WHERE conversation_id = ?", [id])?;
+        transaction.commit()?;
+        Ok(true)
+    }
+
+    pub fn save(&self, owner: &str, id: &str, model_id: &str, history: &ChatHistory) -> rusqlite::Result<bool> {
+        let title: Option<String> = history.messages.iter()
+            .find(|message| message.is_user_msg())
+            .map(|message| message.message.trim().chars().take(GENERATED_TITLE_LENGTH).collect());
+
+        let mut connection = self.connection.lock().unwrap();
+        let transaction = connection.transaction()?;
+        let changed = transaction.execute(
+            "UPDATE conversations SET model = ?, updated = ?, title = COALESCE(title, ?) WHERE id = ? AND owner = ?",
+            params![model_id, now_iso(), title, id, owner],
+        )?;
+        if changed == 0 {
+            return Ok(false);
+        }
+
+        transaction.execute("DELETE FROM messages WHERE conversation_id = ?", [id])?;
+        for (position, message) in history.messages.iter().enumerate() {
+            transaction.execute(
+                "INSERT INTO messages (conversation_id, position, sender, message, time_iso, stopped, variants, tokens) VALUES (?, ?, ?, ?, ?, ?, ?, ?)",
+                params![
+                    id,
+                    position,
+                    sender_to_text(&message.sender),
+                    message.message,
+                    message.time_iso,
+                    message.stopped,
+                    variants_to_text(&message.variants),
+                    tokens_to_text(&message.tokens),
+                ],
+            )?;
+        }
+        transaction.commit()?;
+        Ok(true)
+    }
+
+    // Every model gets a row its answer and vote are recorded in later
+    pub fn create_comparison(&self, id: &str, prompt: &str, model_ids: &[String]) -> rusqlite::Result<()> {
+        let mut connection = self.connection.lock().unwrap();
+        let transaction = connection.transaction()?;
+        transaction.execute(
+            "INSERT INTO comparisons (id, created, prompt, models) VALUES (?, ?, ?, ?)",
+            params![id, now_iso(), prompt, serde_json::to_string(model_ids).expect("Model ids are valid JSON")],
+        )?;
+        for model_id in model_ids {
+            transaction.execute("INSERT INTO compared_answers (comparison_id, model) VALUES (?, ?)", [id, model_id])?;
+        }
+        transaction.commit()
+    }
+
+    pub fn record_answer(&self, comparison_id: &str, model_id: &str, answer: &ComparedAnswer) -> rusqlite::Result<bool> {
+        let changed = self.connection.lock().unwrap().execute(
+            "UPDATE compared_answers SET answer = ?, first_token_ms = ?, total_ms = ?, bytes_per_token = ?
+                WHERE comparison_id = ? AND model = ?",
+            params![answer.text, answer.first_token_ms, answer.total_ms, answer.bytes_per_token, comparison_id, model_id],
+        )?;
+        Ok(changed > 0)
+    }
+
+    // Returns whether the comparison has a recorded answer of the model to vote on
+    pub fn record_preference(&self, comparison_id: &str, model_id: &str, preferred: bool) -> rusqlite::Result<bool> {
+        let changed = self.connection.lock().unwrap().execute(
+            "UPDATE compared_answers SET preferred = ?, voted = ? WHERE comparison_id = ? AND model = ? AND answer IS NOT NULL",
+            params![preferred, now_iso(), comparison_id, model_id],
+        )?;
+        Ok(changed > 0)
+    }
+
+    // Answers that were voted on, oldest comparison first
+    pub fn preferences(&self) -> rusqlite::Result<Vec<Preference>> {
+        let connection = self.connection.lock().unwrap();
+        let mut stmt = connection.prepare(
+            "SELECT comparisons.id, answers.model, comparisons.prompt, comparisons.models, answers.answer, answers.preferred,
+                    answers.first_token_ms, answers.total_ms, answers.bytes_per_token
+                FROM compared_answers AS answers JOIN comparisons ON comparisons.id = answers.comparison_id
+                WHERE answers.preferred IS NOT NULL
+                ORDER BY comparisons.rowid, answers.rowid",
+        )?;
+        let preferences = stmt.query_map([], |row| {
+            Ok(Preference {
+                comparison_id: row.get(0)?,
+                model_id: row.get(1)?,
+                prompt: row.get(2)?,
+                compared: serde_json::from_str(&row.get::<_, String>(3)?).unwrap_or_default(),
+                answer: ComparedAnswer {
+                    text: row.get(4)?,
+                    first_token_ms: row.get(6)?,
+                    total_ms: row.get(7)?,
+                    bytes_per_token: row.get(8)?,
+                },
+                preferred: row.get(5)?,
+            })
+        })?.collect();
+        preferences
+    }
+}
+
+fn summary_from_row(row: &Row) -> rusqlite::Result<ConversationSummary> {
+    Ok(ConversationSummary {
+        id: row.get(0)?,
+        title: row.get::<_, Option<String>>(1)?.unwrap_or_else(|| UNTITLED_CONVERSATION.to_string()),
+        model_id: row.get(2)?,
+        created_iso: row.get(3)?,
+        updated_iso: row.get(4)?,
+    })
+}
+
+fn sender_to_text(sender: &Sender) -> &'static str {
+    match sender {
+        Sender::User => "user",
+        Sender::ChatCLM => "chatclm",
+    }
+}
+
+fn sender_from_text(text: &str) -> Sender {
+    match text {
+        "user" => Sender::User,
+        _ => Sender::ChatCLM,
+    }
+}
+
+fn variants_to_text(variants: &Variants) -> String {
+    if variants.others.is_empty() {
+        return String::new();
+    }
+    serde_json::to_string(variants).expect("Variants are valid JSON")
+}
+
+// Unreadable variants lose the other branches, not the conversation
+fn variants_from_text(text: &str) -> Variants {
+    serde_json::from_str(text).unwrap_or_default()
+}
+
+fn tokens_to_text(tokens: &[TokenInfo]) -> String {
+    if tokens.is_empty() {
+        return String::new();
+    }
+    serde_json::to_string(tokens).expect("Tokens are valid JSON")
+}
+
+fn settings_to_text(settings: &GenerationSettings) -> String {
+    if *settings == GenerationSettings::default() {
+        return String::new();
+    }
+    serde_json::to_string(settings).expect("Generation settings are valid JSON")
+}
+
+// Unreadable settings fall back to the defaults of the model
+fn settings_from_text(text: &str) -> GenerationSettings {
+    serde_json::from_str(text).unwrap_or_default()
+}
+
+// Unreadable tokens only cost the inspect view of the answer
+fn tokens_from_text(text: &str) -> Vec<TokenInfo> {
+    serde_json::from_str(text).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::chat::{ChatHistory, TokenAlternative, TokenInfo, UNTITLED_CONVERSATION};
+    use crate::compare::{ComparedAnswer, Preference};
+    use crate::model::GenerationSettings;
+    use crate::storage::ConversationStore;
+
+    const OWNER: &str = "0123456789abcdef0123456789abcdef";
+
+    fn history() -> ChatHistory {
+        let mut history = ChatHistory::default();
+        history.new_user_message("What is a compression language model?".to_string());
+        history.new_server_message("A model that".to_string());
+        history.stop_last_server_message();
+        history.regenerate(1);
+        history.replace_last_server_message("A model that compresses.".to_string());
+        history.set_last_server_tokens(vec![TokenInfo {
+            text: " A".to_string(),
+            bytes_added: 1,
+            alternatives: vec![TokenAlternative { text: " The".to_string(), bytes_added: 1 }],
+        }]);
+        history
+    }
+
+    #[test]
+    fn saved_conversations_load_again() {
+        let store = ConversationStore::in_memory().unwrap();
+        let summary = store.create(OWNER, "random", &GenerationSettings::default()).unwrap();
+        assert_eq!(summary.title, UNTITLED_CONVERSATION);
+
+        assert!(store.save(OWNER, &summary.id, "chatclm", &history()).unwrap());
+        let conversation = store.get(OWNER, &summary.id).unwrap().unwrap();
+        assert_eq!(conversation.history, history());
+        assert_eq!(conversation.summary.model_id, "chatclm");
+        assert_eq!(conversation.summary.title, "What is a compression language model?");
+
+        assert!(store.get(OWNER, "unknown").unwrap().is_none());
+        assert!(!store.save(OWNER, "unknown", "chatclm", &history()).unwrap());
+    }
+
+    #[test]
+    fn renamed_titles_are_kept() {
+        let store = ConversationStore::in_memory().unwrap();
+        let summary = store.create(OWNER, "chatclm", &GenerationSettings::default()).unwrap();
+
+        assert!(store.rename(OWNER, &summary.id, "Compression").unwrap());
+        store.save(OWNER, &summary.id, "chatclm", &history()).unwrap();
+        assert_eq!(store.list(OWNER).unwrap()[0].title, "Compression");
+    }
+
+    #[test]
+    fn settings_stay_with_their_conversation() {
+        let store = ConversationStore::in_memory().unwrap();
+        let settings = GenerationSettings { temperature: Some(0.7), stop: vec!["\n".to_string()], ..GenerationSettings::default() };
+        let first = store.create(OWNER, "chatclm", &settings).unwrap();
+        let second = store.create(OWNER, "chatclm", &GenerationSettings::default()).unwrap();
+        store.save(OWNER, &second.id, "chatclm", &history()).unwrap();
+        assert_eq!(store.get(OWNER, &first.id).unwrap().unwrap().settings, settings);
+
+        let changed = GenerationSettings { depth: Some(2), ..settings };
+        assert!(store.set_settings(OWNER, &first.id, &changed).unwrap());
+        assert_eq!(store.get(OWNER, &first.id).unwrap().unwrap().settings, changed);
+        assert_eq!(store.get(OWNER, &second.id).unwrap().unwrap().settings, GenerationSettings::default());
+        assert!(!store.set_settings(OWNER, "unknown", &changed).unwrap());
+    }
+
+    fn compared_answer(text: &str) -> ComparedAnswer {
+        ComparedAnswer { text: text.to_string(), first_token_ms: Some(120.0), total_ms: 900.0, bytes_per_token: Some(1.5) }
+    }
+
+    #[test]
+    fn votes_on_the_same_answer_replace_each_other() {
+        let store = ConversationStore::in_memory().unwrap();
+        let compared = vec!["chatclm".to_string(), "random".to_string()];
+        store.create_comparison("1", "hello", &compared).unwrap();
+        assert!(store.record_answer("1", "chatclm", &compared_answer(" hi")).unwrap());
+        assert!(store.record_answer("1", "random", &compared_answer(" the")).unwrap());
+
+        assert!(store.record_preference("1", "chatclm", true).unwrap());
+        assert!(store.record_preference("1", "random", false).unwrap());
+        assert!(store.record_preference("1", "chatclm", false).unwrap());
+
+        let preference = Preference {
+            comparison_id: "1".to_string(),
+            prompt: "hello".to_string(),
+            model_id: "chatclm".to_string(),
+            compared,
+            answer: compared_answer(" hi"),
+            preferred: false,
+        };
+        let other = Preference { model_id: "random".to_string(), answer: compared_answer(" the"), ..preference.clone() };
+        assert_eq!(store.preferences().unwrap(), vec![preference, other]);
+    }
+
+    #[test]
+    fn votes_need_an_answer_of_the_comparison() {
+        let store = ConversationStore::in_memory().unwrap();
+        store.create_comparison("1", "hello", &["chatclm".to_string(), "random".to_string()]).unwrap();
+        assert!(store.record_answer("1", "chatclm", &compared_answer(" hi")).unwrap());
+
+        assert!(!store.record_preference("2", "chatclm", true).unwrap());
+        assert!(!store.record_preference("1", "ensemble", true).unwrap());
+        // the generation of the other model has not ended yet
+        assert!(!store.record_preference("1", "random", true).unwrap());
+        assert!(!store.record_answer("1", "ensemble", &compared_answer(" hi")).unwrap());
+        assert!(store.preferences().unwrap().is_empty());
+    }
+
+    #[test]
+    fn databases_from_before_branches_are_upgraded() {
+        let connection = rusqlite::Connection::open_in_memory().unwrap();
+        connection.execute_batch("
+            CREATE TABLE conversations (
+                id TEXT PRIMARY KEY,
+                title TEXT,
+                model TEXT NOT NULL,
+                created TEXT NOT NULL,
+                updated TEXT NOT NULL
+            );
+            CREATE TABLE messages (
+                conversation_id TEXT NOT NULL,
+                position INTEGER NOT NULL,
+                sender TEXT NOT NULL,
+                message TEXT NOT NULL,
+                time_iso TEXT NOT NULL,
+                stopped INTEGER NOT NULL,
+                PRIMARY KEY (conversation_id, position)
+            );
+        ").unwrap();
+
+        let store = ConversationStore::with_connection(connection).unwrap();
+        let summary = store.create(OWNER, "chatclm", &GenerationSettings::default()).unwrap();
+        store.save(OWNER, &summary.id, "chatclm", &history()).unwrap();
+        assert_eq!(store.get(OWNER, &summary.id).unwrap().unwrap().history, history());
+    }
+
+    #[test]
+    fn conversations_are_only_seen_by_their_owner() {
+        let store = ConversationStore::in_memory().unwrap();
+        let summary = store.create(OWNER, "chatclm", &GenerationSettings::default()).unwrap();
+        store.save(OWNER, &summary.id, "chatclm", &history()).unwrap();
+        let other = "fedcba9876543210fedcba9876543210";
+
+        assert!(store.list(other).unwrap().is_empty());
+        assert!(store.get(other, &summary.id).unwrap().is_none());
+        assert!(!store.rename(other, &summary.id, "Mine now").unwrap());
+        assert!(!store.set_settings(other, &summary.id, &GenerationSettings { depth: Some(2), ..GenerationSettings::default() }).unwrap());
+        assert!(!store.save(other, &summary.id, "random", &ChatHistory::default()).unwrap());
+        assert!(!store.delete(other, &summary.id).unwrap());
+
+        let conversation = store.get(OWNER, &summary.id).unwrap().unwrap();
+        assert_eq!(conversation.summary.title, "What is a compression language model?");
+        assert_eq!(conversation.settings, GenerationSettings::default());
+        assert_eq!(conversation.history, history());
+        assert_eq!(store.list(OWNER).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn deleted_conversations_are_gone() {
+        let store = ConversationStore::in_memory().unwrap();
+        let first = store.create(OWNER, "chatclm", &GenerationSettings::default()).unwrap();
+        let second = store.create(OWNER, "chatclm", &GenerationSettings::default()).unwrap();
+        store.save(OWNER, &first.id, "chatclm", &history()).unwrap();
+
+        assert!(store.delete(OWNER, &first.id).unwrap());
+        assert!(!store.delete(OWNER, &first.id).unwrap());
+        let remaining: Vec<_> = store.list(OWNER).unwrap().into_iter().map(|summary| summary.id).collect();
+        assert_eq!(remaining, vec![second.id]);
+    }
+}
@@ -1,14 +1,12 @@
-use indicatif::ProgressIterator;
-use rand::seq::SliceRandom;
-use rayon::prelude::*;
 use serde::{Deserialize, Serialize};
 
 use chatclm::backend::dataset::Dataset;
 use chatclm::backend::ensemble_model::EnsembleModel;
-use chatclm::backend::MAX_TOKEN;
-use chatclm::backend::Token;
+use chatclm::backend::sampling::{Sampler, SamplingOptions};
 use chatclm::backend::training_options::TrainingOptions;
 
+// Only read and written by the tuning run below, which is commented out for now
+#[allow(dead_code)]
 #[derive(Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 struct TuningParameters {
@@ -20,6 +18,7 @@ struct TuningParameters {
     dictionary_size_percentage: f64,
 }
 
+#[allow(dead_code)]
 #[derive(Serialize, Deserialize)]
 struct TuningMetrics {
     val_bpt: f64,
@@ -107,66 +106,35 @@ fn main() {
     let retrain = false;
     let trained_model = if retrain {
         println!("Reading dataset");
-        let dataset = Dataset::load_or_compute("dataset.checkpoint");
+        let dataset = Dataset::load_or_compute("dataset.checkpoint").expect("Failed to load dataset");
         println!("Training models");
 
-        let mut options = TrainingOptions::default();
-        options.ensemble_size = 20;
-        let trained_model = EnsembleModel::train(dataset, &options);
-        trained_model.save_checkpoint("ensemble.checkpoint");
+        let options = TrainingOptions { ensemble_size: 20, ..TrainingOptions::default() };
+        let trained_model = EnsembleModel::train(dataset, &options).expect("Failed to train models");
+        trained_model.save_checkpoint("ensemble.checkpoint").expect("Failed to save checkpoint");
         trained_model
     } else {
-        EnsembleModel::from_checkpoint("ensemble.checkpoint")
+        EnsembleModel::from_checkpoint("ensemble.checkpoint").expect("Failed to load checkpoint")
     };
 
 
     // Tokenize "The quick brown fox jumps over the lazy dog"
     let prompt = "The quick brown fox jumps over the lazy";
-    let mut prompt_tokens = Dataset::tokenize(prompt);
-    let mut size_before = trained_model.compressed_size(&prompt_tokens);
+    let mut prompt_tokens = Dataset::tokenize(prompt).unwrap();
+    let mut sampler = Sampler::new(SamplingOptions { top_k: Some(10), ..SamplingOptions::new() });
 
     for _ in 0..50 {
-        println!("Prompt: {} ", Dataset::detokenize(prompt_tokens.clone()).as_str());
+        println!("Prompt: {} ", Dataset::detokenize(prompt_tokens.clone()).unwrap());
 
-        let mut sizes: Vec<(Token, f64)> = (0..(Dataset::get_tokenizer().get_max_token()+1)).progress().par_bridge().map(|next_token| {
-            let mut tokens = prompt_tokens.clone();
-            tokens.push(next_token as Token);
-            let size = trained_model.compressed_size(&tokens);
-            println!("Size: {}", size);
-            (next_token as Token, 1.0 / (size - size_before))
-        }).collect();
+        let sizes = trained_model.get_next_token_sizes(&prompt_tokens).expect("Failed to score the next token");
 
-        //shuffle
-        sizes.shuffle(&mut rand::thread_rng());
-
-        // sort descending
-        sizes.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap());
-
-        // take only the first 200 tokens
-        sizes.truncate(10);
-
-        // subtract the mimimum likelihood
-        let min_size = sizes.iter().map(|(_token, size)| *size).min_by(|a, b| a.partial_cmp(b).unwrap()).unwrap();
-        sizes.iter_mut().for_each(|(_token, size)| *size -= min_size);
-
-        // normalize the distribution
-        let sum: f64 = sizes.iter().map(|(_token, size)| *size).sum();
-        sizes.iter_mut().for_each(|(_token, size)| *size /= sum);
-
-        // print the sum of all sizes
-        let sum: f64 = sizes.iter().map(|(_token, size)| *size).sum();
-        println!("Sum: {}", sum);
-
-        // print the ten smallest sizes
-        for (token, size) in sizes.iter().take(10) {
-            println!("Token: '{}' ({}), Size: {}", Dataset::detokenize(vec![*token]), *token as u64,  size);
+        // print the ten most likely tokens
+        for (token, probability) in sampler.distribution(&sizes).iter().take(10) {
+            println!("Token: '{}' ({}), Probability: {}", Dataset::detokenize(vec![*token]).unwrap(), *token as u64, probability);
         }
 
-
-        // choose a token with probability proportional to the size
-        let mut rng = rand::thread_rng();
-        let (next_token, next_size) = sizes.choose_weighted(&mut rng, |(_token, size)| *size).unwrap();
-        prompt_tokens.push(*next_token);
-        size_before = *next_size;
+        // choose a token with probability according to its compressed size
+        let next_token = sampler.sample(&sizes).unwrap();
+        prompt_tokens.push(next_token);
     }
-}
\ No newline at end of file
+}